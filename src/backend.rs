@@ -0,0 +1,98 @@
+use crate::vm_parser::Instruction;
+use crate::{c_emitter, llvm_emitter, vm_emitter, wat_emitter, x86_emitter};
+
+// A code generation target. `emit` calls `begin_program` once with every file so
+// backends can collect labels and statics up front, then `begin_file` before the
+// instructions of each file, `emit_instruction` for every instruction except
+// `Ignored` ones, and `finish` to get the output.
+pub trait Backend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]);
+  fn begin_file(&mut self, program_name: &str);
+  fn emit_instruction(&mut self, instruction: &Instruction);
+  fn finish(&mut self) -> String;
+}
+
+// A backend the CLI can select with `--target`
+pub struct Target {
+  pub name: &'static str,
+  // Extension of the output file
+  pub extension: &'static str,
+  pub description: &'static str,
+  pub new: fn() -> Box<dyn Backend>,
+}
+
+// The built-in targets, Hack assembly first since it's the default
+pub fn targets() -> Vec<Target> {
+  vec![
+    Target { name: "hack", extension: "asm", description: "Hack assembly", new: || Box::<vm_emitter::HackBackend>::default() },
+    Target { name: "c", extension: "c", description: "portable C", new: || Box::<c_emitter::CBackend>::default() },
+    Target { name: "wat", extension: "wat", description: "WebAssembly text", new: || Box::<wat_emitter::WatBackend>::default() },
+    Target { name: "x86", extension: "s", description: "x86-64 GNU assembly", new: || Box::<x86_emitter::X86Backend>::default() },
+    Target { name: "llvm", extension: "ll", description: "LLVM IR", new: || Box::<llvm_emitter::LlvmBackend>::default() },
+  ]
+}
+
+pub fn target(name: &str) -> Option<Target> {
+  targets().into_iter().find(|target| target.name == name)
+}
+
+pub fn emit(backend: &mut dyn Backend, files: &[(String, Vec<Instruction>)]) -> String {
+  backend.begin_program(files);
+  for (program_name, instructions) in files.iter() {
+    backend.begin_file(program_name);
+    for instruction in instructions.iter().filter(|instruction| !matches!(instruction, Instruction::Ignored)) {
+      backend.emit_instruction(instruction);
+    }
+  }
+  backend.finish()
+}
+
+#[cfg(test)]
+mod test {
+  use crate::backend::*;
+
+  // Records the calls it gets
+  #[derive(Default)]
+  struct Recorder {
+    calls: Vec<String>,
+  }
+
+  impl Backend for Recorder {
+    fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+      self.calls.push(format!("begin_program {}", files.len()));
+    }
+
+    fn begin_file(&mut self, program_name: &str) {
+      self.calls.push(format!("begin_file {}", program_name));
+    }
+
+    fn emit_instruction(&mut self, instruction: &Instruction) {
+      self.calls.push(instruction.to_string());
+    }
+
+    fn finish(&mut self) -> String {
+      self.calls.join("\n")
+    }
+  }
+
+  #[test]
+  fn test_emit() {
+    let files = crate::parse_program(&[
+      ("Main".to_string(), "// comment\nfunction Main.main 0\npush constant 1\nreturn\n".to_string()),
+      ("Sys".to_string(), "function Sys.init 0\ncall Main.main 0\n".to_string()),
+    ]).unwrap();
+    assert_eq!(emit(&mut Recorder::default(), &files),
+"begin_program 2
+begin_file Main
+function Main.main 0
+push constant 1
+return
+begin_file Sys
+function Sys.init 0
+call Main.main 0");
+    for target in targets() {
+      assert!(!emit((target.new)().as_mut(), &files).is_empty());
+    }
+    assert_eq!(emit((target("hack").unwrap().new)().as_mut(), &files), vm_emitter::emit_program(files));
+  }
+}
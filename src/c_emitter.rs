@@ -0,0 +1,284 @@
+use crate::vm_parser::*;
+use crate::backend::{self, Backend};
+use crate::vm_emitter;
+use std::collections::HashMap;
+
+// Names the C code can't spell directly, collected before emitting
+#[derive(Default)]
+struct Symbols {
+  // VM labels and function names, which share one namespace like in Hack assembly
+  labels: HashMap<String, usize>,
+  // RAM address of every (file, static index), the same as in the Hack output
+  statics: HashMap<(String, usize), usize>,
+}
+
+// Emits a self-contained C file that runs the program on a simulated Hack RAM `int16_t ram[32768]`.
+// The whole program becomes one C function, `vm_run`, with a `goto` label per VM label and function,
+// and returns jump back through a `switch` over the return addresses so no GNU extensions are needed.
+// Define `VM_NO_MAIN` to link it into a test harness and `VM_MAX_STEPS` to stop programs that loop forever.
+pub fn emit_program(files: Vec<(String, Vec<Instruction>)>) -> String {
+  backend::emit(&mut CBackend::default(), &files)
+}
+
+#[derive(Default)]
+pub struct CBackend {
+  symbols: Symbols,
+  program_name: String,
+  // Numbers the return address of the latest call
+  return_index: usize,
+  has_return: bool,
+  body: Vec<String>,
+}
+
+impl Backend for CBackend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+    self.symbols = collect_symbols(files);
+    if self.symbols.labels.contains_key("Sys.init") {
+      self.body.push("  // Bootstrap: SP = 256, call Sys.init 0\n  ram[SP] = 256;".to_string());
+      self.return_index += 1;
+      self.body.push(emit_call(&self.symbols, "Sys.init", 0, self.return_index));
+    }
+  }
+
+  fn begin_file(&mut self, program_name: &str) {
+    self.program_name = program_name.to_string();
+    self.body.push(format!("  // {}.vm", program_name));
+  }
+
+  fn emit_instruction(&mut self, instruction: &Instruction) {
+    match instruction {
+      Instruction::Call { .. } => self.return_index += 1,
+      Instruction::Return => self.has_return = true,
+      _ => {}
+    }
+    self.body.push(format!("  /* {} */\n{}", instruction, emit_instruction(&self.symbols, &self.program_name, self.return_index, instruction)));
+  }
+
+  fn finish(&mut self) -> String {
+    let dispatch = if self.has_return {
+      format!(
+"  return;
+dispatch:
+  switch (return_address) {{
+{}
+  default: return;
+  }}
+", (1..=self.return_index).map(|index| format!("  case {}: goto return_{};", index, index)).collect::<Vec<String>>().join("\n"))
+    } else {
+      String::new()
+    };
+format!(
+"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define SP 0
+#define LCL 1
+#define ARG 2
+#define THIS 3
+#define THAT 4
+// Addresses wrap around the 32K RAM instead of leaving the array
+#define RAM(address) ram[(uint16_t)(address) & 0x7fff]
+#define PUSH(value) do {{ int16_t pushed = (value); RAM(ram[SP]) = pushed; ram[SP]++; }} while (0)
+#define POP() RAM(--ram[SP])
+#ifdef VM_MAX_STEPS
+static long vm_steps;
+#define STEP() if (++vm_steps > VM_MAX_STEPS) return
+#else
+#define STEP()
+#endif
+
+int16_t ram[32768];
+
+void vm_run(void) {{
+  int16_t x, y, frame, return_address;
+  (void)x; (void)y; (void)frame; (void)return_address;
+{}
+{}}}
+
+#ifndef VM_NO_MAIN
+// Sets RAM from `address=value` arguments, runs the program and prints the stack
+int main(int argc, char **argv) {{
+  int i, address, value;
+  ram[SP] = 256;
+  for (i = 1; i < argc; i++) {{
+    if (sscanf(argv[i], \"%d=%d\", &address, &value) == 2) {{
+      RAM(address) = (int16_t)value;
+    }}
+  }}
+  vm_run();
+  for (address = 256; address < ram[SP]; address++) {{
+    printf(\"%d\\n\", ram[address]);
+  }}
+  return 0;
+}}
+#endif
+", self.body.join("\n"), dispatch)
+  }
+}
+
+
+fn collect_symbols(files: &[(String, Vec<Instruction>)]) -> Symbols {
+  let mut labels = HashMap::new();
+  for instruction in files.iter().flat_map(|(_, instructions)| instructions.iter()) {
+    if let Instruction::Label(name) | Instruction::Function { name, .. } = instruction {
+      let index = labels.len();
+      labels.entry(name.clone()).or_insert(index);
+    }
+  }
+  Symbols { labels, statics: vm_emitter::static_addresses(files) }
+}
+
+// `return_index` numbers the return address of the latest call
+fn emit_instruction(symbols: &Symbols, program_name: &str, return_index: usize, instruction: &Instruction) -> String {
+  match instruction {
+    Instruction::Arithmetic(arith_instruction) =>
+      match arith_instruction {
+        ArithInstruction::Add =>
+          emit_binary_arithmetic("x + y"),
+        ArithInstruction::Sub =>
+          emit_binary_arithmetic("x - y"),
+        ArithInstruction::Eq =>
+          emit_binary_arithmetic("x == y ? -1 : 0"),
+        ArithInstruction::Gt =>
+          emit_binary_arithmetic("x > y ? -1 : 0"),
+        ArithInstruction::Lt =>
+          emit_binary_arithmetic("x < y ? -1 : 0"),
+        ArithInstruction::And =>
+          emit_binary_arithmetic("x & y"),
+        ArithInstruction::Or =>
+          emit_binary_arithmetic("x | y"),
+        ArithInstruction::Neg =>
+          emit_unary_arithmetic("-x"),
+        ArithInstruction::Not =>
+          emit_unary_arithmetic("~x"),
+      },
+    Instruction::Push { segment, offset } =>
+      format!("  PUSH({});", segment_address(symbols, program_name, *segment, *offset)),
+    Instruction::Pop { segment, offset } =>
+      format!("  x = POP();\n  {} = x;", segment_address(symbols, program_name, *segment, *offset)),
+    Instruction::Ignored =>
+      panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
+    Instruction::Label(label) =>
+      format!("{}:;\n  STEP();", label_name(symbols, label)),
+    Instruction::Goto(label) =>
+      format!("  {}", emit_goto(symbols, label)),
+    Instruction::IfGoto(label) =>
+      format!("  if (POP() != 0) {}", emit_goto(symbols, label)),
+    Instruction::Function { name, local_vars } =>
+      format!("{}:;\n  STEP();{}", label_name(symbols, name), "\n  PUSH(0);".repeat(*local_vars)),
+    Instruction::Call { name, args } =>
+      emit_call(symbols, name, *args, return_index),
+    Instruction::Return =>
+      emit_return(),
+  }
+}
+
+// The C expression reading or writing the segment entry, or the constant itself
+fn segment_address(symbols: &Symbols, program_name: &str, segment: Segment, offset: usize) -> String {
+  match segment {
+    Segment::Local =>
+      format!("RAM(ram[LCL] + {})", offset),
+    Segment::Argument =>
+      format!("RAM(ram[ARG] + {})", offset),
+    Segment::This =>
+      format!("RAM(ram[THIS] + {})", offset),
+    Segment::That =>
+      format!("RAM(ram[THAT] + {})", offset),
+    Segment::Constant =>
+      offset.to_string(),
+    Segment::Static =>
+      format!("ram[{}]", symbols.statics[&(program_name.to_string(), offset)]),
+    Segment::Temp =>
+      format!("ram[{}]", 5 + offset),
+    Segment::Pointer =>
+      (if offset == 0 { "ram[THIS]" } else { "ram[THAT]" }).to_string(),
+  }
+}
+
+// C's int arithmetic is truncated back to 16 bits, which gcc does by wrapping around like the Hack ALU
+fn emit_binary_arithmetic(expression: &str) -> String {
+  format!("  y = POP();\n  x = POP();\n  PUSH((int16_t)({}));", expression)
+}
+
+fn emit_unary_arithmetic(expression: &str) -> String {
+  format!("  x = POP();\n  PUSH((int16_t)({}));", expression)
+}
+
+// Label names can hold characters like `.` and `$` that C labels can't,
+// so every label is numbered and jumps keep the original name in a comment
+fn label_name(symbols: &Symbols, label: &str) -> String {
+  format!("label_{}", symbols.labels[label])
+}
+
+// Jumping to a label the program never defines stops it at runtime instead of failing to compile
+fn emit_goto(symbols: &Symbols, label: &str) -> String {
+  if symbols.labels.contains_key(label) {
+    format!("goto {}; // {}", label_name(symbols, label), label)
+  } else {
+    format!("{{ fprintf(stderr, \"The program jumped to the undefined label {}.\\n\"); exit(1); }}", label)
+  }
+}
+
+// Pushes the same frame as the Hack emitter so RAM looks the same in both
+fn emit_call(symbols: &Symbols, name: &str, args: usize, return_index: usize) -> String {
+  format!(
+"  PUSH({});
+  PUSH(ram[LCL]);
+  PUSH(ram[ARG]);
+  PUSH(ram[THIS]);
+  PUSH(ram[THAT]);
+  ram[ARG] = ram[SP] - {};
+  ram[LCL] = ram[SP];
+  {}
+return_{}:;", return_index, 5 + args, emit_goto(symbols, name), return_index)
+}
+
+fn emit_return() -> String {
+"  frame = ram[LCL];
+  return_address = RAM(frame - 5);
+  RAM(ram[ARG]) = POP();
+  ram[SP] = ram[ARG] + 1;
+  ram[THAT] = RAM(frame - 1);
+  ram[THIS] = RAM(frame - 2);
+  ram[ARG] = RAM(frame - 3);
+  ram[LCL] = RAM(frame - 4);
+  goto dispatch;".to_string()
+}
+
+#[cfg(test)]
+mod test {
+  use crate::c_emitter::*;
+  use crate::test_support::*;
+  use std::process::Command;
+
+  #[test]
+  fn test_emit_program() {
+    let c_source = emit_program(fibonacci_program(HALT));
+    assert!(c_source.contains("goto label_0; // Main.fibonacci"));
+
+    // Runs the program natively when gcc is around
+    let harness =
+"#define VM_NO_MAIN
+#define VM_MAX_STEPS 100000000
+#include \"program.c\"
+int main(void) {
+  vm_run();
+  printf(\"%d %d\\n\", ram[16], ram[17]);
+  return 0;
+}
+";
+    let output = run_if_installed("gcc", "the C output", &[("program.c", c_source.as_bytes()), ("harness.c", harness.as_bytes())], |directory| {
+      let status = Command::new("gcc")
+        .args(["-Wall", "-Werror", "-O2", "-o"])
+        .arg(directory.join("harness"))
+        .arg(directory.join("harness.c"))
+        .status()?;
+      assert!(status.success());
+      Command::new(directory.join("harness")).output()
+    });
+    if let Some(output) = output {
+      assert_eq!(String::from_utf8(output.stdout).unwrap(), "6765 3\n");
+    }
+  }
+}
@@ -0,0 +1,305 @@
+use crate::vm_parser::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+  pub name: String,
+  pub program_name: String,
+  pub local_vars: usize,
+  // One more than the highest `argument` index the function reads or writes
+  pub arguments_used: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+  // The calling function, or `(top level of X.vm)` for commands before the first function
+  pub caller: String,
+  pub program_name: String,
+  pub callee: String,
+  pub args: usize,
+}
+
+// Who calls whom across all files of a program, in program order
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallGraph {
+  pub functions: Vec<Function>,
+  pub calls: Vec<Call>,
+}
+
+pub fn build(files: &[(String, Vec<Instruction>)]) -> CallGraph {
+  let mut functions: Vec<Function> = Vec::new();
+  let mut calls = Vec::new();
+  for (program_name, instructions) in files.iter() {
+    let mut caller = format!("(top level of {}.vm)", program_name);
+    let mut in_function = false;
+    for instruction in instructions.iter() {
+      match instruction {
+        Instruction::Function { name, local_vars } => {
+          caller = name.clone();
+          in_function = true;
+          functions.push(Function { name: name.clone(), program_name: program_name.clone(), local_vars: *local_vars, arguments_used: 0 });
+        }
+        Instruction::Call { name, args } =>
+          calls.push(Call { caller: caller.clone(), program_name: program_name.clone(), callee: name.clone(), args: *args }),
+        Instruction::Push { segment: Segment::Argument, offset } | Instruction::Pop { segment: Segment::Argument, offset } if in_function => {
+          let function = functions.last_mut().unwrap();
+          function.arguments_used = function.arguments_used.max(offset + 1);
+        }
+        _ => {}
+      }
+    }
+  }
+  CallGraph { functions, calls }
+}
+
+impl CallGraph {
+  pub fn function(&self, name: &str) -> Option<&Function> {
+    self.functions.iter().find(|function| function.name == name)
+  }
+
+  // Distinct functions called by `caller`, in the order of their first call
+  pub fn callees(&self, caller: &str) -> Vec<&str> {
+    let mut callees = Vec::new();
+    for call in self.calls.iter().filter(|call| call.caller == caller) {
+      if !callees.contains(&call.callee.as_str()) {
+        callees.push(call.callee.as_str());
+      }
+    }
+    callees
+  }
+
+  pub fn undefined_calls(&self) -> Vec<&Call> {
+    self.calls.iter().filter(|call| self.function(&call.callee).is_none()).collect()
+  }
+
+  // Calls passing fewer arguments than the callee uses
+  pub fn arity_mismatches(&self) -> Vec<(&Call, &Function)> {
+    self.calls.iter().filter_map(|call|
+      self.function(&call.callee)
+        .filter(|function| call.args < function.arguments_used)
+        .map(|function| (call, function))
+    ).collect()
+  }
+
+  // Groups of functions that can call themselves again, directly or through the
+  // others in the group, in program order
+  pub fn recursive_cycles(&self) -> Vec<Vec<&str>> {
+    let names = self.functions.iter().map(|function| function.name.as_str()).collect::<Vec<&str>>();
+    let indices = names.iter().enumerate().map(|(index, name)| (*name, index)).collect::<HashMap<&str, usize>>();
+    let edges = names.iter().map(|name|
+      self.callees(name).iter().filter_map(|callee| indices.get(callee).copied()).collect()
+    ).collect::<Vec<Vec<usize>>>();
+    let mut cycles = strongly_connected_components(&edges).into_iter()
+      .filter(|component| component.len() > 1 || edges[component[0]].contains(&component[0]))
+      .map(|mut component| {
+        component.sort_unstable();
+        component
+      })
+      .collect::<Vec<Vec<usize>>>();
+    cycles.sort();
+    cycles.into_iter().map(|component| component.into_iter().map(|index| names[index]).collect()).collect()
+  }
+
+  // Every problem found, as messages for the command line
+  pub fn report(&self) -> Vec<String> {
+    let undefined = self.undefined_calls().into_iter().map(|call| format!(
+      "In {}.vm:\nI found a call to {} in {} but no function with that name.\nTry adding the file that defines it or check the spelling.",
+      call.program_name, call.callee, call.caller
+    ));
+    let mismatches = self.arity_mismatches().into_iter().map(|(call, function)| format!(
+      "In {}.vm:\nI found `call {} {}` in {} but {} uses `argument {}`, so it needs at least {}.",
+      call.program_name, call.callee, call.args, call.caller, function.name, function.arguments_used - 1,
+      if function.arguments_used == 1 { "1 argument".to_string() } else { format!("{} arguments", function.arguments_used) }
+    ));
+    let cycles = self.recursive_cycles().into_iter().map(|cycle| if cycle.len() == 1 {
+      format!("I found {} calling itself.\nEvery level of recursion adds a frame to the stack, so deep recursion can overflow it.", cycle[0])
+    } else {
+      format!("I found {} calling each other in a cycle.\nEvery level of recursion adds a frame to the stack, so deep recursion can overflow it.", cycle.join(", "))
+    });
+    undefined.chain(mismatches).chain(cycles).collect()
+  }
+
+  // A Graphviz digraph with one edge per caller and callee, labeled with the
+  // number of calls when there are several. Recursive functions are red and
+  // undefined ones dashed.
+  pub fn to_dot(&self) -> String {
+    let recursive = self.recursive_cycles().into_iter().flatten().collect::<Vec<&str>>();
+    let mut nodes = self.functions.iter().map(|function| if recursive.contains(&function.name.as_str()) {
+      format!("  \"{}\" [color=red];\n", function.name)
+    } else {
+      format!("  \"{}\";\n", function.name)
+    }).collect::<Vec<String>>();
+    let mut edges: Vec<(&str, &str, usize)> = Vec::new();
+    for call in self.calls.iter() {
+      match edges.iter_mut().find(|(caller, callee, _)| *caller == call.caller && *callee == call.callee) {
+        Some((_, _, count)) => *count += 1,
+        None => edges.push((&call.caller, &call.callee, 1)),
+      }
+    }
+    for (caller, _, _) in edges.iter().filter(|(caller, _, _)| self.function(caller).is_none()) {
+      let node = format!("  \"{}\" [shape=box];\n", caller);
+      if !nodes.contains(&node) {
+        nodes.push(node);
+      }
+    }
+    for call in self.undefined_calls() {
+      let node = format!("  \"{}\" [style=dashed];\n", call.callee);
+      if !nodes.contains(&node) {
+        nodes.push(node);
+      }
+    }
+    let edges = edges.iter().map(|(caller, callee, count)| if *count > 1 {
+      format!("  \"{}\" -> \"{}\" [label=\"{}\"];\n", caller, callee, count)
+    } else {
+      format!("  \"{}\" -> \"{}\";\n", caller, callee)
+    }).collect::<String>();
+    format!("digraph calls {{\n{}{}}}\n", nodes.concat(), edges)
+  }
+}
+
+// Tarjan's algorithm, with an explicit stack so long call chains can't overflow ours
+fn strongly_connected_components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+  let mut indices: Vec<Option<usize>> = vec![None; edges.len()];
+  let mut lowlinks = vec![0; edges.len()];
+  let mut on_stack = vec![false; edges.len()];
+  let mut stack = Vec::new();
+  let mut components = Vec::new();
+  let mut next_index = 0;
+  for root in 0..edges.len() {
+    if indices[root].is_some() {
+      continue;
+    }
+    // (node, how many of its edges were followed)
+    let mut work = vec![(root, 0)];
+    while let Some((node, edge)) = work.pop() {
+      if edge == 0 {
+        indices[node] = Some(next_index);
+        lowlinks[node] = next_index;
+        next_index += 1;
+        stack.push(node);
+        on_stack[node] = true;
+      }
+      if let Some(&successor) = edges[node].get(edge) {
+        work.push((node, edge + 1));
+        match indices[successor] {
+          None => work.push((successor, 0)),
+          Some(index) if on_stack[successor] => lowlinks[node] = lowlinks[node].min(index),
+          Some(_) => {}
+        }
+        continue;
+      }
+      if let Some((parent, _)) = work.last() {
+        lowlinks[*parent] = lowlinks[*parent].min(lowlinks[node]);
+      }
+      if Some(lowlinks[node]) == indices[node] {
+        let mut component = Vec::new();
+        while let Some(member) = stack.pop() {
+          on_stack[member] = false;
+          component.push(member);
+          if member == node {
+            break;
+          }
+        }
+        components.push(component);
+      }
+    }
+  }
+  components
+}
+
+#[cfg(test)]
+mod test {
+  use crate::call_graph::*;
+
+  #[test]
+  fn test_call_graph() {
+    let files = crate::parse_program(&[
+      ("Main".to_string(),
+"function Main.main 0
+push constant 3
+call Parity.even 1
+push constant 4
+call Main.fib 1
+add
+push constant 1
+call Main.add 1
+call Output.printInt 1
+return
+function Main.add 0
+push argument 0
+push argument 1
+add
+return
+function Main.fib 0
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push constant 1
+sub
+call Main.fib 1
+push argument 0
+push constant 2
+sub
+call Main.fib 1
+add
+return
+label BASE
+push argument 0
+return
+".to_string()),
+      ("Parity".to_string(),
+"function Parity.even 0
+push argument 0
+if-goto MORE
+push constant 1
+return
+label MORE
+push argument 0
+push constant 1
+sub
+call Parity.odd 1
+return
+function Parity.odd 0
+push argument 0
+if-goto ODD_MORE
+push constant 0
+return
+label ODD_MORE
+push argument 0
+push constant 1
+sub
+call Parity.even 1
+return
+".to_string()),
+    ]).unwrap();
+    let graph = build(&files);
+    assert_eq!(graph.function("Main.add").map(|function| function.arguments_used), Some(2));
+    assert_eq!(graph.callees("Main.main"), vec!["Parity.even", "Main.fib", "Main.add", "Output.printInt"]);
+    assert_eq!(graph.recursive_cycles(), vec![vec!["Main.fib"], vec!["Parity.even", "Parity.odd"]]);
+    assert_eq!(graph.report(), vec![
+      "In Main.vm:\nI found a call to Output.printInt in Main.main but no function with that name.\nTry adding the file that defines it or check the spelling.",
+      "In Main.vm:\nI found `call Main.add 1` in Main.main but Main.add uses `argument 1`, so it needs at least 2 arguments.",
+      "I found Main.fib calling itself.\nEvery level of recursion adds a frame to the stack, so deep recursion can overflow it.",
+      "I found Parity.even, Parity.odd calling each other in a cycle.\nEvery level of recursion adds a frame to the stack, so deep recursion can overflow it.",
+    ]);
+    assert_eq!(graph.to_dot(),
+"digraph calls {
+  \"Main.main\";
+  \"Main.add\";
+  \"Main.fib\" [color=red];
+  \"Parity.even\" [color=red];
+  \"Parity.odd\" [color=red];
+  \"Output.printInt\" [style=dashed];
+  \"Main.main\" -> \"Parity.even\";
+  \"Main.main\" -> \"Main.fib\";
+  \"Main.main\" -> \"Main.add\";
+  \"Main.main\" -> \"Output.printInt\";
+  \"Main.fib\" -> \"Main.fib\" [label=\"2\"];
+  \"Parity.even\" -> \"Parity.odd\";
+  \"Parity.odd\" -> \"Parity.even\";
+}
+");
+  }
+}
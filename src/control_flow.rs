@@ -0,0 +1,188 @@
+use crate::vm_parser::*;
+use std::collections::HashMap;
+use std::ops::Range;
+
+// A run of commands that always execute together, from a label or the start of
+// a function to the next jump, `return` or label
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+  // Indices into the instructions the graph was built from
+  pub instructions: Range<usize>,
+  // Indices into the blocks of the same graph. A conditional jump lists the
+  // block it jumps to first and the next block second.
+  pub successors: Vec<usize>,
+}
+
+// The blocks of one function, or of the commands before the first function.
+// The entry is always the first block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlFlowGraph {
+  pub name: String,
+  pub blocks: Vec<BasicBlock>,
+}
+
+impl ControlFlowGraph {
+  // Blocks that can't be reached from the entry, in order
+  pub fn unreachable_blocks(&self) -> Vec<usize> {
+    let mut reached = vec![false; self.blocks.len()];
+    let mut worklist = vec![0];
+    while let Some(block) = worklist.pop() {
+      if block < reached.len() && !reached[block] {
+        reached[block] = true;
+        worklist.extend(self.blocks[block].successors.iter().copied());
+      }
+    }
+    (0..self.blocks.len()).filter(|block| !reached[*block]).collect()
+  }
+}
+
+// Builds one graph per function of a file. Commands before the first function get
+// their own graph named after the file. Jumps only go to labels of the same
+// function, jumps to undefined labels have no successor, and a block running
+// off the end of its function has none either.
+pub fn build(program_name: &str, instructions: &[Instruction]) -> Vec<ControlFlowGraph> {
+  regions(instructions).into_iter().map(|region| {
+    let name = match &instructions[region.start] {
+      Instruction::Function { name, .. } => name.clone(),
+      _ => format!("(top level of {}.vm)", program_name),
+    };
+    ControlFlowGraph { name, blocks: build_blocks(instructions, region) }
+  }).collect()
+}
+
+// The commands of each function, and of the commands before the first function if any
+pub fn regions(instructions: &[Instruction]) -> Vec<Range<usize>> {
+  let mut starts = instructions.iter().enumerate().filter_map(|(index, instruction)| match instruction {
+    Instruction::Function { .. } => Some(index),
+    _ => None,
+  }).collect::<Vec<usize>>();
+  if starts.first() != Some(&0) && !instructions.is_empty() {
+    starts.insert(0, 0);
+  }
+  starts.iter().enumerate().map(|(region, start)|
+    *start..starts.get(region + 1).copied().unwrap_or(instructions.len())
+  ).collect()
+}
+
+fn build_blocks(instructions: &[Instruction], region: Range<usize>) -> Vec<BasicBlock> {
+  let mut leaders = vec![region.start];
+  for index in region.clone() {
+    match &instructions[index] {
+      Instruction::Label(_) if index != region.start =>
+        leaders.push(index),
+      Instruction::Goto(_) | Instruction::IfGoto(_) | Instruction::Return if index + 1 < region.end =>
+        leaders.push(index + 1),
+      _ => {}
+    }
+  }
+  leaders.dedup();
+  let ranges = leaders.iter().enumerate()
+    .map(|(block, start)| *start..leaders.get(block + 1).copied().unwrap_or(region.end))
+    .collect::<Vec<Range<usize>>>();
+  let labels = ranges.iter().enumerate().filter_map(|(block, range)| match &instructions[range.start] {
+    Instruction::Label(label) => Some((label.clone(), block)),
+    _ => None,
+  }).collect::<HashMap<String, usize>>();
+  ranges.iter().enumerate().map(|(block, range)| {
+    let next = if block + 1 < ranges.len() { vec![block + 1] } else { vec![] };
+    let successors = match &instructions[range.end - 1] {
+      Instruction::Goto(label) =>
+        labels.get(label).copied().into_iter().collect(),
+      Instruction::IfGoto(label) =>
+        labels.get(label).copied().into_iter().chain(next.into_iter().filter(|next| labels.get(label) != Some(next))).collect(),
+      Instruction::Return =>
+        vec![],
+      _ =>
+        next,
+    };
+    BasicBlock { instructions: range.clone(), successors }
+  }).collect()
+}
+
+// One Graphviz digraph per graph, with the commands of each block as its label.
+// The edge taken when `if-goto` jumps is labeled `true`, the other one `false`.
+pub fn to_dot(instructions: &[Instruction], graphs: &[ControlFlowGraph]) -> String {
+  graphs.iter().map(|graph| {
+    let nodes = graph.blocks.iter().enumerate().map(|(block, basic_block)| {
+      let commands = instructions[basic_block.instructions.clone()].iter()
+        .map(|instruction| format!("{}\\l", escape(&instruction.to_string())))
+        .collect::<String>();
+      format!("  b{} [label=\"{}\"];\n", block, commands)
+    }).collect::<String>();
+    let edges = graph.blocks.iter().enumerate().flat_map(|(block, basic_block)| {
+      let is_branch = matches!(instructions[basic_block.instructions.end - 1], Instruction::IfGoto(_))
+        && basic_block.successors.len() == 2;
+      basic_block.successors.iter().enumerate().map(move |(index, successor)|
+        if is_branch {
+          format!("  b{} -> b{} [label=\"{}\"];\n", block, successor, if index == 0 { "true" } else { "false" })
+        } else {
+          format!("  b{} -> b{};\n", block, successor)
+        }
+      )
+    }).collect::<String>();
+    format!(
+      "digraph \"{}\" {{\n  node [shape=box, fontname=\"monospace\"];\n{}{}}}\n",
+      escape(&graph.name), nodes, edges
+    )
+  }).collect::<Vec<String>>().join("\n")
+}
+
+fn escape(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+  use crate::control_flow::*;
+
+  #[test]
+  fn test_build() {
+    let instructions = parse(
+"push constant 1
+pop static 0
+function Main.countdown 0
+label LOOP
+push argument 0
+if-goto BODY
+goto END
+label BODY
+push argument 0
+push constant 1
+sub
+pop argument 0
+goto LOOP
+label END
+push constant 0
+return
+push constant 1
+function Main.fallthrough 0
+push constant 0
+if-goto DONE
+label DONE
+").unwrap();
+    let graphs = build("Main", &instructions);
+    assert_eq!(graphs.iter().map(|graph| graph.name.as_str()).collect::<Vec<&str>>(), vec!["(top level of Main.vm)", "Main.countdown", "Main.fallthrough"]);
+    assert_eq!(graphs[0].blocks, vec![BasicBlock { instructions: 0..2, successors: vec![] }]);
+    let countdown = &graphs[1];
+    assert_eq!(countdown.blocks.iter().map(|block| (block.instructions.clone(), block.successors.clone())).collect::<Vec<_>>(), vec![
+      (2..3, vec![1]),
+      (3..6, vec![3, 2]),
+      (6..7, vec![4]),
+      (7..13, vec![1]),
+      (13..16, vec![]),
+      (16..17, vec![]),
+    ]);
+    assert_eq!(countdown.unreachable_blocks(), vec![5]);
+    // Both ways of the `if-goto` lead to the same block
+    assert_eq!(graphs[2].blocks.iter().map(|block| block.successors.clone()).collect::<Vec<_>>(), vec![vec![1], vec![]]);
+
+    assert_eq!(to_dot(&instructions, &graphs[2..]),
+"digraph \"Main.fallthrough\" {
+  node [shape=box, fontname=\"monospace\"];
+  b0 [label=\"function Main.fallthrough 0\\lpush constant 0\\lif-goto DONE\\l\"];
+  b1 [label=\"label DONE\\l\"];
+  b0 -> b1;
+}
+");
+  }
+}
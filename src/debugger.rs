@@ -0,0 +1,313 @@
+use crate::vm_interpreter::{Machine, State};
+use crate::vm_parser::*;
+use lip::Located;
+
+// How many commands `continue`, `next` and `finish` run before giving up, for
+// programs like games that never halt
+pub const MAX_STEPS: usize = 10_000_000;
+// How many entries `this` and `that` show without a count
+const DEFAULT_COUNT: usize = 4;
+
+const HELP: &str =
+"break NAME       stop at function or label NAME, or list the breakpoints without NAME
+delete NAME      remove the breakpoint at NAME
+continue         run until a breakpoint or the end (also `run` and `c`)
+step             run one command, going into calls (also `s`)
+next             run one command, going over calls (also `n`)
+finish           run until the current function returns
+where            show the calls leading here (also `backtrace` and `bt`)
+stack            show the values the current function pushed
+local            show the locals of the current function
+argument         show the arguments of the current function
+this [COUNT]     show the first COUNT entries of the this segment
+that [COUNT]     show the first COUNT entries of the that segment
+print FILE.N     show static N of FILE.vm, like `print Main.0`
+restart          start the program over, keeping the breakpoints
+quit             leave the debugger (also `q`)";
+
+// A VM-level debugger. Each line typed at the prompt goes to `execute`.
+pub struct Debugger {
+  files: Vec<(String, Vec<Located<Instruction>>)>,
+  pub machine: Machine,
+  breakpoints: Breakpoints,
+  halted: bool,
+}
+
+// Breakpoints on functions and labels as the commands they stop at
+#[derive(Default)]
+pub struct Breakpoints {
+  // (name, command index)
+  breakpoints: Vec<(String, usize)>,
+}
+
+impl Breakpoints {
+  pub fn add(&mut self, machine: &Machine, name: &str) -> String {
+    let pcs = machine.commands_named(name);
+    if pcs.is_empty() {
+      return format!("I can't find a function or label called {}.", name);
+    }
+    for pc in pcs.iter() {
+      self.breakpoints.push((name.to_string(), *pc));
+    }
+    pcs.iter().map(|pc| format!("Breakpoint at {} in {}", name, machine.location(*pc))).collect::<Vec<String>>().join("\n")
+  }
+
+  pub fn delete(&mut self, name: &str) -> String {
+    let count = self.breakpoints.len();
+    self.breakpoints.retain(|(breakpoint, _)| breakpoint != name);
+    if self.breakpoints.len() < count { format!("Deleted the breakpoint at {}.", name) } else { format!("There's no breakpoint at {}.", name) }
+  }
+
+  pub fn list(&self, machine: &Machine) -> String {
+    if self.breakpoints.is_empty() {
+      "There are no breakpoints.".to_string()
+    } else {
+      self.breakpoints.iter().map(|(name, pc)| format!("{} at {}", name, machine.location(*pc))).collect::<Vec<String>>().join("\n")
+    }
+  }
+
+  // The name of the breakpoint at a command
+  pub fn at(&self, pc: usize) -> Option<&str> {
+    self.breakpoints.iter().find(|(_, breakpoint)| *breakpoint == pc).map(|(name, _)| name.as_str())
+  }
+}
+
+impl Debugger {
+  pub fn new(files: Vec<(String, Vec<Located<Instruction>>)>) -> Self {
+    Debugger { machine: Machine::new(files.clone()), files, breakpoints: Breakpoints::default(), halted: false }
+  }
+
+  // Where the program is paused, for the first prompt
+  pub fn status(&self) -> String {
+    match self.machine.current() {
+      Some(instruction) if !self.halted => format!("{}  {}", self.machine.location(self.machine.pc), instruction.value),
+      _ => "The program halted.".to_string(),
+    }
+  }
+
+  // Runs one debugger command and returns what to show
+  pub fn execute(&mut self, line: &str) -> String {
+    let words = line.split_whitespace().collect::<Vec<&str>>();
+    match words.as_slice() {
+      [] =>
+        String::new(),
+      ["help"] =>
+        HELP.to_string(),
+      ["break"] | ["b"] =>
+        self.breakpoints.list(&self.machine),
+      ["break", name] | ["b", name] =>
+        self.breakpoints.add(&self.machine, name),
+      ["delete", name] =>
+        self.breakpoints.delete(name),
+      ["continue"] | ["c"] | ["run"] =>
+        self.resume(|_| false),
+      ["step"] | ["s"] =>
+        self.resume(|_| true),
+      ["next"] | ["n"] => {
+        let depth = self.machine.frames.len();
+        self.resume(move |machine| machine.frames.len() <= depth)
+      }
+      ["finish"] => {
+        let depth = self.machine.frames.len();
+        if depth == 0 {
+          return "There's no function to finish outside of functions.".to_string();
+        }
+        let function = self.machine.frames[depth - 1].function.clone();
+        let output = self.resume(move |machine| machine.frames.len() < depth);
+        if self.machine.frames.len() < depth {
+          let value = self.machine.working_stack().last().copied().unwrap_or(0);
+          format!("{} returned {}\n{}", function, value, output)
+        } else {
+          output
+        }
+      }
+      ["where"] | ["backtrace"] | ["bt"] =>
+        self.backtrace(),
+      ["stack"] =>
+        format!("stack: {}", show(self.machine.working_stack())),
+      ["local"] =>
+        match self.machine.frames.last() {
+          Some(frame) => self.segment("local", 1, frame.local_vars),
+          None => "There are no locals outside of functions.".to_string(),
+        },
+      ["argument"] =>
+        match self.machine.frames.last() {
+          Some(frame) => self.segment("argument", 2, frame.args),
+          None => "There are no arguments outside of functions.".to_string(),
+        },
+      [segment @ "this"] | [segment @ "that"] =>
+        self.segment(segment, if *segment == "this" { 3 } else { 4 }, DEFAULT_COUNT),
+      [segment @ "this", count] | [segment @ "that", count] =>
+        match count.parse::<usize>() {
+          Ok(count) => self.segment(segment, if *segment == "this" { 3 } else { 4 }, count),
+          Err(_) => format!("I'm expecting a number of entries after `{}` but found `{}`.", segment, count),
+        },
+      ["print", name] | ["p", name] =>
+        self.print_static(name),
+      ["restart"] => {
+        self.machine = Machine::new(self.files.clone());
+        self.halted = false;
+        self.status()
+      }
+      _ =>
+        format!("I don't know the command `{}`.\nTry `help` to see the commands.", line.trim()),
+    }
+  }
+
+  // Runs at least one command, then until `done` says so, a breakpoint, the
+  // end of the program or `MAX_STEPS`
+  fn resume(&mut self, done: impl Fn(&Machine) -> bool) -> String {
+    if self.halted {
+      return "The program halted. Try `restart` to run it again.".to_string();
+    }
+    for _ in 0..MAX_STEPS {
+      match self.machine.step() {
+        Err(error) => {
+          self.halted = true;
+          return error;
+        }
+        Ok(State::Halted) => {
+          self.halted = true;
+          return format!("The program halted after {} commands.", self.machine.steps);
+        }
+        Ok(State::Running) => {}
+      }
+      if let Some(name) = self.breakpoints.at(self.machine.pc) {
+        return format!("Breakpoint at {}\n{}", name, self.status());
+      }
+      if done(&self.machine) {
+        return self.status();
+      }
+    }
+    format!("Paused after {} commands without reaching a breakpoint.\n{}", MAX_STEPS, self.status())
+  }
+
+  fn backtrace(&self) -> String {
+    let mut lines = vec![];
+    let mut pc = Some(self.machine.pc);
+    for (depth, frame) in self.machine.frames.iter().rev().enumerate() {
+      lines.push(match pc.filter(|pc| *pc < self.machine.commands.len()) {
+        Some(pc) => format!("#{} {} at {}", depth, frame.function, self.machine.location(pc)),
+        None => format!("#{} {}", depth, frame.function),
+      });
+      pc = frame.call_site;
+    }
+    if self.machine.frames.is_empty() && self.machine.current().is_some() {
+      lines.push(format!("#0 (top level) at {}", self.machine.location(self.machine.pc)));
+    }
+    lines.join("\n")
+  }
+
+  fn segment(&self, name: &str, pointer: usize, count: usize) -> String {
+    let base = self.machine.pointer(pointer);
+    let values = (0..count).map(|offset| self.machine.ram[(base + offset) % self.machine.ram.len()]).collect::<Vec<i16>>();
+    format!("{}: {}", name, show(&values))
+  }
+
+  fn print_static(&self, name: &str) -> String {
+    let static_name = name.rsplit_once('.').and_then(|(program_name, offset)| offset.parse::<usize>().ok().map(|offset| (program_name, offset)));
+    match static_name {
+      Some((program_name, offset)) =>
+        match self.machine.static_address(program_name, offset) {
+          Some(address) => format!("{} = {}", name, self.machine.ram[address]),
+          None => format!("{}.vm doesn't use static {}.", program_name, offset),
+        },
+      None =>
+        format!("I'm expecting a static like `Main.0` but found `{}`.", name),
+    }
+  }
+}
+
+// Values like `[3, 4]`
+pub fn show(values: &[i16]) -> String {
+  format!("[{}]", values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", "))
+}
+
+#[cfg(test)]
+mod test {
+  use crate::debugger::*;
+  use crate::test_support::located_program;
+
+  #[test]
+  fn test_session() {
+    let sources = [
+      ("Sys", "function Sys.init 0\npush constant 3\npush constant 4\ncall Main.add 2\npop static 0\nlabel HALT\ngoto HALT\n"),
+      ("Main",
+"// Adds two numbers
+function Main.add 1
+push argument 0
+push argument 1
+add
+pop local 0
+goto DONE
+label DONE
+push local 0
+return
+"),
+    ];
+    let mut debugger = Debugger::new(located_program(&sources));
+    let session = [
+      "break Main.add",
+      "break DONE",
+      "break Nowhere",
+      "continue",
+      "argument",
+      "where",
+      "next",
+      "next",
+      "next",
+      "stack",
+      "continue",
+      "local",
+      "finish",
+      "print Sys.0",
+      "print Sys.1",
+      "continue",
+      "step",
+      "jump",
+    ].iter().map(|line| format!("> {}\n{}", line, debugger.execute(line))).collect::<Vec<String>>().join("\n");
+    assert_eq!(debugger.status(), "The program halted.");
+    assert_eq!(session,
+"> break Main.add
+Breakpoint at Main.add in Main.vm:2
+> break DONE
+Breakpoint at DONE in Main.vm:8
+> break Nowhere
+I can't find a function or label called Nowhere.
+> continue
+Breakpoint at Main.add
+Main.vm:2  function Main.add 1
+> argument
+argument: [3, 4]
+> where
+#0 Main.add at Main.vm:2
+#1 Sys.init at Sys.vm:4
+> next
+Main.vm:3  push argument 0
+> next
+Main.vm:4  push argument 1
+> next
+Main.vm:5  add
+> stack
+stack: [3, 4]
+> continue
+Breakpoint at DONE
+Main.vm:8  label DONE
+> local
+local: [7]
+> finish
+Main.add returned 7
+Sys.vm:5  pop static 0
+> print Sys.0
+Sys.0 = 0
+> print Sys.1
+Sys.vm doesn't use static 1.
+> continue
+The program halted after 16 commands.
+> step
+The program halted. Try `restart` to run it again.
+> jump
+I don't know the command `jump`.
+Try `help` to see the commands.");
+  }
+}
@@ -0,0 +1,409 @@
+use crate::hack_emulator::{Emulator, RAM_SIZE};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+// The largest packet we accept, told to the debugger in `qSupported`
+const PACKET_SIZE: usize = 4096;
+// How many instructions `continue` runs between checks for a Ctrl-C
+const INTERRUPT_INTERVAL: usize = 100_000;
+// Sent by the debugger outside of any packet to stop a running program
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Registers in the order of `g` and the numbers of `p` and `P`
+const TARGET_XML: &str =
+r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nand2tetris.hack">
+    <reg name="a" bitsize="16" type="int16" regnum="0"/>
+    <reg name="d" bitsize="16" type="int16" regnum="1"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="2"/>
+  </feature>
+</target>
+"#;
+
+// Lets debuggers speaking the GDB remote serial protocol drive the emulator.
+// Registers are A, D and PC, 16 bits each in little-endian. Memory is the RAM
+// with RAM[n] in bytes 2n and 2n + 1, so `x/2dh 0` in gdb shows SP and LCL.
+// Breakpoints are ROM addresses like the PC.
+pub struct GdbStub {
+  pub emulator: Emulator,
+  breakpoints: HashSet<usize>,
+}
+
+impl GdbStub {
+  pub fn new(emulator: Emulator) -> Self {
+    GdbStub { emulator, breakpoints: HashSet::new() }
+  }
+
+  // Answers the packets of one connection until the debugger detaches, kills
+  // the program or hangs up
+  pub fn serve(&mut self, mut stream: TcpStream) -> Result<(), String> {
+    // Packets are tiny and each waits for an answer
+    let _ = stream.set_nodelay(true);
+    while let Some(packet) = read_packet(&mut stream)? {
+      match self.handle(&packet, &mut || interrupted(&stream)) {
+        Some(reply) => write_packet(&mut stream, &reply)?,
+        None => break,
+      }
+      if packet.starts_with('D') {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  // Returns the reply to a packet without its framing, or None to end the
+  // session without replying. Empty replies tell the debugger we don't support
+  // the packet.
+  pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+    let mut chars = packet.chars();
+    let command = chars.next();
+    let rest = chars.as_str();
+    let reply = match command {
+      Some('?') =>
+        stop(SIGTRAP),
+      Some('g') =>
+        (0..3).map(|register| hex_word(self.register(register).unwrap())).collect(),
+      Some('G') =>
+        match hex_bytes(rest) {
+          Some(bytes) if bytes.len() == 6 => {
+            for register in 0..3 {
+              self.set_register(register, word(&bytes[2 * register..]));
+            }
+            "OK".to_string()
+          }
+          _ => error(),
+        },
+      Some('p') =>
+        usize::from_str_radix(rest, 16).ok()
+          .and_then(|register| self.register(register))
+          .map_or_else(error, hex_word),
+      Some('P') =>
+        match rest.split_once('=').and_then(|(register, value)| Some((usize::from_str_radix(register, 16).ok()?, hex_bytes(value)?))) {
+          Some((register, value)) if register < 3 && value.len() == 2 => {
+            self.set_register(register, word(&value));
+            "OK".to_string()
+          }
+          _ => error(),
+        },
+      Some('m') =>
+        address_and_length(rest)
+          .and_then(|(address, length)| self.read_memory(address, length))
+          .unwrap_or_else(error),
+      Some('M') =>
+        match rest.split_once(':').and_then(|(range, data)| Some((address_and_length(range)?, hex_bytes(data)?))) {
+          Some(((address, length), bytes)) if length == bytes.len() && self.write_memory(address, &bytes) =>
+            "OK".to_string(),
+          _ => error(),
+        },
+      Some(command @ 'c') | Some(command @ 's') => {
+        if !rest.is_empty() {
+          match usize::from_str_radix(rest, 16) {
+            Ok(address) => self.emulator.pc = address,
+            Err(_) => return Some(error()),
+          }
+        }
+        self.resume(command == 's', interrupted)
+      }
+      Some(command @ 'Z') | Some(command @ 'z') => {
+        let fields = rest.split(',').collect::<Vec<&str>>();
+        match fields.as_slice() {
+          // Software and hardware breakpoints are the same to us
+          ["0", address, _] | ["1", address, _] =>
+            match usize::from_str_radix(address, 16) {
+              Ok(address) => {
+                if command == 'Z' {
+                  self.breakpoints.insert(address);
+                } else {
+                  self.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+              }
+              Err(_) => error(),
+            },
+          _ => String::new(),
+        }
+      }
+      Some('q') =>
+        query(rest),
+      Some('H') | Some('D') =>
+        "OK".to_string(),
+      Some('k') =>
+        return None,
+      _ =>
+        String::new(),
+    };
+    Some(reply)
+  }
+
+  // Runs one instruction for a step, otherwise until a breakpoint, the program
+  // halts or the debugger interrupts
+  fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+    let mut executed: usize = 0;
+    loop {
+      if (!step && self.emulator.halted()) || !self.emulator.step() {
+        return "W00".to_string();
+      }
+      executed += 1;
+      if step || self.breakpoints.contains(&self.emulator.pc) {
+        return stop(SIGTRAP);
+      }
+      if executed.is_multiple_of(INTERRUPT_INTERVAL) && interrupted() {
+        return stop(SIGINT);
+      }
+    }
+  }
+
+  fn register(&self, register: usize) -> Option<u16> {
+    match register {
+      0 => Some(self.emulator.a as u16),
+      1 => Some(self.emulator.d as u16),
+      2 => Some(self.emulator.pc as u16),
+      _ => None,
+    }
+  }
+
+  fn set_register(&mut self, register: usize, value: u16) {
+    match register {
+      0 => self.emulator.a = value as i16,
+      1 => self.emulator.d = value as i16,
+      _ => self.emulator.pc = value as usize,
+    }
+  }
+
+  fn read_memory(&self, address: usize, length: usize) -> Option<String> {
+    (address..address.saturating_add(length)).map(|byte|
+      self.emulator.ram.get(byte / 2).map(|word| format!("{:02x}", (*word as u16 >> (8 * (byte % 2))) as u8))
+    ).collect()
+  }
+
+  fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+    if address.saturating_add(bytes.len()) > 2 * RAM_SIZE {
+      return false;
+    }
+    for (index, byte) in bytes.iter().enumerate() {
+      let shift = 8 * ((address + index) % 2);
+      let word = &mut self.emulator.ram[(address + index) / 2];
+      *word = ((*word as u16 & !(0xff << shift)) | (*byte as u16) << shift) as i16;
+    }
+    true
+  }
+}
+
+fn query(query: &str) -> String {
+  if query.starts_with("Supported") {
+    format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+  } else if query == "Attached" {
+    "1".to_string()
+  } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+    match address_and_length(range) {
+      Some((offset, _)) if offset >= TARGET_XML.len() =>
+        "l".to_string(),
+      Some((offset, length)) => {
+        let end = TARGET_XML.len().min(offset.saturating_add(length));
+        format!("{}{}", if end < TARGET_XML.len() { "m" } else { "l" }, &TARGET_XML[offset..end])
+      }
+      None => error(),
+    }
+  } else {
+    String::new()
+  }
+}
+
+// Reads the next packet and acknowledges it, or None once the debugger hangs up.
+// Skips acknowledgements and interrupts that arrive while the program is stopped.
+pub fn read_packet(stream: &mut TcpStream) -> Result<Option<String>, String> {
+  loop {
+    loop {
+      match read_byte(stream)? {
+        Some(b'$') => break,
+        Some(_) => {}
+        None => return Ok(None),
+      }
+    }
+    let mut data = vec![];
+    loop {
+      match read_byte(stream)? {
+        Some(b'#') => break,
+        Some(byte) => data.push(byte),
+        None => return Ok(None),
+      }
+    }
+    let checksum = match (read_byte(stream)?, read_byte(stream)?) {
+      (Some(high), Some(low)) => std::str::from_utf8(&[high, low]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+      _ => return Ok(None),
+    };
+    let data = unescape(&data);
+    if checksum == Some(sum(&data)) {
+      send(stream, b"+")?;
+      return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+    }
+    // Asks the debugger to send it again
+    send(stream, b"-")?;
+  }
+}
+
+pub fn write_packet(stream: &mut TcpStream, data: &str) -> Result<(), String> {
+  let escaped = data.bytes().flat_map(|byte| match byte {
+    b'$' | b'#' | b'}' | b'*' => vec![b'}', byte ^ 0x20],
+    _ => vec![byte],
+  }).collect::<Vec<u8>>();
+  let mut packet = vec![b'$'];
+  packet.extend_from_slice(&escaped);
+  packet.extend_from_slice(format!("#{:02x}", sum(&escaped)).as_bytes());
+  send(stream, &packet)
+}
+
+// Whether the debugger sent a Ctrl-C, without waiting for it
+fn interrupted(mut stream: &TcpStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return false;
+  }
+  let mut byte = [0];
+  let result = stream.read(&mut byte);
+  let _ = stream.set_nonblocking(false);
+  matches!(result, Ok(1) if byte[0] == INTERRUPT)
+}
+
+fn read_byte(stream: &mut TcpStream) -> Result<Option<u8>, String> {
+  let mut byte = [0];
+  loop {
+    return match stream.read(&mut byte) {
+      Ok(0) => Ok(None),
+      Ok(_) => Ok(Some(byte[0])),
+      Err(why) if why.kind() == ErrorKind::Interrupted => continue,
+      Err(why) if why.kind() == ErrorKind::ConnectionReset => Ok(None),
+      Err(why) => Err(format!("I couldn't read from the debugger: {}.", why)),
+    };
+  }
+}
+
+fn send(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), String> {
+  stream.write_all(bytes).map_err(|why| format!("I couldn't write to the debugger: {}.", why))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+  let mut bytes = vec![];
+  let mut escaped = false;
+  for byte in data.iter() {
+    if escaped {
+      bytes.push(byte ^ 0x20);
+      escaped = false;
+    } else if *byte == b'}' {
+      escaped = true;
+    } else {
+      bytes.push(*byte);
+    }
+  }
+  bytes
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+  bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn stop(signal: u8) -> String {
+  format!("S{:02x}", signal)
+}
+
+fn error() -> String {
+  "E01".to_string()
+}
+
+// `ADDRESS,LENGTH` in hex
+fn address_and_length(text: &str) -> Option<(usize, usize)> {
+  let (address, length) = text.split_once(',')?;
+  Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+  if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok()).collect()
+}
+
+fn hex_word(word: u16) -> String {
+  format!("{:02x}{:02x}", word & 0xff, word >> 8)
+}
+
+fn word(bytes: &[u8]) -> u16 {
+  bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+#[cfg(test)]
+mod test {
+  use crate::gdb_stub::*;
+  use crate::hack_assembler::assemble;
+  use std::net::TcpListener;
+  use std::thread;
+
+  fn request(client: &mut TcpStream, packet: &str) -> String {
+    write_packet(client, packet).unwrap();
+    read_packet(client).unwrap().unwrap()
+  }
+
+  #[test]
+  fn test_serve() {
+    let rom = assemble(
+"// RAM[2] = RAM[0] + RAM[1], then counts up in RAM[3] forever
+@R0
+D=M
+@R1
+D=D+M
+@R2
+M=D
+(LOOP)
+@R3
+M=M+1
+@LOOP
+0;JMP
+").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut stub = GdbStub::new(Emulator::new(rom));
+      stub.serve(stream).unwrap();
+      stub.emulator.ram[2]
+    });
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client.set_nodelay(true).unwrap();
+    assert_eq!(request(&mut client, "qSupported:multiprocess+"), "PacketSize=1000;qXfer:features:read+");
+    assert!(request(&mut client, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    assert!(request(&mut client, "qXfer:features:read:target.xml:1,ffffffffffffffff").starts_with("l?xml"));
+    assert_eq!(request(&mut client, "?"), "S05");
+    assert_eq!(request(&mut client, "g"), "000000000000");
+    // RAM[0] = 3 and RAM[1] = 4
+    assert_eq!(request(&mut client, "M0,4:03000400"), "OK");
+    assert_eq!(request(&mut client, "m0,4"), "03000400");
+    assert_eq!(request(&mut client, "m10000,2"), "E01");
+    assert_eq!(request(&mut client, "Z0,4,2"), "OK");
+    assert_eq!(request(&mut client, "c"), "S05");
+    // A = 1 and D = 7 at the breakpoint
+    assert_eq!(request(&mut client, "g"), "010007000400");
+    assert_eq!(request(&mut client, "s"), "S05");
+    assert_eq!(request(&mut client, "p2"), "0500");
+    assert_eq!(request(&mut client, "m4,2"), "0000");
+    assert_eq!(request(&mut client, "s"), "S05");
+    assert_eq!(request(&mut client, "m4,2"), "0700");
+    assert_eq!(request(&mut client, "z0,4,2"), "OK");
+    assert_eq!(request(&mut client, "vMustReplyEmpty"), "");
+
+    // The loop never ends without a Ctrl-C
+    write_packet(&mut client, "c").unwrap();
+    client.write_all(&[INTERRUPT]).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap().unwrap(), "S02");
+    assert_ne!(request(&mut client, "m6,2"), "0000");
+    // Past the end of the ROM
+    assert_eq!(request(&mut client, "P2=0a00"), "OK");
+    assert_eq!(request(&mut client, "c"), "W00");
+    write_packet(&mut client, "k").unwrap();
+    assert_eq!(server.join().unwrap(), 7);
+  }
+}
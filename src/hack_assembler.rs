@@ -0,0 +1,137 @@
+use lip::{display_error, Location};
+use std::collections::HashMap;
+
+// The a-bit followed by the six c-bits of every computation.
+// The first spelling of each computation is the canonical one.
+pub const COMPUTATIONS: [(&str, u16); 37] = [
+  ("0", 0b0101010), ("1", 0b0111111), ("-1", 0b0111010),
+  ("D", 0b0001100), ("A", 0b0110000), ("!D", 0b0001101),
+  ("!A", 0b0110001), ("-D", 0b0001111), ("-A", 0b0110011),
+  ("D+1", 0b0011111), ("A+1", 0b0110111), ("D-1", 0b0001110),
+  ("A-1", 0b0110010), ("D+A", 0b0000010), ("D-A", 0b0010011),
+  ("A-D", 0b0000111), ("D&A", 0b0000000), ("D|A", 0b0010101),
+  ("M", 0b1110000), ("!M", 0b1110001), ("-M", 0b1110011),
+  ("M+1", 0b1110111), ("M-1", 0b1110010), ("D+M", 0b1000010),
+  ("D-M", 0b1010011), ("M-D", 0b1000111), ("D&M", 0b1000000),
+  ("D|M", 0b1010101),
+  // Commutative spellings used by the emitter
+  ("A+D", 0b0000010), ("A&D", 0b0000000), ("A|D", 0b0010101),
+  ("M+D", 0b1000010), ("M&D", 0b1000000), ("M|D", 0b1010101),
+  ("1+D", 0b0011111), ("1+A", 0b0110111), ("1+M", 0b1110111),
+];
+
+pub const JUMPS: [(&str, u16); 7] = [
+  ("JGT", 0b001), ("JEQ", 0b010), ("JGE", 0b011), ("JLT", 0b100),
+  ("JNE", 0b101), ("JLE", 0b110), ("JMP", 0b111),
+];
+
+pub const PREDEFINED_SYMBOLS: [(&str, u16); 23] = [
+  ("SP", 0), ("LCL", 1), ("ARG", 2), ("THIS", 3), ("THAT", 4),
+  ("R0", 0), ("R1", 1), ("R2", 2), ("R3", 3), ("R4", 4), ("R5", 5),
+  ("R6", 6), ("R7", 7), ("R8", 8), ("R9", 9), ("R10", 10), ("R11", 11),
+  ("R12", 12), ("R13", 13), ("R14", 14), ("R15", 15),
+  ("SCREEN", 16384), ("KBD", 24576),
+];
+
+// Variables are allocated from RAM[16] in order of first use
+pub const VARIABLE_BASE: u16 = 16;
+
+// @21
+// D=M;JGT
+pub fn assemble(source: &str) -> Result<Vec<u16>, String> {
+  let lines = source_lines(source);
+  let mut symbols = PREDEFINED_SYMBOLS.iter()
+    .map(|(name, address)| (name.to_string(), *address))
+    .collect::<HashMap<String, u16>>();
+  let mut address = 0;
+  for (row, line) in lines.iter() {
+    if let Some(label) = line.strip_prefix('(') {
+      match label.strip_suffix(')') {
+        Some(label) => { symbols.insert(label.to_string(), address); }
+        None => return Err(error(source, *row, line, "I found a label declaration without the closing `)`.")),
+      }
+    } else {
+      address += 1;
+    }
+  }
+  let mut next_variable = VARIABLE_BASE;
+  let mut words = Vec::new();
+  for (row, line) in lines.iter() {
+    if line.starts_with('(') {
+      continue;
+    }
+    if let Some(value) = line.strip_prefix('@') {
+      let word = match value.parse::<u16>() {
+        Ok(number) if number < 0x8000 => number,
+        Ok(_) => return Err(error(source, *row, line, "I found an address that is too large. A-instructions can only hold numbers from 0 to 32767.")),
+        Err(_) => match symbols.get(value) {
+          Some(address) => *address,
+          None => {
+            symbols.insert(value.to_string(), next_variable);
+            next_variable += 1;
+            next_variable - 1
+          }
+        },
+      };
+      words.push(word);
+    } else {
+      words.push(assemble_computation(source, *row, line)?);
+    }
+  }
+  Ok(words)
+}
+
+// dest=comp;jump
+pub(crate) fn assemble_computation(source: &str, row: usize, line: &str) -> Result<u16, String> {
+  let (dest, rest) = match line.find('=') {
+    Some(index) => (&line[..index], &line[index + 1..]),
+    None => ("", line),
+  };
+  let (comp, jump) = match rest.find(';') {
+    Some(index) => (&rest[..index], &rest[index + 1..]),
+    None => (rest, ""),
+  };
+  let comp_bits = match COMPUTATIONS.iter().find(|(name, _)| *name == comp) {
+    Some((_, bits)) => *bits,
+    None => return Err(error(source, row, line, &format!("I can't find a computation instruction matching `{}`.\nTry something like `D+1` and `0`.", comp))),
+  };
+  let mut dest_bits = 0;
+  for register in dest.chars() {
+    dest_bits |= match register {
+      'A' => 0b100,
+      'D' => 0b010,
+      'M' => 0b001,
+      _ => return Err(error(source, row, line, &format!("I found an invalid destination `{}`. Destinations can only combine `A`, `D` and `M`.", dest))),
+    };
+  }
+  let jump_bits = if jump.is_empty() {
+    0
+  } else {
+    match JUMPS.iter().find(|(name, _)| *name == jump) {
+      Some((_, bits)) => *bits,
+      None => return Err(error(source, row, line, &format!("I found an invalid jump `{}`. Try one of JGT, JEQ, JGE, JLT, JNE, JLE or JMP.", jump))),
+    }
+  };
+  Ok(0b111 << 13 | comp_bits << 6 | dest_bits << 3 | jump_bits)
+}
+
+// Returns the 1-based row and trimmed content of every line holding an instruction or label
+fn source_lines(source: &str) -> Vec<(usize, String)> {
+  source.split('\n').enumerate().filter_map(|(index, line)| {
+    let line = match line.find("//") {
+      Some(comment_start) => &line[..comment_start],
+      None => line,
+    };
+    let line = line.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    if line.is_empty() {
+      None
+    } else {
+      Some((index + 1, line))
+    }
+  }).collect()
+}
+
+fn error(source: &str, row: usize, line: &str, message: &str) -> String {
+  let col = source.split('\n').nth(row - 1).map_or(1, |text| text.len() - text.trim_start().len() + 1);
+  display_error(source, message.to_string(), Location { row, col }, Location { row, col: col + line.len() })
+}
@@ -0,0 +1,83 @@
+use crate::hack_assembler::{COMPUTATIONS, JUMPS};
+use lip::{display_error, Location};
+use std::collections::HashSet;
+
+// Turns `.hack` machine code, one 16-bit binary word per line, back into Hack assembly.
+// Addresses loaded right before a jump get a generated label like `(L42)`
+// since they almost always point into the program.
+pub fn disassemble(source: &str) -> Result<String, String> {
+  let words = parse_words(source)?;
+  let jump_targets = words.iter().zip(words.iter().skip(1))
+    .filter(|(word, next_word)| is_address(**word) && !is_address(**next_word) && *next_word & 0b111 != 0)
+    .map(|(word, _)| *word as usize)
+    .filter(|address| *address < words.len())
+    .collect::<HashSet<usize>>();
+  let mut lines = Vec::new();
+  for (address, word) in words.iter().enumerate() {
+    if jump_targets.contains(&address) {
+      lines.push(format!("(L{})", address));
+    }
+    let is_jump_target_reference = is_address(*word)
+      && jump_targets.contains(&(*word as usize))
+      && words.get(address + 1).is_some_and(|next_word| !is_address(*next_word) && next_word & 0b111 != 0);
+    if is_jump_target_reference {
+      lines.push(format!("@L{}", word));
+    } else if is_address(*word) {
+      lines.push(format!("@{}", word));
+    } else {
+      lines.push(disassemble_computation(*word).ok_or_else(|| {
+        let row = source_row(source, address);
+        display_error(source,
+          format!("I found the word {:016b} whose computation bits don't match any Hack computation.", word),
+          Location { row, col: 1 }, Location { row, col: 17 })
+      })?);
+    }
+  }
+  Ok(lines.join("\n"))
+}
+
+fn is_address(word: u16) -> bool {
+  word & 0x8000 == 0
+}
+
+fn disassemble_computation(word: u16) -> Option<String> {
+  let comp_bits = (word >> 6) & 0b1111111;
+  let dest_bits = (word >> 3) & 0b111;
+  let jump_bits = word & 0b111;
+  let (comp, _) = COMPUTATIONS.iter().find(|(_, bits)| *bits == comp_bits)?;
+  let dest = [(0b100, 'A'), (0b010, 'D'), (0b001, 'M')].iter()
+    .filter(|(bit, _)| dest_bits & bit != 0)
+    .map(|(_, register)| register)
+    .collect::<String>();
+  let jump = JUMPS.iter().find(|(_, bits)| *bits == jump_bits).map(|(name, _)| *name);
+  Some(format!(
+    "{}{}{}",
+    if dest.is_empty() { String::new() } else { format!("{}=", dest) },
+    comp,
+    jump.map_or(String::new(), |jump| format!(";{}", jump)),
+  ))
+}
+
+fn parse_words(source: &str) -> Result<Vec<u16>, String> {
+  source.split('\n').enumerate()
+    .filter(|(_, line)| !line.trim().is_empty())
+    .map(|(index, line)| {
+      let line = line.trim();
+      if line.len() == 16 && line.chars().all(|c| c == '0' || c == '1') {
+        Ok(u16::from_str_radix(line, 2).unwrap())
+      } else {
+        let row = index + 1;
+        Err(display_error(source,
+          "I'm expecting a machine instruction made of 16 `0`s and `1`s.".to_string(),
+          Location { row, col: 1 }, Location { row, col: line.len().max(1) + 1 }))
+      }
+    }).collect()
+}
+
+// Finds the row of the `address`th non-empty line
+fn source_row(source: &str, address: usize) -> usize {
+  source.split('\n').enumerate()
+    .filter(|(_, line)| !line.trim().is_empty())
+    .nth(address)
+    .map_or(1, |(index, _)| index + 1)
+}
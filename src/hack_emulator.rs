@@ -0,0 +1,98 @@
+// 32K words of data memory, including the screen and keyboard maps
+pub const RAM_SIZE: usize = 32768;
+// `0;JMP`, the end of every `goto`
+const JUMP: u16 = 0b1110_1010_1000_0111;
+
+// The Hack CPU running machine code from the ROM. Addresses wrap around the RAM
+// like in the hardware, where A only has 15 address bits.
+pub struct Emulator {
+  pub rom: Vec<u16>,
+  pub ram: Vec<i16>,
+  pub a: i16,
+  pub d: i16,
+  pub pc: usize,
+}
+
+impl Emulator {
+  pub fn new(rom: Vec<u16>) -> Self {
+    Emulator { rom, ram: vec![0; RAM_SIZE], a: 0, d: 0, pc: 0 }
+  }
+
+  // Executes one instruction. Returns false without doing anything once the
+  // program counter is past the end of the ROM.
+  pub fn step(&mut self) -> bool {
+    let instruction = match self.rom.get(self.pc) {
+      Some(instruction) => *instruction,
+      None => return false,
+    };
+    if instruction & 0x8000 == 0 {
+      self.a = instruction as i16;
+      self.pc += 1;
+      return true;
+    }
+    let address = self.a as u16 as usize & (RAM_SIZE - 1);
+    let mut x = self.d;
+    let mut y = if instruction & 0x1000 != 0 { self.ram[address] } else { self.a };
+    // zx, nx, zy, ny, f and no
+    let control = (instruction >> 6) & 0x3f;
+    if control & 0x20 != 0 { x = 0; }
+    if control & 0x10 != 0 { x = !x; }
+    if control & 0x08 != 0 { y = 0; }
+    if control & 0x04 != 0 { y = !y; }
+    let mut out = if control & 0x02 != 0 { x.wrapping_add(y) } else { x & y };
+    if control & 0x01 != 0 { out = !out; }
+    if instruction & 0x08 != 0 { self.ram[address] = out; }
+    if instruction & 0x20 != 0 { self.a = out; }
+    if instruction & 0x10 != 0 { self.d = out; }
+    let jump = instruction & 0x7;
+    let jumps = (jump & 0x4 != 0 && out < 0) || (jump & 0x2 != 0 && out == 0) || (jump & 0x1 != 0 && out > 0);
+    self.pc = if jumps { self.a as u16 as usize } else { self.pc + 1 };
+    true
+  }
+
+  // Whether the program ran past the end of the ROM or sits in a loop of `@X`
+  // `0;JMP` at address X, which is how `label END` `goto END` halts a program
+  pub fn halted(&self) -> bool {
+    self.pc >= self.rom.len() || (self.rom[self.pc] as usize == self.pc && self.rom.get(self.pc + 1) == Some(&JUMP))
+  }
+
+  // Runs at most `cycles` instructions and returns how many ran
+  pub fn run(&mut self, cycles: usize) -> usize {
+    (0..cycles).take_while(|_| self.step()).count()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::hack_emulator::*;
+  use crate::hack_assembler::assemble;
+
+  #[test]
+  fn test_run() {
+    let rom = assemble(
+"// RAM[2] = max(RAM[0], RAM[1]) - 1
+@R0
+D=M
+@R1
+D=D-M
+@FIRST
+D;JGT
+@R1
+D=M
+@END
+0;JMP
+(FIRST)
+@R0
+D=M
+(END)
+@R2
+M=D-1
+").unwrap();
+    let mut emulator = Emulator::new(rom);
+    emulator.ram[0] = -7;
+    emulator.ram[1] = 12;
+    assert_eq!(emulator.run(100), 12);
+    assert_eq!(emulator.ram[2], 11);
+    assert!(!emulator.step());
+  }
+}
@@ -0,0 +1,299 @@
+use crate::vm_parser::*;
+use crate::memory_layout::MemoryLayout;
+use std::collections::{HashMap, HashSet};
+
+// A function whose body can replace its calls
+struct Candidate {
+  program_name: String,
+  local_vars: usize,
+  // The commands between `function` and the final `return`
+  body: Vec<Instruction>,
+}
+
+// Replaces calls to small functions with their bodies. Only straight-line leaf
+// functions with at most `threshold` commands, counting the `return`, are
+// inlined, like the accessors Jack classes are full of. The callee's arguments
+// and locals move to temp entries the program never uses, and THIS and THAT are
+// saved around bodies that set them, so the caller sees the same memory as after
+// a real call. Calls that can't be inlined, for example when there aren't
+// enough free temp entries in `layout`, are kept. The functions themselves stay
+// in place.
+pub fn inline(files: &[(String, Vec<Instruction>)], threshold: usize, layout: &MemoryLayout) -> Vec<(String, Vec<Instruction>)> {
+  let used_temps = files.iter().flat_map(|(_, instructions)| instructions.iter()).filter_map(|instruction| match instruction {
+    Instruction::Push { segment: Segment::Temp, offset } | Instruction::Pop { segment: Segment::Temp, offset } => Some(*offset),
+    _ => None,
+  }).collect::<HashSet<usize>>();
+  let free_temps = (0..layout.temp_size).filter(|offset| !used_temps.contains(offset)).collect::<Vec<usize>>();
+  let candidates = collect_candidates(files, threshold);
+  files.iter().map(|(program_name, instructions)| {
+    let instructions = instructions.iter().flat_map(|instruction| match instruction {
+      Instruction::Call { name, args } =>
+        candidates.get(name)
+          .and_then(|candidate| expand(candidate, program_name, *args, &free_temps))
+          .unwrap_or_else(|| vec![instruction.clone()]),
+      _ =>
+        vec![instruction.clone()],
+    }).collect();
+    (program_name.clone(), instructions)
+  }).collect()
+}
+
+fn collect_candidates(files: &[(String, Vec<Instruction>)], threshold: usize) -> HashMap<String, Candidate> {
+  let mut candidates = HashMap::new();
+  for (program_name, instructions) in files.iter() {
+    let instructions = instructions.iter().filter(|instruction| !matches!(instruction, Instruction::Ignored)).collect::<Vec<&Instruction>>();
+    for (start, instruction) in instructions.iter().enumerate() {
+      if let Instruction::Function { name, local_vars } = instruction {
+        let body = instructions[start + 1..].iter()
+          .take_while(|instruction| !matches!(instruction, Instruction::Function { .. }))
+          .map(|instruction| (*instruction).clone())
+          .collect::<Vec<Instruction>>();
+        if body.len() <= threshold && is_inlinable(&body, *local_vars) {
+          candidates.insert(name.clone(), Candidate {
+            program_name: program_name.clone(),
+            local_vars: *local_vars,
+            body: body[..body.len() - 1].to_vec(),
+          });
+        }
+      }
+    }
+  }
+  candidates
+}
+
+// The body has to end in its only `return` with just the return value on the stack,
+// without jumps, calls or locals outside the frame
+fn is_inlinable(body: &[Instruction], local_vars: usize) -> bool {
+  let mut depth: usize = 0;
+  for (index, instruction) in body.iter().enumerate() {
+    let (pops, pushes) = match instruction {
+      Instruction::Return => return index == body.len() - 1 && depth == 1,
+      Instruction::Push { segment: Segment::Local, offset } | Instruction::Pop { segment: Segment::Local, offset } if *offset >= local_vars =>
+        return false,
+      Instruction::Push { .. } => (0, 1),
+      Instruction::Pop { .. } => (1, 0),
+      Instruction::Arithmetic(ArithInstruction::Neg) | Instruction::Arithmetic(ArithInstruction::Not) => (1, 1),
+      Instruction::Arithmetic(_) => (2, 1),
+      _ => return false,
+    };
+    depth = match depth.checked_sub(pops) {
+      Some(depth) => depth + pushes,
+      None => return false,
+    };
+  }
+  false
+}
+
+fn expand(candidate: &Candidate, program_name: &str, args: usize, free_temps: &[usize]) -> Option<Vec<Instruction>> {
+  let mut uses_statics = false;
+  let mut saved_pointers = Vec::new();
+  for instruction in candidate.body.iter() {
+    match instruction {
+      Instruction::Push { segment: Segment::Argument, offset } | Instruction::Pop { segment: Segment::Argument, offset } if *offset >= args =>
+        return None,
+      Instruction::Push { segment: Segment::Static, .. } | Instruction::Pop { segment: Segment::Static, .. } =>
+        uses_statics = true,
+      Instruction::Pop { segment: Segment::Pointer, offset } if !saved_pointers.contains(offset) =>
+        saved_pointers.push(*offset),
+      _ => {}
+    }
+  }
+  // Statics belong to the file of the function
+  if uses_statics && candidate.program_name != program_name {
+    return None;
+  }
+  if args + candidate.local_vars + saved_pointers.len() > free_temps.len() {
+    return None;
+  }
+  let (argument_temps, rest) = free_temps.split_at(args);
+  let (local_temps, pointer_temps) = rest.split_at(candidate.local_vars);
+
+  let mut expanded = Vec::new();
+  for argument_temp in argument_temps.iter().rev() {
+    expanded.push(Instruction::Pop { segment: Segment::Temp, offset: *argument_temp });
+  }
+  for local_temp in local_temps.iter() {
+    expanded.push(Instruction::Push { segment: Segment::Constant, offset: 0 });
+    expanded.push(Instruction::Pop { segment: Segment::Temp, offset: *local_temp });
+  }
+  for (pointer, pointer_temp) in saved_pointers.iter().zip(pointer_temps.iter()) {
+    expanded.push(Instruction::Push { segment: Segment::Pointer, offset: *pointer });
+    expanded.push(Instruction::Pop { segment: Segment::Temp, offset: *pointer_temp });
+  }
+  for instruction in candidate.body.iter() {
+    expanded.push(match instruction {
+      Instruction::Push { segment: Segment::Argument, offset } => Instruction::Push { segment: Segment::Temp, offset: argument_temps[*offset] },
+      Instruction::Pop { segment: Segment::Argument, offset } => Instruction::Pop { segment: Segment::Temp, offset: argument_temps[*offset] },
+      Instruction::Push { segment: Segment::Local, offset } => Instruction::Push { segment: Segment::Temp, offset: local_temps[*offset] },
+      Instruction::Pop { segment: Segment::Local, offset } => Instruction::Pop { segment: Segment::Temp, offset: local_temps[*offset] },
+      _ => instruction.clone(),
+    });
+  }
+  for (pointer, pointer_temp) in saved_pointers.iter().zip(pointer_temps.iter()) {
+    expanded.push(Instruction::Push { segment: Segment::Temp, offset: *pointer_temp });
+    expanded.push(Instruction::Pop { segment: Segment::Pointer, offset: *pointer });
+  }
+  Some(expanded)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::inliner::*;
+  use crate::test_support::*;
+  use std::process::Command;
+
+  // Runs the program through the C backend and prints the first statics, or None without gcc
+  fn run(files: Vec<(String, Vec<Instruction>)>) -> Option<String> {
+    let harness =
+"#define VM_NO_MAIN
+#define VM_MAX_STEPS 1000000
+#include \"program.c\"
+int main(void) {
+  vm_run();
+  printf(\"%d %d %d %d %d\\n\", ram[16], ram[17], ram[18], ram[19], ram[3]);
+  return 0;
+}
+";
+    let program = crate::c_emitter::emit_program(files);
+    run_if_installed("gcc", "the inlined program", &[("program.c", program.as_bytes()), ("harness.c", harness.as_bytes())], |directory| {
+      let status = Command::new("gcc")
+        // Inlined functions may no longer be called
+        .args(["-Wall", "-Werror", "-Wno-unused-label", "-o"])
+        .arg(directory.join("harness"))
+        .arg(directory.join("harness.c"))
+        .status()?;
+      assert!(status.success());
+      Ok(String::from_utf8(Command::new(directory.join("harness")).output()?.stdout).unwrap())
+    })
+  }
+
+  #[test]
+  fn test_inline() {
+    let sys =
+"function Sys.init 0
+push constant 4000
+pop pointer 0
+push constant 11
+push constant 3000
+pop pointer 1
+pop that 0
+push constant 22
+pop that 1
+push constant 3000
+call Point.getx 1
+push constant 3000
+call Point.gety 1
+call Sys.add 2
+pop static 0
+push constant 7
+call Sys.triple 1
+pop static 1
+push constant 6
+call Sys.countdown 1
+pop static 2
+push constant 3000
+call Point.gety 1
+push constant 100
+call Sys.add 2
+pop static 3
+label HALT
+goto HALT
+function Sys.add 0
+push argument 0
+push argument 1
+add
+return
+function Sys.triple 1
+push argument 0
+push argument 0
+add
+pop local 0
+push local 0
+push argument 0
+add
+return
+function Sys.countdown 0
+push argument 0
+if-goto MORE
+push constant 0
+return
+label MORE
+push argument 0
+push constant 1
+sub
+call Sys.countdown 1
+push constant 1
+add
+return
+";
+    let point =
+"function Point.getx 0
+push argument 0
+pop pointer 0
+push this 0
+return
+function Point.gety 0
+push argument 0
+pop pointer 0
+push this 1
+return
+";
+    let files = crate::parse_program(&[("Sys".to_string(), sys.to_string()), ("Point".to_string(), point.to_string())]).unwrap();
+    let inlined = inline(&files, 8, &MemoryLayout::default());
+    let calls = |files: &[(String, Vec<Instruction>)]| files.iter().flat_map(|(_, instructions)| instructions.iter())
+      .filter_map(|instruction| match instruction {
+        Instruction::Call { name, .. } => Some(name.clone()),
+        _ => None,
+      }).collect::<Vec<String>>();
+    // Sys.countdown isn't straight-line
+    assert_eq!(calls(&inlined), vec!["Sys.countdown", "Sys.countdown"]);
+    assert_eq!(calls(&inline(&files, 4, &MemoryLayout::default())), vec!["Sys.triple", "Sys.countdown", "Sys.countdown"]);
+    // push constant 3000
+    // call Point.getx 1
+    assert_eq!(inlined[0].1[10..18].to_vec(), crate::vm_parser::parse(
+"pop temp 0
+push pointer 0
+pop temp 1
+push temp 0
+pop pointer 0
+push this 0
+push temp 1
+pop pointer 0
+").unwrap());
+
+    if let (Some(expected), Some(actual)) = (run(files), run(inlined)) {
+      assert_eq!(expected, "33 21 6 122 4000\n");
+      assert_eq!(actual, expected);
+    }
+  }
+
+  #[test]
+  fn test_inline_layout() {
+    let sys =
+"function Sys.init 0
+push constant 1
+pop temp 0
+push constant 2
+pop temp 1
+push temp 0
+push temp 1
+call Sys.add 2
+pop static 0
+label HALT
+goto HALT
+function Sys.add 0
+push argument 0
+push argument 1
+add
+return
+";
+    let files = crate::parse_program(&[("Sys".to_string(), sys.to_string())]).unwrap();
+    let layout = MemoryLayout { temp_size: 2, ..MemoryLayout::default() };
+    // Both temp entries are taken so the call stays
+    let inlined = inline(&files, 8, &layout);
+    assert_eq!(inlined, files);
+    assert_eq!(layout.check_program(&inlined), Ok(()));
+    // The default layout has room for the arguments
+    assert_ne!(inline(&files, 8, &MemoryLayout::default()), files);
+  }
+}
@@ -0,0 +1,464 @@
+use crate::jack_parser::*;
+use crate::vm_parser::{ArithInstruction, Instruction, Segment};
+use lip::{display_error, Located};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+struct Variable {
+  segment: Segment,
+  index: usize,
+  variable_type: Type,
+}
+
+struct Generator<'a> {
+  source: &'a str,
+  class: &'a Class,
+  class_variables: HashMap<String, Variable>,
+  subroutine_variables: HashMap<String, Variable>,
+  subroutine: &'a Subroutine,
+  label_index: usize,
+  instructions: Vec<Instruction>,
+}
+
+// Translates a parsed class into VM instructions. `source` is only used for error messages.
+pub fn generate(source: &str, class: &Class) -> Result<Vec<Instruction>, String> {
+  let mut class_variables = HashMap::new();
+  let mut static_index = 0;
+  let mut field_index = 0;
+  for variable in class.variables.iter() {
+    let (segment, index) = match variable.kind {
+      ClassVariableKind::Static => {
+        static_index += 1;
+        (Segment::Static, static_index - 1)
+      }
+      ClassVariableKind::Field => {
+        field_index += 1;
+        (Segment::This, field_index - 1)
+      }
+    };
+    class_variables.insert(variable.name.clone(), Variable { segment, index, variable_type: variable.variable_type.clone() });
+  }
+  let mut instructions = Vec::new();
+  for subroutine in class.subroutines.iter() {
+    let mut generator = Generator {
+      source,
+      class,
+      class_variables: class_variables.clone(),
+      subroutine_variables: HashMap::new(),
+      subroutine,
+      label_index: 0,
+      instructions: Vec::new(),
+    };
+    generator.subroutine(field_index)?;
+    instructions.append(&mut generator.instructions);
+  }
+  Ok(instructions)
+}
+
+impl<'a> Generator<'a> {
+  fn emit(&mut self, instruction: Instruction) {
+    self.instructions.push(instruction);
+  }
+
+  fn push(&mut self, segment: Segment, offset: usize) {
+    self.emit(Instruction::Push { segment, offset });
+  }
+
+  fn pop(&mut self, segment: Segment, offset: usize) {
+    self.emit(Instruction::Pop { segment, offset });
+  }
+
+  fn call(&mut self, name: String, args: usize) {
+    self.emit(Instruction::Call { name, args });
+  }
+
+  // Labels are prefixed with the function name so they stay unique across the whole program
+  fn new_label(&mut self, name: &str) -> String {
+    self.label_index += 1;
+    format!("{}.{}${}{}", self.class.name, self.subroutine.name, name, self.label_index - 1)
+  }
+
+  fn subroutine(&mut self, fields: usize) -> Result<(), String> {
+    let subroutine = self.subroutine;
+    // Methods receive `this` as argument 0
+    let argument_offset = if subroutine.kind == SubroutineKind::Method { 1 } else { 0 };
+    for (index, (parameter_type, name)) in subroutine.parameters.iter().enumerate() {
+      self.subroutine_variables.insert(name.clone(), Variable {
+        segment: Segment::Argument,
+        index: index + argument_offset,
+        variable_type: parameter_type.clone(),
+      });
+    }
+    for (index, (local_type, name)) in subroutine.locals.iter().enumerate() {
+      self.subroutine_variables.insert(name.clone(), Variable {
+        segment: Segment::Local,
+        index,
+        variable_type: local_type.clone(),
+      });
+    }
+    self.emit(Instruction::Function {
+      name: format!("{}.{}", self.class.name, subroutine.name),
+      local_vars: subroutine.locals.len(),
+    });
+    match subroutine.kind {
+      SubroutineKind::Constructor => {
+        self.push(Segment::Constant, fields);
+        self.call("Memory.alloc".to_string(), 1);
+        self.pop(Segment::Pointer, 0);
+      }
+      SubroutineKind::Method => {
+        self.push(Segment::Argument, 0);
+        self.pop(Segment::Pointer, 0);
+      }
+      SubroutineKind::Function => {}
+    }
+    self.statements(&subroutine.statements)
+  }
+
+  fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+    for statement in statements.iter() {
+      self.statement(statement)?;
+    }
+    Ok(())
+  }
+
+  fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+    match statement {
+      Statement::Let { name, index: None, value } => {
+        let variable = self.variable(name)?;
+        self.expression(value)?;
+        self.pop(variable.segment, variable.index);
+      }
+      // The value is computed before `that` is pointed at the element
+      // because the value may index into another array
+      Statement::Let { name, index: Some(index), value } => {
+        let variable = self.variable(name)?;
+        self.push(variable.segment, variable.index);
+        self.expression(index)?;
+        self.emit(Instruction::Arithmetic(ArithInstruction::Add));
+        self.expression(value)?;
+        self.pop(Segment::Temp, 0);
+        self.pop(Segment::Pointer, 1);
+        self.push(Segment::Temp, 0);
+        self.pop(Segment::That, 0);
+      }
+      Statement::If { condition, then_branch, else_branch } => {
+        self.expression(condition)?;
+        self.emit(Instruction::Arithmetic(ArithInstruction::Not));
+        match else_branch {
+          None => {
+            let end_label = self.new_label("IF_END");
+            self.emit(Instruction::IfGoto(end_label.clone()));
+            self.statements(then_branch)?;
+            self.emit(Instruction::Label(end_label));
+          }
+          Some(else_branch) => {
+            let else_label = self.new_label("IF_ELSE");
+            let end_label = self.new_label("IF_END");
+            self.emit(Instruction::IfGoto(else_label.clone()));
+            self.statements(then_branch)?;
+            self.emit(Instruction::Goto(end_label.clone()));
+            self.emit(Instruction::Label(else_label));
+            self.statements(else_branch)?;
+            self.emit(Instruction::Label(end_label));
+          }
+        }
+      }
+      Statement::While { condition, body } => {
+        let start_label = self.new_label("WHILE_START");
+        let end_label = self.new_label("WHILE_END");
+        self.emit(Instruction::Label(start_label.clone()));
+        self.expression(condition)?;
+        self.emit(Instruction::Arithmetic(ArithInstruction::Not));
+        self.emit(Instruction::IfGoto(end_label.clone()));
+        self.statements(body)?;
+        self.emit(Instruction::Goto(start_label));
+        self.emit(Instruction::Label(end_label));
+      }
+      // The returned value of a `do` is thrown away
+      Statement::Do(call) => {
+        self.subroutine_call(call)?;
+        self.pop(Segment::Temp, 0);
+      }
+      // Void subroutines return 0
+      Statement::Return(value) => {
+        match value {
+          Some(value) => self.expression(value)?,
+          None => self.push(Segment::Constant, 0),
+        }
+        self.emit(Instruction::Return);
+      }
+    }
+    Ok(())
+  }
+
+  fn expression(&mut self, expression: &Expression) -> Result<(), String> {
+    match expression {
+      Expression::IntegerConstant(number) =>
+        self.push(Segment::Constant, *number),
+      Expression::StringConstant(string) => {
+        self.push(Segment::Constant, string.chars().count());
+        self.call("String.new".to_string(), 1);
+        for c in string.chars() {
+          self.push(Segment::Constant, c as usize);
+          self.call("String.appendChar".to_string(), 2);
+        }
+      }
+      // true is -1, which has all bits set
+      Expression::KeywordConstant(Located { value: KeywordConstant::True, .. }) => {
+        self.push(Segment::Constant, 0);
+        self.emit(Instruction::Arithmetic(ArithInstruction::Not));
+      }
+      Expression::KeywordConstant(Located { value: KeywordConstant::False, .. }) | Expression::KeywordConstant(Located { value: KeywordConstant::Null, .. }) =>
+        self.push(Segment::Constant, 0),
+      Expression::KeywordConstant(Located { value: KeywordConstant::This, from, to }) => {
+        if self.subroutine.kind == SubroutineKind::Function {
+          return Err(display_error(self.source,
+            format!("I found `this` inside function `{}.{}`. Functions don't have a `this` object. Try declaring `{}` as a method.",
+              self.class.name, self.subroutine.name, self.subroutine.name),
+            *from, *to));
+        }
+        self.push(Segment::Pointer, 0);
+      }
+      Expression::Variable(name) => {
+        let variable = self.variable(name)?;
+        self.push(variable.segment, variable.index);
+      }
+      Expression::ArrayAccess(name, index) => {
+        let variable = self.variable(name)?;
+        self.push(variable.segment, variable.index);
+        self.expression(index)?;
+        self.emit(Instruction::Arithmetic(ArithInstruction::Add));
+        self.pop(Segment::Pointer, 1);
+        self.push(Segment::That, 0);
+      }
+      Expression::Call(call) =>
+        self.subroutine_call(call)?,
+      Expression::Unary(operator, operand) => {
+        self.expression(operand)?;
+        self.emit(Instruction::Arithmetic(match operator {
+          UnaryOperator::Negate => ArithInstruction::Neg,
+          UnaryOperator::Not => ArithInstruction::Not,
+        }));
+      }
+      Expression::Binary(operator, left, right) => {
+        self.expression(left)?;
+        self.expression(right)?;
+        match operator {
+          BinaryOperator::Add => self.emit(Instruction::Arithmetic(ArithInstruction::Add)),
+          BinaryOperator::Subtract => self.emit(Instruction::Arithmetic(ArithInstruction::Sub)),
+          BinaryOperator::Multiply => self.call("Math.multiply".to_string(), 2),
+          BinaryOperator::Divide => self.call("Math.divide".to_string(), 2),
+          BinaryOperator::And => self.emit(Instruction::Arithmetic(ArithInstruction::And)),
+          BinaryOperator::Or => self.emit(Instruction::Arithmetic(ArithInstruction::Or)),
+          BinaryOperator::LessThan => self.emit(Instruction::Arithmetic(ArithInstruction::Lt)),
+          BinaryOperator::GreaterThan => self.emit(Instruction::Arithmetic(ArithInstruction::Gt)),
+          BinaryOperator::Equal => self.emit(Instruction::Arithmetic(ArithInstruction::Eq)),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn subroutine_call(&mut self, call: &SubroutineCall) -> Result<(), String> {
+    let arguments = call.arguments.len();
+    match &call.receiver {
+      // `foo()` calls a method on `this` unless `foo` is a function of the current class
+      None => {
+        let callee_kind = self.class.subroutines.iter()
+          .find(|subroutine| subroutine.name == call.name.value)
+          .map_or(SubroutineKind::Method, |subroutine| subroutine.kind);
+        if callee_kind == SubroutineKind::Method {
+          if self.subroutine.kind == SubroutineKind::Function {
+            return Err(display_error(self.source,
+              format!("I found a call to method `{}` inside function `{}.{}` which has no `this` object. Try calling it on an object like `object.{}()`.",
+                call.name.value, self.class.name, self.subroutine.name, call.name.value),
+              call.name.from, call.name.to));
+          }
+          self.push(Segment::Pointer, 0);
+          self.arguments(&call.arguments)?;
+          self.call(format!("{}.{}", self.class.name, call.name.value), arguments + 1);
+        } else {
+          self.arguments(&call.arguments)?;
+          self.call(format!("{}.{}", self.class.name, call.name.value), arguments);
+        }
+      }
+      // `ball.move()` calls a method on the object in variable `ball`
+      // while `Math.max(x, y)` calls a function of class `Math`
+      Some(receiver) => match self.lookup(&receiver.value) {
+        Some(_) => {
+          // Fields aren't there inside functions
+          let variable = self.variable(receiver)?;
+          let class_name = match variable.variable_type {
+            Type::Class(class_name) => class_name,
+            _ => return Err(display_error(self.source,
+              format!("I found a method call on `{}` but it isn't an object. Only variables with a class type have methods.", receiver.value),
+              receiver.from, receiver.to)),
+          };
+          self.push(variable.segment, variable.index);
+          self.arguments(&call.arguments)?;
+          self.call(format!("{}.{}", class_name, call.name.value), arguments + 1);
+        }
+        None => {
+          self.arguments(&call.arguments)?;
+          self.call(format!("{}.{}", receiver.value, call.name.value), arguments);
+        }
+      },
+    }
+    Ok(())
+  }
+
+  fn arguments(&mut self, arguments: &[Expression]) -> Result<(), String> {
+    for argument in arguments.iter() {
+      self.expression(argument)?;
+    }
+    Ok(())
+  }
+
+  fn lookup(&self, name: &str) -> Option<Variable> {
+    self.subroutine_variables.get(name).or_else(|| self.class_variables.get(name)).cloned()
+  }
+
+  fn variable(&self, name: &Located<String>) -> Result<Variable, String> {
+    match self.lookup(&name.value) {
+      Some(variable) if variable.segment == Segment::This && self.subroutine.kind == SubroutineKind::Function =>
+        Err(display_error(self.source,
+          format!("I found field `{}` used inside function `{}`. Functions can't access fields. Try declaring `{}` as a method.",
+            name.value, self.subroutine.name, self.subroutine.name),
+          name.from, name.to)),
+      Some(variable) =>
+        Ok(variable),
+      None =>
+        Err(display_error(self.source,
+          format!("I can't find a variable named `{}`. Try declaring it with `var`, as a parameter, or as a `field` or `static` of the class.", name.value),
+          name.from, name.to)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::jack_parser::parse;
+  use crate::jack_codegen::*;
+  use crate::vm_parser::Instruction::*;
+  use crate::vm_parser::ArithInstruction::*;
+  use crate::vm_parser::Segment::*;
+
+  #[test]
+  fn test_generate() {
+    let source =
+"class Point {
+  field int x, y;
+  static int count;
+
+  constructor Point new(int ax, int ay) {
+    let x = ax;
+    let y = ay;
+    let count = count + 1;
+    return this;
+  }
+
+  /** Returns the distance along both axes */
+  method int manhattan(Point other) {
+    var Array deltas;
+    let deltas = Array.new(2);
+    let deltas[0] = Math.abs(x - other.getx());
+    if (~(deltas[0] > 0)) { let deltas[0] = 0; }
+    return deltas[0] + 1;
+  }
+
+  method int getx() { return x; }
+}
+";
+    let class = parse(source).unwrap();
+    assert_eq!(
+      generate(source, &class),
+      Ok(vec![
+        Function { name: "Point.new".to_string(), local_vars: 0 },
+        Push { segment: Constant, offset: 2 },
+        Call { name: "Memory.alloc".to_string(), args: 1 },
+        Pop { segment: Pointer, offset: 0 },
+        Push { segment: Argument, offset: 0 },
+        Pop { segment: This, offset: 0 },
+        Push { segment: Argument, offset: 1 },
+        Pop { segment: This, offset: 1 },
+        Push { segment: Static, offset: 0 },
+        Push { segment: Constant, offset: 1 },
+        Arithmetic(Add),
+        Pop { segment: Static, offset: 0 },
+        Push { segment: Pointer, offset: 0 },
+        Return,
+        Function { name: "Point.manhattan".to_string(), local_vars: 1 },
+        Push { segment: Argument, offset: 0 },
+        Pop { segment: Pointer, offset: 0 },
+        Push { segment: Constant, offset: 2 },
+        Call { name: "Array.new".to_string(), args: 1 },
+        Pop { segment: Local, offset: 0 },
+        Push { segment: Local, offset: 0 },
+        Push { segment: Constant, offset: 0 },
+        Arithmetic(Add),
+        Push { segment: This, offset: 0 },
+        Push { segment: Argument, offset: 1 },
+        Call { name: "Point.getx".to_string(), args: 1 },
+        Arithmetic(Sub),
+        Call { name: "Math.abs".to_string(), args: 1 },
+        Pop { segment: Temp, offset: 0 },
+        Pop { segment: Pointer, offset: 1 },
+        Push { segment: Temp, offset: 0 },
+        Pop { segment: That, offset: 0 },
+        Push { segment: Local, offset: 0 },
+        Push { segment: Constant, offset: 0 },
+        Arithmetic(Add),
+        Pop { segment: Pointer, offset: 1 },
+        Push { segment: That, offset: 0 },
+        Push { segment: Constant, offset: 0 },
+        Arithmetic(Gt),
+        Arithmetic(Not),
+        Arithmetic(Not),
+        IfGoto("Point.manhattan$IF_END0".to_string()),
+        Push { segment: Local, offset: 0 },
+        Push { segment: Constant, offset: 0 },
+        Arithmetic(Add),
+        Push { segment: Constant, offset: 0 },
+        Pop { segment: Temp, offset: 0 },
+        Pop { segment: Pointer, offset: 1 },
+        Push { segment: Temp, offset: 0 },
+        Pop { segment: That, offset: 0 },
+        Label("Point.manhattan$IF_END0".to_string()),
+        Push { segment: Local, offset: 0 },
+        Push { segment: Constant, offset: 0 },
+        Arithmetic(Add),
+        Pop { segment: Pointer, offset: 1 },
+        Push { segment: That, offset: 0 },
+        Push { segment: Constant, offset: 1 },
+        Arithmetic(Add),
+        Return,
+        Function { name: "Point.getx".to_string(), local_vars: 0 },
+        Push { segment: Argument, offset: 0 },
+        Pop { segment: Pointer, offset: 0 },
+        Push { segment: This, offset: 0 },
+        Return,
+      ])
+    );
+    let source = "class Main {\n  function void main() {\n    let y = 1;\n    return;\n  }\n}\n";
+    assert_eq!(
+      generate(source, &parse(source).unwrap()),
+      Err("3|     let y = 1;\n           ^\n⚠️ I can't find a variable named `y`. Try declaring it with `var`, as a parameter, or as a `field` or `static` of the class.".to_string())
+    );
+    let source = "class Main {\n  function int main() {\n    return this;\n  }\n}\n";
+    assert_eq!(
+      generate(source, &parse(source).unwrap()),
+      Err("3|     return this;\n              ^^^^\n⚠️ I found `this` inside function `Main.main`. Functions don't have a `this` object. Try declaring `main` as a method.".to_string())
+    );
+    let source = "class Main {\n  function void main() {\n    do draw();\n    return;\n  }\n  method void draw() {\n    return;\n  }\n}\n";
+    assert_eq!(
+      generate(source, &parse(source).unwrap()),
+      Err("3|     do draw();\n          ^^^^\n⚠️ I found a call to method `draw` inside function `Main.main` which has no `this` object. Try calling it on an object like `object.draw()`.".to_string())
+    );
+    let source = "class Main {\n  field Ball ball;\n  function void main() {\n    do ball.move();\n    return;\n  }\n}\n";
+    assert_eq!(
+      generate(source, &parse(source).unwrap()),
+      Err("4|     do ball.move();\n          ^^^^\n⚠️ I found field `ball` used inside function `main`. Functions can't access fields. Try declaring `main` as a method.".to_string())
+    );
+  }
+}
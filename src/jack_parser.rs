@@ -0,0 +1,490 @@
+use crate::jack_tokenizer::*;
+use lip::{display_error, Located, Location};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+  pub name: String,
+  pub variables: Vec<ClassVariable>,
+  pub subroutines: Vec<Subroutine>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClassVariableKind {
+  Static,
+  Field,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassVariable {
+  pub kind: ClassVariableKind,
+  pub variable_type: Type,
+  pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+  Int,
+  Char,
+  Boolean,
+  Class(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubroutineKind {
+  Constructor,
+  Function,
+  Method,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subroutine {
+  pub kind: SubroutineKind,
+  // `None` for void subroutines
+  pub return_type: Option<Type>,
+  pub name: String,
+  pub parameters: Vec<(Type, String)>,
+  pub locals: Vec<(Type, String)>,
+  pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+  Let {
+    name: Located<String>,
+    index: Option<Expression>,
+    value: Expression,
+  },
+  If {
+    condition: Expression,
+    then_branch: Vec<Statement>,
+    else_branch: Option<Vec<Statement>>,
+  },
+  While {
+    condition: Expression,
+    body: Vec<Statement>,
+  },
+  Do(SubroutineCall),
+  Return(Option<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+  IntegerConstant(usize),
+  StringConstant(String),
+  KeywordConstant(Located<KeywordConstant>),
+  Variable(Located<String>),
+  ArrayAccess(Located<String>, Box<Expression>),
+  Call(SubroutineCall),
+  Unary(UnaryOperator, Box<Expression>),
+  // Jack has no operator precedence so operators apply from left to right
+  Binary(BinaryOperator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeywordConstant {
+  True,
+  False,
+  Null,
+  This,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+  Negate,
+  Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+  Add,
+  Subtract,
+  Multiply,
+  Divide,
+  And,
+  Or,
+  LessThan,
+  GreaterThan,
+  Equal,
+}
+
+// `foo(x)`, `Math.max(x, y)` or `ball.move()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubroutineCall {
+  pub receiver: Option<Located<String>>,
+  pub name: Located<String>,
+  pub arguments: Vec<Expression>,
+}
+
+struct ParserState<'a> {
+  source: &'a str,
+  tokens: Vec<LocatedToken>,
+  position: usize,
+}
+
+pub fn parse(source: &str) -> Result<Class, String> {
+  let mut state = ParserState {
+    source,
+    tokens: tokenize(source)?,
+    position: 0,
+  };
+  let class = state.class()?;
+  match state.peek() {
+    None => Ok(class),
+    Some(_) => Err(state.error("I'm expecting the end of the file after the class. Each file should only hold one class.")),
+  }
+}
+
+impl<'a> ParserState<'a> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position).map(|located_token| &located_token.token)
+  }
+
+  fn peek_next(&self) -> Option<&Token> {
+    self.tokens.get(self.position + 1).map(|located_token| &located_token.token)
+  }
+
+  fn advance(&mut self) -> Option<LocatedToken> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+    token
+  }
+
+  // Points at the current token or the end of the last token
+  fn error(&self, message: &str) -> String {
+    let (from, to) = match self.tokens.get(self.position) {
+      Some(located_token) => (located_token.from, located_token.to),
+      None => match self.tokens.last() {
+        Some(located_token) => (located_token.to, located_token.to),
+        None => (Location { row: 1, col: 1 }, Location { row: 1, col: 1 }),
+      },
+    };
+    display_error(self.source, message.to_string(), from, to)
+  }
+
+  fn expecting(&self, expected: &str) -> String {
+    let found = match self.peek() {
+      Some(token) => format!("`{}`", describe(token)),
+      None => "the end of the file".to_string(),
+    };
+    self.error(&format!("I'm expecting {} but found {}.", expected, found))
+  }
+
+  fn is_symbol(&self, symbol: char) -> bool {
+    self.peek() == Some(&Token::Symbol(symbol))
+  }
+
+  fn is_keyword(&self, keyword: &str) -> bool {
+    matches!(self.peek(), Some(Token::Keyword(other)) if other == keyword)
+  }
+
+  fn symbol(&mut self, symbol: char) -> Result<(), String> {
+    if self.is_symbol(symbol) {
+      self.advance();
+      Ok(())
+    } else {
+      Err(self.expecting(&format!("`{}`", symbol)))
+    }
+  }
+
+  fn keyword(&mut self, keyword: &str) -> Result<(), String> {
+    if self.is_keyword(keyword) {
+      self.advance();
+      Ok(())
+    } else {
+      Err(self.expecting(&format!("`{}`", keyword)))
+    }
+  }
+
+  fn identifier(&mut self, expected: &str) -> Result<Located<String>, String> {
+    match self.tokens.get(self.position).cloned() {
+      Some(LocatedToken { token: Token::Identifier(name), from, to }) => {
+        self.advance();
+        Ok(Located { value: name, from, to })
+      }
+      _ => Err(self.expecting(expected)),
+    }
+  }
+
+  // class Name { classVarDec* subroutineDec* }
+  fn class(&mut self) -> Result<Class, String> {
+    self.keyword("class")?;
+    let name = self.identifier("a class name like `Main`")?.value;
+    self.symbol('{')?;
+    let mut variables = Vec::new();
+    while self.is_keyword("static") || self.is_keyword("field") {
+      variables.append(&mut self.class_variables()?);
+    }
+    let mut subroutines = Vec::new();
+    while self.is_keyword("constructor") || self.is_keyword("function") || self.is_keyword("method") {
+      subroutines.push(self.subroutine()?);
+    }
+    if !self.is_symbol('}') {
+      return Err(self.expecting("a subroutine declaration like `function void main()` or the `}` closing the class"));
+    }
+    self.symbol('}')?;
+    Ok(Class { name, variables, subroutines })
+  }
+
+  // (static | field) type name (, name)* ;
+  fn class_variables(&mut self) -> Result<Vec<ClassVariable>, String> {
+    let kind = if self.is_keyword("static") { ClassVariableKind::Static } else { ClassVariableKind::Field };
+    self.advance();
+    let variable_type = self.variable_type()?;
+    let names = self.variable_names()?;
+    Ok(names.into_iter().map(|name| ClassVariable { kind, variable_type: variable_type.clone(), name }).collect())
+  }
+
+  fn variable_names(&mut self) -> Result<Vec<String>, String> {
+    let mut names = vec![self.identifier("a variable name")?.value];
+    while self.is_symbol(',') {
+      self.advance();
+      names.push(self.identifier("a variable name")?.value);
+    }
+    self.symbol(';')?;
+    Ok(names)
+  }
+
+  fn variable_type(&mut self) -> Result<Type, String> {
+    let variable_type = match self.peek() {
+      Some(Token::Keyword(keyword)) if keyword == "int" => Type::Int,
+      Some(Token::Keyword(keyword)) if keyword == "char" => Type::Char,
+      Some(Token::Keyword(keyword)) if keyword == "boolean" => Type::Boolean,
+      Some(Token::Identifier(name)) => Type::Class(name.clone()),
+      _ => return Err(self.expecting("a type like `int`, `char`, `boolean` or a class name")),
+    };
+    self.advance();
+    Ok(variable_type)
+  }
+
+  // (constructor | function | method) (void | type) name ( parameterList ) subroutineBody
+  fn subroutine(&mut self) -> Result<Subroutine, String> {
+    let kind = if self.is_keyword("constructor") {
+      SubroutineKind::Constructor
+    } else if self.is_keyword("function") {
+      SubroutineKind::Function
+    } else {
+      SubroutineKind::Method
+    };
+    self.advance();
+    let return_type = if self.is_keyword("void") {
+      self.advance();
+      None
+    } else {
+      Some(self.variable_type()?)
+    };
+    let name = self.identifier("a subroutine name")?.value;
+    self.symbol('(')?;
+    let mut parameters = Vec::new();
+    if !self.is_symbol(')') {
+      loop {
+        let parameter_type = self.variable_type()?;
+        parameters.push((parameter_type, self.identifier("a parameter name")?.value));
+        if self.is_symbol(',') {
+          self.advance();
+        } else {
+          break;
+        }
+      }
+    }
+    self.symbol(')')?;
+    self.symbol('{')?;
+    let mut locals = Vec::new();
+    while self.is_keyword("var") {
+      self.advance();
+      let local_type = self.variable_type()?;
+      locals.extend(self.variable_names()?.into_iter().map(|name| (local_type.clone(), name)));
+    }
+    let statements = self.statements()?;
+    self.symbol('}')?;
+    Ok(Subroutine { kind, return_type, name, parameters, locals, statements })
+  }
+
+  fn statements(&mut self) -> Result<Vec<Statement>, String> {
+    let mut statements = Vec::new();
+    while !self.is_symbol('}') {
+      statements.push(self.statement()?);
+    }
+    Ok(statements)
+  }
+
+  fn block(&mut self) -> Result<Vec<Statement>, String> {
+    self.symbol('{')?;
+    let statements = self.statements()?;
+    self.symbol('}')?;
+    Ok(statements)
+  }
+
+  fn statement(&mut self) -> Result<Statement, String> {
+    let keyword = match self.peek() {
+      Some(Token::Keyword(keyword)) => keyword.clone(),
+      _ => return Err(self.expecting("a statement starting with `let`, `if`, `while`, `do` or `return`")),
+    };
+    match keyword.as_str() {
+      // let name ([ expression ])? = expression ;
+      "let" => {
+        self.advance();
+        let name = self.identifier("a variable name")?;
+        let index = if self.is_symbol('[') {
+          self.advance();
+          let index = self.expression()?;
+          self.symbol(']')?;
+          Some(index)
+        } else {
+          None
+        };
+        self.symbol('=')?;
+        let value = self.expression()?;
+        self.symbol(';')?;
+        Ok(Statement::Let { name, index, value })
+      }
+      // if ( expression ) { statements } (else { statements })?
+      "if" => {
+        self.advance();
+        self.symbol('(')?;
+        let condition = self.expression()?;
+        self.symbol(')')?;
+        let then_branch = self.block()?;
+        let else_branch = if self.is_keyword("else") {
+          self.advance();
+          Some(self.block()?)
+        } else {
+          None
+        };
+        Ok(Statement::If { condition, then_branch, else_branch })
+      }
+      // while ( expression ) { statements }
+      "while" => {
+        self.advance();
+        self.symbol('(')?;
+        let condition = self.expression()?;
+        self.symbol(')')?;
+        let body = self.block()?;
+        Ok(Statement::While { condition, body })
+      }
+      // do subroutineCall ;
+      "do" => {
+        self.advance();
+        let name = self.identifier("a subroutine call like `Output.printInt(1)`")?;
+        let call = self.subroutine_call(name)?;
+        self.symbol(';')?;
+        Ok(Statement::Do(call))
+      }
+      // return expression? ;
+      "return" => {
+        self.advance();
+        let value = if self.is_symbol(';') { None } else { Some(self.expression()?) };
+        self.symbol(';')?;
+        Ok(Statement::Return(value))
+      }
+      _ => Err(self.expecting("a statement starting with `let`, `if`, `while`, `do` or `return`")),
+    }
+  }
+
+  // term (op term)*
+  fn expression(&mut self) -> Result<Expression, String> {
+    let mut expression = self.term()?;
+    loop {
+      let operator = match self.peek() {
+        Some(Token::Symbol('+')) => BinaryOperator::Add,
+        Some(Token::Symbol('-')) => BinaryOperator::Subtract,
+        Some(Token::Symbol('*')) => BinaryOperator::Multiply,
+        Some(Token::Symbol('/')) => BinaryOperator::Divide,
+        Some(Token::Symbol('&')) => BinaryOperator::And,
+        Some(Token::Symbol('|')) => BinaryOperator::Or,
+        Some(Token::Symbol('<')) => BinaryOperator::LessThan,
+        Some(Token::Symbol('>')) => BinaryOperator::GreaterThan,
+        Some(Token::Symbol('=')) => BinaryOperator::Equal,
+        _ => return Ok(expression),
+      };
+      self.advance();
+      expression = Expression::Binary(operator, Box::new(expression), Box::new(self.term()?));
+    }
+  }
+
+  fn term(&mut self) -> Result<Expression, String> {
+    match self.peek().cloned() {
+      Some(Token::IntegerConstant(number)) => {
+        self.advance();
+        Ok(Expression::IntegerConstant(number))
+      }
+      Some(Token::StringConstant(string)) => {
+        self.advance();
+        Ok(Expression::StringConstant(string))
+      }
+      Some(Token::Keyword(keyword)) => {
+        let constant = match keyword.as_str() {
+          "true" => KeywordConstant::True,
+          "false" => KeywordConstant::False,
+          "null" => KeywordConstant::Null,
+          "this" => KeywordConstant::This,
+          _ => return Err(self.expecting("an expression")),
+        };
+        let token = self.advance().unwrap();
+        Ok(Expression::KeywordConstant(Located { value: constant, from: token.from, to: token.to }))
+      }
+      Some(Token::Symbol('(')) => {
+        self.advance();
+        let expression = self.expression()?;
+        self.symbol(')')?;
+        Ok(expression)
+      }
+      Some(Token::Symbol('-')) => {
+        self.advance();
+        Ok(Expression::Unary(UnaryOperator::Negate, Box::new(self.term()?)))
+      }
+      Some(Token::Symbol('~')) => {
+        self.advance();
+        Ok(Expression::Unary(UnaryOperator::Not, Box::new(self.term()?)))
+      }
+      Some(Token::Identifier(_)) => {
+        let is_call = matches!(self.peek_next(), Some(Token::Symbol('(')) | Some(Token::Symbol('.')));
+        let name = self.identifier("a variable name")?;
+        if is_call {
+          Ok(Expression::Call(self.subroutine_call(name)?))
+        } else if self.is_symbol('[') {
+          self.advance();
+          let index = self.expression()?;
+          self.symbol(']')?;
+          Ok(Expression::ArrayAccess(name, Box::new(index)))
+        } else {
+          Ok(Expression::Variable(name))
+        }
+      }
+      _ => Err(self.expecting("an expression")),
+    }
+  }
+
+  // name ( expressionList ) | (className | varName) . name ( expressionList )
+  fn subroutine_call(&mut self, first_name: Located<String>) -> Result<SubroutineCall, String> {
+    let (receiver, name) = if self.is_symbol('.') {
+      self.advance();
+      (Some(first_name), self.identifier("a subroutine name")?)
+    } else {
+      (None, first_name)
+    };
+    self.symbol('(')?;
+    let mut arguments = Vec::new();
+    if !self.is_symbol(')') {
+      loop {
+        arguments.push(self.expression()?);
+        if self.is_symbol(',') {
+          self.advance();
+        } else {
+          break;
+        }
+      }
+    }
+    self.symbol(')')?;
+    Ok(SubroutineCall { receiver, name, arguments })
+  }
+}
+
+fn describe(token: &Token) -> String {
+  match token {
+    Token::Keyword(keyword) => keyword.clone(),
+    Token::Symbol(symbol) => symbol.to_string(),
+    Token::IntegerConstant(number) => number.to_string(),
+    Token::StringConstant(string) => format!("\"{}\"", string),
+    Token::Identifier(name) => name.clone(),
+  }
+}
@@ -0,0 +1,129 @@
+use lip::{display_error, Location};
+
+const KEYWORDS: [&str; 21] = [
+  "class", "constructor", "function", "method", "field", "static", "var",
+  "int", "char", "boolean", "void", "true", "false", "null", "this",
+  "let", "do", "if", "else", "while", "return",
+];
+
+const SYMBOLS: &str = "{}()[].,;+-*/&|<>=~";
+
+// Jack integer constants range from 0 to 32767
+const MAX_INTEGER: usize = 32767;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+  Keyword(String),
+  Symbol(char),
+  IntegerConstant(usize),
+  StringConstant(String),
+  Identifier(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocatedToken {
+  pub token: Token,
+  pub from: Location,
+  pub to: Location,
+}
+
+// class Main {
+//   function void main() {
+//     do Output.printString("Hello");
+//     return;
+//   }
+// }
+pub fn tokenize(source: &str) -> Result<Vec<LocatedToken>, String> {
+  let chars = source.chars().collect::<Vec<char>>();
+  let mut tokens = Vec::new();
+  let mut index = 0;
+  let mut location = Location { row: 1, col: 1 };
+  let advance = |index: &mut usize, location: &mut Location| {
+    if chars[*index] == '\n' {
+      location.row += 1;
+      location.col = 1;
+    } else {
+      location.col += 1;
+    }
+    *index += 1;
+  };
+  while index < chars.len() {
+    let c = chars[index];
+    let from = location;
+    if c.is_whitespace() {
+      advance(&mut index, &mut location);
+    } else if c == '/' && chars.get(index + 1) == Some(&'/') {
+      while index < chars.len() && chars[index] != '\n' {
+        advance(&mut index, &mut location);
+      }
+    } else if c == '/' && chars.get(index + 1) == Some(&'*') {
+      advance(&mut index, &mut location);
+      advance(&mut index, &mut location);
+      loop {
+        if index + 1 >= chars.len() {
+          return Err(display_error(source,
+            "I found a block comment that is never closed. Try adding `*/` to end it.".to_string(),
+            from, Location { col: from.col + 2, ..from }));
+        }
+        if chars[index] == '*' && chars[index + 1] == '/' {
+          advance(&mut index, &mut location);
+          advance(&mut index, &mut location);
+          break;
+        }
+        advance(&mut index, &mut location);
+      }
+    } else if SYMBOLS.contains(c) {
+      advance(&mut index, &mut location);
+      tokens.push(LocatedToken { token: Token::Symbol(c), from, to: location });
+    } else if c.is_ascii_digit() {
+      let mut value = String::new();
+      while index < chars.len() && chars[index].is_ascii_digit() {
+        value.push(chars[index]);
+        advance(&mut index, &mut location);
+      }
+      match value.parse::<usize>() {
+        Ok(number) if number <= MAX_INTEGER =>
+          tokens.push(LocatedToken { token: Token::IntegerConstant(number), from, to: location }),
+        _ =>
+          return Err(display_error(source,
+            format!("I found the integer {} which is too large. Jack integers range from 0 to {}.", value, MAX_INTEGER),
+            from, location)),
+      }
+    } else if c == '"' {
+      advance(&mut index, &mut location);
+      let mut value = String::new();
+      loop {
+        if index >= chars.len() || chars[index] == '\n' {
+          return Err(display_error(source,
+            "I found a string that is never closed. Try adding a `\"` to end it on the same line.".to_string(),
+            from, location));
+        }
+        if chars[index] == '"' {
+          advance(&mut index, &mut location);
+          break;
+        }
+        value.push(chars[index]);
+        advance(&mut index, &mut location);
+      }
+      tokens.push(LocatedToken { token: Token::StringConstant(value), from, to: location });
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let mut value = String::new();
+      while index < chars.len() && (chars[index].is_ascii_alphanumeric() || chars[index] == '_') {
+        value.push(chars[index]);
+        advance(&mut index, &mut location);
+      }
+      let token = if KEYWORDS.contains(&value.as_str()) {
+        Token::Keyword(value)
+      } else {
+        Token::Identifier(value)
+      };
+      tokens.push(LocatedToken { token, from, to: location });
+    } else {
+      advance(&mut index, &mut location);
+      return Err(display_error(source,
+        format!("I don't recognize the character `{}`. Try removing it.", c),
+        from, location));
+    }
+  }
+  Ok(tokens)
+}
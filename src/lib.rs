@@ -1,11 +1,139 @@
-mod vm_parser;
-mod vm_emitter;
+pub mod vm_parser;
+pub mod vm_emitter;
+pub mod vm_stats;
+pub mod jack_tokenizer;
+pub mod jack_parser;
+pub mod jack_codegen;
+pub mod hack_assembler;
+pub mod hack_disassembler;
+pub mod vm_recognizer;
+pub mod c_emitter;
+pub mod wat_emitter;
+pub mod x86_emitter;
+pub mod llvm_emitter;
+pub mod backend;
+pub mod memory_layout;
+pub mod stack_optimizer;
+pub mod inliner;
+pub mod hack_emulator;
+pub mod stack_verifier;
+pub mod control_flow;
+pub mod call_graph;
+pub mod stack_usage;
+pub mod offset_lint;
+pub mod strict_mode;
+pub mod profiler;
+pub mod vm_interpreter;
+pub mod debugger;
+pub mod gdb_stub;
+pub mod trace;
+#[cfg(test)]
+mod test_support;
+
+// The Hack computer has 32K words of instruction memory
+pub const ROM_SIZE: usize = 32768;
 
 pub fn compile(program_name: &str, source: &str) -> Result<String, String>
 {
   vm_parser::parse(source).map(
     |instructions| vm_emitter::emit(program_name, instructions)
-  )
+  ).and_then(check_rom_size)
+}
+
+// Compiles all the files of a program, given as (program name, source) pairs,
+// into a single assembly output
+pub fn compile_program(sources: &[(String, String)]) -> Result<String, String>
+{
+  parse_program(sources).map(vm_emitter::emit_program).and_then(check_rom_size)
+}
+
+// Same as `compile_program` but with the segments where `layout` puts them
+// and the given optimizations
+pub fn compile_program_in(sources: &[(String, String)], layout: &memory_layout::MemoryLayout, optimizations: vm_emitter::Optimizations) -> Result<String, String>
+{
+  layout.validate()?;
+  let files = parse_program(sources)?;
+  layout.check_program(&files)?;
+  check_rom_size(backend::emit(&mut vm_emitter::HackBackend::new(layout.clone(), optimizations), &files))
+}
+
+// Same as `compile_program` but for any backend. Only Hack assembly is checked against the ROM size.
+pub fn compile_program_with(sources: &[(String, String)], backend: &mut dyn backend::Backend) -> Result<String, String>
+{
+  parse_program(sources).map(|files| backend::emit(backend, &files))
+}
+
+pub fn parse_program(sources: &[(String, String)]) -> Result<Vec<(String, Vec<vm_parser::Instruction>)>, String>
+{
+  parse_files(sources, "vm", vm_parser::parse)
+}
+
+// Same as `parse_program` but also rejects the problems found by `checks`,
+// like `stack_verifier::verify` or `strict_mode::lint`
+pub fn parse_checked_program(sources: &[(String, String)], checks: &[Check]) -> Result<Vec<(String, Vec<vm_parser::Instruction>)>, String>
+{
+  parse_files(sources, "vm", |source| {
+    let instructions = vm_parser::parse_located(source).map_err(|diagnostics| vm_parser::display_diagnostics(source, &diagnostics))?;
+    let mut diagnostics = checks.iter().flat_map(|check| check(&instructions)).collect::<Vec<vm_parser::Diagnostic>>();
+    if diagnostics.is_empty() {
+      Ok(instructions.into_iter().map(|instruction| instruction.value).collect())
+    } else {
+      diagnostics.sort_by_key(|diagnostic| (diagnostic.from.row, diagnostic.from.col));
+      Err(vm_parser::display_diagnostics(source, &diagnostics))
+    }
+  })
+}
+
+// An analysis of one parsed VM file
+pub type Check = fn(&[lip::Located<vm_parser::Instruction>]) -> Vec<vm_parser::Diagnostic>;
+
+// Compiles one Jack class into VM instructions
+pub fn compile_jack(source: &str) -> Result<Vec<vm_parser::Instruction>, String>
+{
+  jack_parser::parse(source).and_then(|class| jack_codegen::generate(source, &class))
+}
+
+// Same as `parse_program` but for Jack classes, where each program name is a class name
+pub fn parse_jack_program(sources: &[(String, String)]) -> Result<Vec<(String, Vec<vm_parser::Instruction>)>, String>
+{
+  parse_files(sources, "jack", compile_jack)
+}
+
+fn parse_files<F>(sources: &[(String, String)], extension: &str, parse: F) -> Result<Vec<(String, Vec<vm_parser::Instruction>)>, String>
+  where F: Fn(&str) -> Result<Vec<vm_parser::Instruction>, String>
+{
+  let (files, errors): (Vec<_>, Vec<_>) = sources.iter().map(|(program_name, source)|
+    parse(source)
+      .map(|instructions| (program_name.clone(), instructions))
+      .map_err(|error| format!("In {}.{}:\n{}", program_name, extension, error))
+  ).partition(Result::is_ok);
+  if errors.is_empty() {
+    Ok(files.into_iter().map(Result::unwrap).collect())
+  } else {
+    Err(errors.into_iter().map(Result::unwrap_err).collect::<Vec<String>>().join("\n\n"))
+  }
+}
+
+pub fn check_rom_size(assembly: String) -> Result<String, String>
+{
+  let size = rom_size(&assembly);
+  if size > ROM_SIZE {
+    Err(format!(
+      "The compiled program needs {} Hack instructions but the ROM only holds {}.\nTry `--stats` to find the largest functions and commands.",
+      size, ROM_SIZE
+    ))
+  } else {
+    Ok(assembly)
+  }
+}
+
+// Counts the Hack instructions in the assembly, skipping label declarations
+pub fn rom_size(assembly: &str) -> usize
+{
+  assembly.lines().filter(|line| {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('(') && !line.starts_with("//")
+  }).count()
 }
 
 #[cfg(test)]
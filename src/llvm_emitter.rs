@@ -0,0 +1,417 @@
+use crate::vm_parser::*;
+use crate::backend::{self, Backend};
+use crate::vm_emitter;
+use std::collections::{HashMap, HashSet};
+
+// What every function needs to know about the whole program
+#[derive(Default)]
+struct Program {
+  statics: HashMap<(String, usize), usize>,
+  functions: HashSet<String>,
+  // The labels of every chunk of code that becomes an LLVM function, in order.
+  // The top level code of each file comes first, followed by its functions.
+  labels: Vec<HashSet<String>>,
+}
+
+// Emits the body of one LLVM function with fresh temporaries `%t0`, `%t1`, ...
+#[derive(Default)]
+struct FunctionEmitter {
+  program_name: String,
+  // VM labels defined in this function, the only ones a branch can reach
+  labels: HashSet<String>,
+  lines: Vec<String>,
+  next_temp: usize,
+  next_block: usize,
+}
+
+// Emits textual LLVM IR running the program on a global `[32768 x i16]` standing in for
+// the Hack RAM, so segments, frames and statics sit at the same addresses as in the Hack
+// output. Every VM function becomes an LLVM function and its labels become basic blocks,
+// which lets the optimizer see the control flow. Code before the first function of a file
+// goes into `vm_run`, which also calls `Sys.init` when there is one, and `main` runs it
+// and prints the stack. Calls still push the Hack frame, with 0 for the return address
+// since LLVM's own call stack does the returning. Pointers are opaque `ptr`s, which
+// LLVM 15 and later read by default and LLVM 14 reads with `-opaque-pointers`.
+pub fn emit_program(files: Vec<(String, Vec<Instruction>)>) -> String {
+  backend::emit(&mut LlvmBackend::default(), &files)
+}
+
+#[derive(Default)]
+pub struct LlvmBackend {
+  program: Program,
+  // Index into `program.labels` of the next chunk of code
+  next_chunk: usize,
+  top_level: FunctionEmitter,
+  // The name and body of the function being emitted
+  function: Option<(String, FunctionEmitter)>,
+  definitions: Vec<String>,
+}
+
+impl Backend for LlvmBackend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+    self.program = Program {
+      statics: vm_emitter::static_addresses(files),
+      functions: files.iter()
+        .flat_map(|(_, instructions)| instructions.iter())
+        .filter_map(|instruction| match instruction {
+          Instruction::Function { name, .. } => Some(name.clone()),
+          _ => None,
+        })
+        .collect(),
+      labels: files.iter().flat_map(|(_, instructions)| {
+        let mut chunks = vec![HashSet::new()];
+        for instruction in instructions.iter() {
+          match instruction {
+            Instruction::Function { .. } => chunks.push(HashSet::new()),
+            Instruction::Label(label) => { chunks.last_mut().unwrap().insert(label.clone()); }
+            _ => {}
+          }
+        }
+        chunks
+      }).collect(),
+    };
+    if self.program.functions.contains("Sys.init") {
+      self.top_level.lines.push("  ; Bootstrap: SP = 256, call Sys.init 0".to_string());
+      self.top_level.set("0", "256");
+      self.top_level.call(&self.program, "Sys.init", 0);
+    }
+  }
+
+  fn begin_file(&mut self, program_name: &str) {
+    self.finish_function();
+    self.top_level.program_name = program_name.to_string();
+    let labels = self.next_labels();
+    self.top_level.labels.extend(labels);
+  }
+
+  fn emit_instruction(&mut self, instruction: &Instruction) {
+    if let Instruction::Function { name, .. } = instruction {
+      self.finish_function();
+      let function = FunctionEmitter {
+        program_name: self.top_level.program_name.clone(),
+        labels: self.next_labels(),
+        ..FunctionEmitter::default()
+      };
+      self.function = Some((name.clone(), function));
+    }
+    match &mut self.function {
+      Some((_, function)) => function.instruction(&self.program, instruction),
+      None => self.top_level.instruction(&self.program, instruction),
+    }
+  }
+
+  fn finish(&mut self) -> String {
+    self.finish_function();
+    self.top_level.lines.push("  ret void".to_string());
+format!(
+"@ram = global [32768 x i16] zeroinitializer
+@format = private constant [4 x i8] c\"%d\\0A\\00\"
+
+declare i32 @printf(ptr, ...)
+declare void @llvm.trap()
+
+; Addresses wrap around the 32K RAM instead of leaving it
+define internal i32 @get(i32 %address) {{
+  %masked = and i32 %address, 32767
+  %pointer = getelementptr inbounds [32768 x i16], ptr @ram, i32 0, i32 %masked
+  %word = load i16, ptr %pointer
+  %value = sext i16 %word to i32
+  ret i32 %value
+}}
+
+; Storing 16 bits wraps the value around like the Hack ALU
+define internal void @set(i32 %address, i32 %value) {{
+  %masked = and i32 %address, 32767
+  %pointer = getelementptr inbounds [32768 x i16], ptr @ram, i32 0, i32 %masked
+  %word = trunc i32 %value to i16
+  store i16 %word, ptr %pointer
+  ret void
+}}
+
+define internal void @push(i32 %value) {{
+  %sp = call i32 @get(i32 0)
+  call void @set(i32 %sp, i32 %value)
+  %next = add i32 %sp, 1
+  call void @set(i32 0, i32 %next)
+  ret void
+}}
+
+define internal i32 @pop() {{
+  %sp = call i32 @get(i32 0)
+  %next = sub i32 %sp, 1
+  call void @set(i32 0, i32 %next)
+  %value = call i32 @get(i32 %next)
+  ret i32 %value
+}}
+{}
+define void @vm_run() {{
+entry:
+{}
+}}
+
+; SP = 256 unless the bootstrap sets it
+define i32 @main() {{
+entry:
+  call void @set(i32 0, i32 256)
+  call void @vm_run()
+  br label %loop
+loop:
+  %address = phi i32 [256, %entry], [%next, %body]
+  %sp = call i32 @get(i32 0)
+  %done = icmp sge i32 %address, %sp
+  br i1 %done, label %exit, label %body
+body:
+  %value = call i32 @get(i32 %address)
+  call i32 (ptr, ...) @printf(ptr @format, i32 %value)
+  %next = add i32 %address, 1
+  br label %loop
+exit:
+  ret i32 0
+}}
+", self.definitions.join(""), self.top_level.lines.join("\n"))
+  }
+}
+
+impl LlvmBackend {
+  fn next_labels(&mut self) -> HashSet<String> {
+    self.next_chunk += 1;
+    std::mem::take(&mut self.program.labels[self.next_chunk - 1])
+  }
+
+  // Functions end where the next one starts or at the end of their file
+  fn finish_function(&mut self) {
+    if let Some((name, mut function)) = self.function.take() {
+      // Falling off the end of a VM function runs into whatever comes next in the Hack
+      // output, which has no equivalent here
+      function.lines.push("  call void @llvm.trap()\n  unreachable".to_string());
+      self.definitions.push(format!(
+"
+define void @\"{}\"() {{
+entry:
+{}
+}}
+", name, function.lines.join("\n")));
+    }
+  }
+}
+
+impl FunctionEmitter {
+  fn temp(&mut self) -> String {
+    self.next_temp += 1;
+    format!("%t{}", self.next_temp - 1)
+  }
+
+  fn get(&mut self, address: &str) -> String {
+    let value = self.temp();
+    self.lines.push(format!("  {} = call i32 @get(i32 {})", value, address));
+    value
+  }
+
+  fn set(&mut self, address: &str, value: &str) {
+    self.lines.push(format!("  call void @set(i32 {}, i32 {})", address, value));
+  }
+
+  fn push(&mut self, value: &str) {
+    self.lines.push(format!("  call void @push(i32 {})", value));
+  }
+
+  fn pop(&mut self) -> String {
+    let value = self.temp();
+    self.lines.push(format!("  {} = call i32 @pop()", value));
+    value
+  }
+
+  fn operation(&mut self, operation: &str) -> String {
+    let value = self.temp();
+    self.lines.push(format!("  {} = {}", value, operation));
+    value
+  }
+
+  // Code after a terminator needs a block of its own even when nothing jumps to it
+  fn new_block(&mut self) {
+    self.next_block += 1;
+    self.lines.push(format!("b{}:", self.next_block - 1));
+  }
+
+  // Branching to a label outside the function, or one that doesn't exist, traps
+  fn branch_target(&mut self, label: &str) -> String {
+    if self.labels.contains(label) {
+      format!("%\"label.{}\"", label)
+    } else {
+      self.next_block += 1;
+      let block = format!("b{}", self.next_block - 1);
+      let after = format!("b{}", self.next_block);
+      self.next_block += 1;
+      self.lines.push(format!("  br label %{}\n{}:\n  ; {} isn't defined in this function\n  call void @llvm.trap()\n  unreachable\n{}:", after, block, label, after));
+      format!("%{}", block)
+    }
+  }
+
+  fn instruction(&mut self, program: &Program, instruction: &Instruction) {
+    self.lines.push(format!("  ; {}", instruction));
+    match instruction {
+      Instruction::Arithmetic(arith_instruction) =>
+        match arith_instruction {
+          ArithInstruction::Add =>
+            self.binary_arithmetic("add i32"),
+          ArithInstruction::Sub =>
+            self.binary_arithmetic("sub i32"),
+          ArithInstruction::Eq =>
+            self.comparison("eq"),
+          ArithInstruction::Gt =>
+            self.comparison("sgt"),
+          ArithInstruction::Lt =>
+            self.comparison("slt"),
+          ArithInstruction::And =>
+            self.binary_arithmetic("and i32"),
+          ArithInstruction::Or =>
+            self.binary_arithmetic("or i32"),
+          ArithInstruction::Neg => {
+            let x = self.pop();
+            let result = self.operation(&format!("sub i32 0, {}", x));
+            self.push(&result);
+          }
+          ArithInstruction::Not => {
+            let x = self.pop();
+            let result = self.operation(&format!("xor i32 {}, -1", x));
+            self.push(&result);
+          }
+        },
+      Instruction::Push { segment: Segment::Constant, offset } =>
+        self.push(&offset.to_string()),
+      Instruction::Push { segment, offset } => {
+        let address = self.segment_address(program, *segment, *offset);
+        let value = self.get(&address);
+        self.push(&value);
+      }
+      Instruction::Pop { segment, offset } => {
+        let value = self.pop();
+        let address = self.segment_address(program, *segment, *offset);
+        self.set(&address, &value);
+      }
+      Instruction::Ignored =>
+        panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
+      Instruction::Label(label) =>
+        self.lines.push(format!("  br label %\"label.{}\"\n\"label.{}\":", label, label)),
+      Instruction::Goto(label) => {
+        let target = self.branch_target(label);
+        self.lines.push(format!("  br label {}", target));
+        self.new_block();
+      }
+      Instruction::IfGoto(label) => {
+        let value = self.pop();
+        let condition = self.operation(&format!("icmp ne i32 {}, 0", value));
+        let target = self.branch_target(label);
+        self.lines.push(format!("  br i1 {}, label {}, label %b{}", condition, target, self.next_block));
+        self.new_block();
+      }
+      Instruction::Function { local_vars, .. } =>
+        for _ in 0..*local_vars {
+          self.push("0");
+        },
+      Instruction::Call { name, args } =>
+        self.call(program, name, *args),
+      Instruction::Return => {
+        let frame = self.get("1");
+        let value = self.pop();
+        let argument = self.get("2");
+        self.set(&argument, &value);
+        let stack_pointer = self.operation(&format!("add i32 {}, 1", argument));
+        self.set("0", &stack_pointer);
+        for (register, distance) in [(4, 1), (3, 2), (2, 3), (1, 4)].iter() {
+          let address = self.operation(&format!("sub i32 {}, {}", frame, distance));
+          let saved = self.get(&address);
+          self.set(&register.to_string(), &saved);
+        }
+        self.lines.push("  ret void".to_string());
+        self.new_block();
+      }
+    }
+  }
+
+  // The address of the segment entry as an LLVM value
+  fn segment_address(&mut self, program: &Program, segment: Segment, offset: usize) -> String {
+    let base_plus_offset = |emitter: &mut Self, base: &str| {
+      let base = emitter.get(base);
+      emitter.operation(&format!("add i32 {}, {}", base, offset))
+    };
+    match segment {
+      Segment::Local =>
+        base_plus_offset(self, "1"),
+      Segment::Argument =>
+        base_plus_offset(self, "2"),
+      Segment::This =>
+        base_plus_offset(self, "3"),
+      Segment::That =>
+        base_plus_offset(self, "4"),
+      Segment::Constant =>
+        panic!("`constant {}` has no address. The parser should filter out `pop constant` before emitting.", offset),
+      Segment::Static =>
+        program.statics[&(self.program_name.clone(), offset)].to_string(),
+      Segment::Temp =>
+        (5 + offset).to_string(),
+      Segment::Pointer =>
+        (3 + offset).to_string(),
+    }
+  }
+
+  fn binary_arithmetic(&mut self, operation: &str) {
+    let y = self.pop();
+    let x = self.pop();
+    let result = self.operation(&format!("{} {}, {}", operation, x, y));
+    self.push(&result);
+  }
+
+  // Pushes -1 when the comparison holds and 0 otherwise
+  fn comparison(&mut self, condition: &str) {
+    let y = self.pop();
+    let x = self.pop();
+    let holds = self.operation(&format!("icmp {} i32 {}, {}", condition, x, y));
+    let result = self.operation(&format!("sext i1 {} to i32", holds));
+    self.push(&result);
+  }
+
+  // Pushes the same frame as the Hack emitter so RAM looks the same in both
+  fn call(&mut self, program: &Program, name: &str, args: usize) {
+    self.push("0");
+    for register in 1..=4 {
+      let value = self.get(&register.to_string());
+      self.push(&value);
+    }
+    let stack_pointer = self.get("0");
+    let argument = self.operation(&format!("sub i32 {}, {}", stack_pointer, 5 + args));
+    self.set("2", &argument);
+    self.set("1", &stack_pointer);
+    if program.functions.contains(name) {
+      self.lines.push(format!("  call void @\"{}\"()", name));
+    } else {
+      self.lines.push(format!("  ; {} is undefined\n  call void @llvm.trap()\n  unreachable", name));
+      self.new_block();
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::llvm_emitter::*;
+  use crate::test_support::*;
+  use std::process::Command;
+
+  #[test]
+  fn test_emit_program() {
+    let ir = emit_program(fibonacci_program("push static 0\npush static 1\ngt\nreturn\n"));
+
+    // Interprets the IR when LLVM is around. Sys.init returns -1 into the bootstrap
+    // frame's return address slot, which is left on the stack.
+    let output = run_if_installed("lli", "the LLVM IR", &[("program.ll", ir.as_bytes())], |directory| {
+      // LLVM 14 only reads opaque pointers with a flag
+      let version = String::from_utf8_lossy(&Command::new("lli").arg("--version").output()?.stdout).to_string();
+      let flags: &[&str] = if version.contains("LLVM version 14") { &["-opaque-pointers"] } else { &[] };
+      Command::new("lli").args(flags).arg(directory.join("program.ll")).output()
+    });
+    if let Some(output) = output {
+      assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+      assert_eq!(String::from_utf8(output.stdout).unwrap(), "-1\n");
+    }
+  }
+}
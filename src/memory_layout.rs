@@ -0,0 +1,198 @@
+use crate::vm_parser::*;
+use lip::{display_error, Location};
+use std::collections::HashSet;
+
+// The memory-mapped screen and keyboard of the Hack computer
+const IO_BASE: usize = 16384;
+const IO_LIMIT: usize = 24577;
+const RAM_SIZE: usize = 32768;
+
+// Where the Hack target puts each memory segment. Limits are exclusive.
+// The pointers SP, LCL, ARG, THIS and THAT always sit at RAM[0] to RAM[4]
+// and the emitter uses R13 to R15 as scratch registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryLayout {
+  pub stack_base: usize,
+  pub temp_base: usize,
+  pub temp_size: usize,
+  pub static_base: usize,
+  pub static_limit: usize,
+  // The stack grows up from `stack_base` until the heap starts
+  pub heap_base: usize,
+  pub heap_limit: usize,
+}
+
+// The standard layout of the Nand to Tetris course
+impl Default for MemoryLayout {
+  fn default() -> Self {
+    MemoryLayout {
+      stack_base: 256,
+      temp_base: 5,
+      temp_size: 8,
+      static_base: 16,
+      static_limit: 256,
+      heap_base: 2048,
+      heap_limit: 16384,
+    }
+  }
+}
+
+impl MemoryLayout {
+  // Reads a layout from `name = value` lines. Missing names keep their default.
+  // stack_base = 512
+  // heap_base = 4096 // comments are allowed
+  pub fn parse(source: &str) -> Result<MemoryLayout, String> {
+    let mut layout = MemoryLayout::default();
+    for (index, line) in source.split('\n').enumerate() {
+      let content = match line.find("//") {
+        Some(comment_start) => &line[..comment_start],
+        None => line,
+      };
+      if content.trim().is_empty() {
+        continue;
+      }
+      let row = index + 1;
+      let col = line.len() - line.trim_start().len() + 1;
+      let error = |message: String| display_error(source, message, Location { row, col }, Location { row, col: col + content.trim().len() });
+      let (name, value) = match content.find('=') {
+        Some(equal_sign) => (content[..equal_sign].trim(), content[equal_sign + 1..].trim()),
+        None => return Err(error("I'm expecting a setting like `stack_base = 256`.".to_string())),
+      };
+      let value = value.parse::<usize>()
+        .map_err(|_| error(format!("I'm expecting a non-negative number for `{}` but found `{}`.", name, value)))?;
+      let field = match name {
+        "stack_base" => &mut layout.stack_base,
+        "temp_base" => &mut layout.temp_base,
+        "temp_size" => &mut layout.temp_size,
+        "static_base" => &mut layout.static_base,
+        "static_limit" => &mut layout.static_limit,
+        "heap_base" => &mut layout.heap_base,
+        "heap_limit" => &mut layout.heap_limit,
+        _ => return Err(error(format!(
+          "I don't know the setting `{}`.\nTry one of stack_base, temp_base, temp_size, static_base, static_limit, heap_base and heap_limit.", name
+        ))),
+      };
+      *field = value;
+    }
+    layout.validate()?;
+    Ok(layout)
+  }
+
+  // Checks that every region fits in the RAM and that no two regions overlap
+  pub fn validate(&self) -> Result<(), String> {
+    if self.static_limit < self.static_base {
+      return Err(format!("The static limit RAM[{}] is below the static base RAM[{}].", self.static_limit, self.static_base));
+    }
+    if self.heap_base <= self.stack_base {
+      return Err(format!(
+        "The heap starts at RAM[{}] but the stack starts at RAM[{}].\nThe stack grows up into the heap so the heap has to start above it.",
+        self.heap_base, self.stack_base
+      ));
+    }
+    if self.heap_limit < self.heap_base {
+      return Err(format!("The heap limit RAM[{}] is below the heap base RAM[{}].", self.heap_limit, self.heap_base));
+    }
+    let regions = self.regions();
+    if let Some((name, _, limit)) = regions.iter().find(|(_, _, limit)| *limit > RAM_SIZE) {
+      return Err(format!("The {} region ends at RAM[{}] but the RAM only has {} words.", name, limit - 1, RAM_SIZE));
+    }
+    for (index, (name, base, limit)) in regions.iter().enumerate() {
+      for (other_name, other_base, other_limit) in regions[index + 1..].iter() {
+        if base < other_limit && other_base < limit {
+          return Err(format!(
+            "The {} region (RAM[{}] to RAM[{}]) overlaps the {} region (RAM[{}] to RAM[{}]).\nTry moving one of them.",
+            name, base, limit - 1, other_name, other_base, other_limit - 1
+          ));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // (name, base, limit) of every non-empty region
+  fn regions(&self) -> Vec<(&'static str, usize, usize)> {
+    vec![
+      ("pointer", 0, 5),
+      ("temp", self.temp_base, self.temp_base + self.temp_size),
+      ("scratch register", 13, 16),
+      ("static", self.static_base, self.static_limit),
+      ("stack", self.stack_base, self.heap_base),
+      ("heap", self.heap_base, self.heap_limit),
+      ("screen and keyboard", IO_BASE, IO_LIMIT),
+    ].into_iter().filter(|(_, base, limit)| base < limit).collect()
+  }
+
+  // Checks that the program's temp and static segments fit in the layout
+  pub fn check_program(&self, files: &[(String, Vec<Instruction>)]) -> Result<(), String> {
+    let mut statics = HashSet::new();
+    for (program_name, instructions) in files.iter() {
+      for instruction in instructions.iter() {
+        match instruction {
+          Instruction::Push { segment: Segment::Temp, offset } | Instruction::Pop { segment: Segment::Temp, offset } if *offset >= self.temp_size =>
+            return Err(format!(
+              "In {}.vm:\nI found `{}` but the temp segment only has {} entries, from temp 0 to temp {}.",
+              program_name, instruction, self.temp_size, self.temp_size.max(1) - 1
+            )),
+          Instruction::Push { segment: Segment::Static, offset } | Instruction::Pop { segment: Segment::Static, offset } => {
+            statics.insert((program_name, offset));
+          }
+          _ => {}
+        }
+      }
+    }
+    let static_slots = self.static_limit - self.static_base;
+    if statics.len() > static_slots {
+      return Err(format!(
+        "The program uses {} static variables but the static region only holds {}.\nTry `--stats` to see the statics used by each file.",
+        statics.len(), static_slots
+      ));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::memory_layout::*;
+
+  #[test]
+  fn test_memory_layout() {
+    assert_eq!(MemoryLayout::default().validate(), Ok(()));
+    assert_eq!(
+      MemoryLayout::parse("// A bigger stack\nstack_base = 512\nheap_base = 4096\nstatic_limit = 512\n"),
+      Ok(MemoryLayout { stack_base: 512, heap_base: 4096, static_limit: 512, ..MemoryLayout::default() })
+    );
+    assert_eq!(
+      MemoryLayout::parse("static_limit = 300"),
+      Err("The static region (RAM[16] to RAM[299]) overlaps the stack region (RAM[256] to RAM[2047]).\nTry moving one of them.".to_string())
+    );
+    assert_eq!(
+      MemoryLayout::parse("temp_base = 3"),
+      Err("The pointer region (RAM[0] to RAM[4]) overlaps the temp region (RAM[3] to RAM[10]).\nTry moving one of them.".to_string())
+    );
+    assert_eq!(
+      MemoryLayout::parse("stack_base = 256\nstack_size = 10"),
+      Err(
+"2| stack_size = 10
+   ^^^^^^^^^^^^^^^
+⚠️ I don't know the setting `stack_size`.
+Try one of stack_base, temp_base, temp_size, static_base, static_limit, heap_base and heap_limit.".to_string())
+    );
+
+    let layout = MemoryLayout { temp_size: 2, ..MemoryLayout::default() };
+    let files = crate::parse_program(&[("Main".to_string(), "push constant 1\npop temp 2\n".to_string())]).unwrap();
+    assert_eq!(
+      layout.check_program(&files),
+      Err("In Main.vm:\nI found `pop temp 2` but the temp segment only has 2 entries, from temp 0 to temp 1.".to_string())
+    );
+
+    // The bootstrap and the emitted segments follow the layout
+    let layout = MemoryLayout { stack_base: 300, static_base: 100, static_limit: 300, ..MemoryLayout::default() };
+    let sources = [("Sys".to_string(), "function Sys.init 0\npush static 3\npop static 1\n".to_string())];
+    let output = crate::compile_program_in(&sources, &layout, Default::default()).unwrap();
+    assert!(output.starts_with("@300\nD=A\n@SP\nM=D\n"));
+    assert!(output.contains("@100\nD=M\n"));
+    assert!(output.contains("@101\nM=D"));
+    assert!(!output.contains("Sys.3"));
+  }
+}
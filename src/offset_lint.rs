@@ -0,0 +1,132 @@
+use crate::vm_parser::*;
+use crate::call_graph::{self, Call};
+use lip::Located;
+use std::collections::HashMap;
+
+// Finds `local` offsets past the locals a function declares and `argument`
+// offsets past the arguments its callers pass. Functions without calls in
+// `files` aren't checked for arguments since their callers could be anywhere.
+// Returns the index of the file of each diagnostic.
+pub fn lint(files: &[(String, Vec<Located<Instruction>>)]) -> Vec<(usize, Diagnostic)> {
+  let graph = call_graph::build(&files_without_locations(files));
+  // The call passing the fewest arguments to each function that uses more
+  let mut fewest_args: HashMap<&str, &Call> = HashMap::new();
+  for (call, function) in graph.arity_mismatches() {
+    let fewest = fewest_args.entry(function.name.as_str()).or_insert(call);
+    if call.args < fewest.args {
+      *fewest = call;
+    }
+  }
+  let mut diagnostics = Vec::new();
+  for (file, (_, instructions)) in files.iter().enumerate() {
+    let mut function = None;
+    for instruction in instructions.iter() {
+      let message = match (&instruction.value, function) {
+        (Instruction::Function { name, local_vars }, _) => {
+          function = Some((name.as_str(), *local_vars));
+          None
+        }
+        (Instruction::Push { segment: Segment::Local, offset } | Instruction::Pop { segment: Segment::Local, offset }, Some((name, local_vars)))
+          if *offset >= local_vars =>
+          Some(format!(
+            "I found `{}` but {} {}.\nTry `function {} {}` to make room for it.",
+            instruction.value, name, if local_vars == 0 { "declares no locals".to_string() } else {
+              format!("only declares {}, from local 0 to local {}", count(local_vars, "local"), local_vars - 1)
+            }, name, offset + 1
+          )),
+        (Instruction::Push { segment: Segment::Argument, offset } | Instruction::Pop { segment: Segment::Argument, offset }, Some((name, _))) =>
+          match fewest_args.get(name) {
+            Some(call) if *offset >= call.args =>
+              Some(format!(
+                "I found `{}` but {} calls {} with {}{}.\nTry passing more arguments or check the offset.",
+                instruction.value, call.caller, name, count(call.args, "argument"),
+                if call.args == 0 { String::new() } else { format!(", from argument 0 to argument {}", call.args - 1) }
+              )),
+            _ => None,
+          },
+        _ => None,
+      };
+      if let Some(message) = message {
+        diagnostics.push((file, Diagnostic { message, severity: Severity::Error, from: instruction.from, to: instruction.to }));
+      }
+    }
+  }
+  diagnostics
+}
+
+// Parses the VM sources and rejects the problems `lint` finds, with the
+// parser's error messages for each file
+pub fn check(sources: &[(String, String)]) -> Result<(), String> {
+  let files = sources.iter().map(|(program_name, source)|
+    parse_located(source)
+      .map(|instructions| (program_name.clone(), instructions))
+      .map_err(|diagnostics| format!("In {}.vm:\n{}", program_name, display_diagnostics(source, &diagnostics)))
+  ).collect::<Result<Vec<(String, Vec<Located<Instruction>>)>, String>>()?;
+  let diagnostics = lint(&files);
+  if diagnostics.is_empty() {
+    return Ok(());
+  }
+  Err(sources.iter().enumerate().filter_map(|(file, (program_name, source))| {
+    let diagnostics = diagnostics.iter()
+      .filter(|(diagnostic_file, _)| *diagnostic_file == file)
+      .map(|(_, diagnostic)| diagnostic.clone())
+      .collect::<Vec<Diagnostic>>();
+    if diagnostics.is_empty() {
+      None
+    } else {
+      Some(format!("In {}.vm:\n{}", program_name, display_diagnostics(source, &diagnostics)))
+    }
+  }).collect::<Vec<String>>().join("\n\n"))
+}
+
+fn count(count: usize, noun: &str) -> String {
+  if count == 1 { format!("1 {}", noun) } else { format!("{} {}s", count, noun) }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::offset_lint::*;
+
+  #[test]
+  fn test_check() {
+    let main =
+"function Main.main 1
+push constant 1
+push constant 2
+call Foo.bar 2
+pop local 0
+push constant 3
+call Foo.bar 1
+push local 1
+return
+";
+    let foo =
+"function Foo.bar 2
+push argument 1
+pop local 2
+push argument 0
+return
+function Foo.unused 0
+push argument 4
+return
+";
+    assert_eq!(check(&[("Main".to_string(), main.to_string()), ("Foo".to_string(), foo.to_string())]), Err(
+"In Main.vm:
+8| push local 1
+   ^^^^^^^^^^^^
+⚠️ I found `push local 1` but Main.main only declares 1 local, from local 0 to local 0.
+Try `function Main.main 2` to make room for it.
+
+In Foo.vm:
+2| push argument 1
+   ^^^^^^^^^^^^^^^
+⚠️ I found `push argument 1` but Main.main calls Foo.bar with 1 argument, from argument 0 to argument 0.
+Try passing more arguments or check the offset.
+
+3| pop local 2
+   ^^^^^^^^^^^
+⚠️ I found `pop local 2` but Foo.bar only declares 2 locals, from local 0 to local 1.
+Try `function Foo.bar 3` to make room for it.".to_string()));
+    assert_eq!(check(&[("Foo".to_string(), foo.replace("pop local 2", "pop local 1"))]), Ok(()));
+  }
+}
@@ -0,0 +1,308 @@
+use crate::hack_assembler::assemble;
+use crate::hack_emulator::Emulator;
+use crate::memory_layout::MemoryLayout;
+use crate::vm_emitter;
+use crate::vm_parser::*;
+use itertools::Itertools;
+use lip::Located;
+use std::collections::HashMap;
+
+// The VM command a ROM address was emitted for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+  // SP = 256 and call Sys.init 0
+  Bootstrap,
+  Command { file: usize, instruction: usize },
+}
+
+// One function on a path through the calls
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallNode {
+  pub name: String,
+  pub parent: Option<usize>,
+  pub children: Vec<usize>,
+  // Instructions executed by this function itself on this path
+  pub self_cycles: usize,
+  pub calls: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+  pub cycles: usize,
+  // False when the cycle limit stopped the program
+  pub halted: bool,
+  // (function, instructions executed by the function itself, calls), most expensive first
+  pub functions: Vec<(String, usize, usize)>,
+  // (file, line, command, instructions), most expensive first
+  pub lines: Vec<(String, usize, String, usize)>,
+  // The root is where the program starts, the bootstrap when there's `Sys.init`
+  pub call_tree: Vec<CallNode>,
+}
+
+// Runs the plain Hack code of the program for at most `cycles` instructions and
+// counts how many ran for each VM command. The program halts when it runs past
+// the end of the ROM or reaches a `goto` to itself like `label END` `goto END`.
+// Lines are those of `Located` instructions, so programs compiled from Jack can
+// use the lines of the VM listing.
+pub fn profile(files: &[(String, Vec<Located<Instruction>>)], cycles: usize) -> Result<Profile, String> {
+  let plain_files = files_without_locations(files);
+  let (assembly, origins) = emit_with_origins(&plain_files);
+  let mut emulator = Emulator::new(assemble(&assembly)?);
+  let owners = owners(&plain_files);
+
+  let root_name = match origins.first() {
+    Some(Origin::Command { file, instruction }) => owners[*file][*instruction].clone(),
+    _ => "(bootstrap)".to_string(),
+  };
+  let mut call_tree = vec![CallNode { name: root_name, parent: None, children: vec![], self_cycles: 0, calls: 1 }];
+  let mut current = 0;
+  let mut counts = vec![0; emulator.rom.len()];
+  let mut previous: Option<Origin> = None;
+  let mut executed = 0;
+  let mut halted = false;
+  while executed < cycles {
+    let pc = emulator.pc;
+    let origin = match origins.get(pc) {
+      Some(origin) => *origin,
+      None => {
+        halted = true;
+        break;
+      }
+    };
+    if emulator.halted() {
+      halted = true;
+      break;
+    }
+    // Calls and returns always leave their code with a jump
+    if previous.is_some() && previous != Some(origin) {
+      match previous.map(|previous| instruction_at(files, previous)) {
+        Some(Some(Instruction::Call { name, .. })) =>
+          current = enter(&mut call_tree, current, name),
+        Some(None) =>
+          current = enter(&mut call_tree, current, "Sys.init"),
+        Some(Some(Instruction::Return)) =>
+          current = call_tree[current].parent.unwrap_or(current),
+        _ => {}
+      }
+    }
+    counts[pc] += 1;
+    call_tree[current].self_cycles += 1;
+    previous = Some(origin);
+    emulator.step();
+    executed += 1;
+  }
+
+  let mut functions: Vec<(String, usize, usize)> = Vec::new();
+  let mut lines: HashMap<(usize, usize), usize> = HashMap::new();
+  for (pc, count) in counts.iter().enumerate().filter(|(_, count)| **count > 0) {
+    let name = match origins[pc] {
+      Origin::Bootstrap => "(bootstrap)".to_string(),
+      Origin::Command { file, instruction } => {
+        *lines.entry((file, instruction)).or_insert(0) += count;
+        owners[file][instruction].clone()
+      }
+    };
+    match functions.iter_mut().find(|(function, _, _)| *function == name) {
+      Some((_, function_cycles, _)) => *function_cycles += count,
+      None => functions.push((name, *count, 0)),
+    }
+  }
+  for node in call_tree.iter().skip(1) {
+    if let Some((_, _, calls)) = functions.iter_mut().find(|(function, _, _)| *function == node.name) {
+      *calls += node.calls;
+    }
+  }
+  functions.sort_by(|(_, cycles1, _), (_, cycles2, _)| cycles2.cmp(cycles1));
+  let lines = lines.into_iter()
+    .map(|((file, instruction), count)| {
+      let located = &files[file].1[instruction];
+      (files[file].0.clone(), located.from.row, located.value.to_string(), count)
+    })
+    .sorted_by(|(file1, row1, _, count1), (file2, row2, _, count2)| count2.cmp(count1).then(file1.cmp(file2)).then(row1.cmp(row2)))
+    .collect();
+  Ok(Profile { cycles: executed, halted, functions, lines, call_tree })
+}
+
+// The same code as `vm_emitter::emit_program` with the VM command of each ROM address
+fn emit_with_origins(files: &[(String, Vec<Instruction>)]) -> (String, Vec<Origin>) {
+  let mut output = Vec::new();
+  let mut origins = Vec::new();
+  let mut instruction_index = 0;
+  let has_sys_init = files.iter().any(|(_, instructions)| instructions.iter().any(|instruction|
+    matches!(instruction, Instruction::Function { name, .. } if name == "Sys.init")
+  ));
+  if has_sys_init {
+    let code = vm_emitter::emit_bootstrap(MemoryLayout::default().stack_base, instruction_index);
+    origins.extend(std::iter::repeat_n(Origin::Bootstrap, crate::rom_size(&code)));
+    output.push(code);
+    instruction_index += 1;
+  }
+  for (file, (program_name, instructions)) in files.iter().enumerate() {
+    for (instruction, value) in instructions.iter().enumerate().filter(|(_, value)| !matches!(value, Instruction::Ignored)) {
+      let code = vm_emitter::emit_instruction(program_name, instruction_index, value);
+      origins.extend(std::iter::repeat_n(Origin::Command { file, instruction }, crate::rom_size(&code)));
+      output.push(code);
+      instruction_index += 1;
+    }
+  }
+  (output.join("\n"), origins)
+}
+
+// The function each instruction belongs to
+fn owners(files: &[(String, Vec<Instruction>)]) -> Vec<Vec<String>> {
+  files.iter().map(|(program_name, instructions)| {
+    let mut owner = format!("(top level of {}.vm)", program_name);
+    instructions.iter().map(|instruction| {
+      if let Instruction::Function { name, .. } = instruction {
+        owner = name.clone();
+      }
+      owner.clone()
+    }).collect()
+  }).collect()
+}
+
+// None for the bootstrap
+fn instruction_at(files: &[(String, Vec<Located<Instruction>>)], origin: Origin) -> Option<&Instruction> {
+  match origin {
+    Origin::Bootstrap => None,
+    Origin::Command { file, instruction } => Some(&files[file].1[instruction].value),
+  }
+}
+
+fn enter(call_tree: &mut Vec<CallNode>, current: usize, name: &str) -> usize {
+  let existing = call_tree[current].children.iter().copied().find(|child| call_tree[*child].name == name);
+  let child = existing.unwrap_or_else(|| {
+    call_tree.push(CallNode { name: name.to_string(), parent: Some(current), children: vec![], self_cycles: 0, calls: 0 });
+    let child = call_tree.len() - 1;
+    call_tree[current].children.push(child);
+    child
+  });
+  call_tree[child].calls += 1;
+  child
+}
+
+impl Profile {
+  // Instructions executed by a node and everything it called
+  pub fn total_cycles(&self, node: usize) -> usize {
+    self.call_tree[node].self_cycles + self.call_tree[node].children.iter().map(|child| self.total_cycles(*child)).sum::<usize>()
+  }
+
+  // The functions and the `limit` hottest lines by the instructions they executed themselves
+  pub fn flat_report(&self, limit: usize) -> String {
+    let percent = |cycles: usize| cycles as f64 * 100.0 / self.cycles.max(1) as f64;
+    let function_width = self.functions.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    let lines = self.lines.iter().take(limit).map(|(file, row, command, cycles)| (format!("{}.vm:{}", file, row), command, cycles)).collect::<Vec<_>>();
+    let location_width = lines.iter().map(|(location, _, _)| location.len()).max().unwrap_or(0);
+    let command_width = lines.iter().map(|(_, command, _)| command.len()).max().unwrap_or(0);
+    format!(
+"Executed {} Hack instructions{}
+
+Instructions per function:
+{}
+
+Hottest lines:
+{}",
+      self.cycles,
+      if self.halted { " until the program halted" } else { " before reaching the cycle limit" },
+      self.functions.iter().map(|(name, cycles, calls)|
+        format!("  {:<function_width$}  {:>10}  {:>5.1}%  {:>8} {}", name, cycles, percent(*cycles), calls, if *calls == 1 { "call" } else { "calls" },
+          function_width = function_width)
+      ).join("\n"),
+      lines.iter().map(|(location, command, cycles)|
+        format!("  {:<location_width$}  {:<command_width$}  {:>10}  {:>5.1}%", location, command, cycles, percent(**cycles),
+          location_width = location_width, command_width = command_width)
+      ).join("\n"),
+    )
+  }
+
+  // Every path through the calls with the instructions executed under it and by itself
+  pub fn call_tree_report(&self) -> String {
+    let mut lines = vec!["Call tree (total, self, calls):".to_string()];
+    let mut stack = vec![(0, 1)];
+    while let Some((node, depth)) = stack.pop() {
+      let total = self.total_cycles(node);
+      lines.push(format!(
+        "{}{}  {} ({:.1}%), {}, {}",
+        "  ".repeat(depth), self.call_tree[node].name, total, total as f64 * 100.0 / self.cycles.max(1) as f64,
+        self.call_tree[node].self_cycles, self.call_tree[node].calls
+      ));
+      let children = self.call_tree[node].children.iter().sorted_by_key(|child| self.total_cycles(**child)).map(|child| (*child, depth + 1));
+      stack.extend(children);
+    }
+    lines.join("\n")
+  }
+
+  // One `caller;callee count` line per path with instructions of its own, the
+  // format flame graph tools like inferno and flamegraph.pl read
+  pub fn collapsed_stacks(&self) -> String {
+    self.call_tree.iter().enumerate().filter(|(_, node)| node.self_cycles > 0).map(|(index, node)| {
+      let mut path = vec![node.name.as_str()];
+      let mut parent = node.parent;
+      while let Some(ancestor) = parent {
+        path.push(&self.call_tree[ancestor].name);
+        parent = self.call_tree[ancestor].parent;
+      }
+      path.reverse();
+      format!("{} {}\n", path.join(";"), self.call_tree[index].self_cycles)
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::profiler::*;
+  use crate::test_support::located_program;
+
+  #[test]
+  fn test_profile() {
+    let sys =
+"function Sys.init 0
+push constant 3
+call Main.triple 1
+push constant 4
+call Main.triple 1
+add
+pop static 0
+label HALT
+goto HALT
+";
+    let main =
+"// 3x with two calls
+function Main.triple 0
+push argument 0
+call Main.double 1
+push argument 0
+add
+return
+function Main.double 0
+push argument 0
+push argument 0
+add
+return
+";
+    let files = located_program(&[("Sys", sys), ("Main", main)]);
+    let plain_files = files_without_locations(&files);
+    assert_eq!(emit_with_origins(&plain_files).0, vm_emitter::emit_program(plain_files.clone()));
+
+    let profile = profile(&files, 100000).unwrap();
+    assert!(profile.halted);
+    // Everything runs in the emulator, ending right before the HALT loop
+    let mut emulator = Emulator::new(assemble(&vm_emitter::emit_program(plain_files)).unwrap());
+    emulator.run(profile.cycles);
+    assert_eq!(emulator.ram[16], 21);
+    assert_eq!(profile.call_tree.iter().map(|node| node.calls).collect::<Vec<usize>>(), vec![1, 1, 2, 2]);
+    assert_eq!(profile.total_cycles(0), profile.cycles);
+    assert_eq!(profile.functions.iter().map(|(name, _, calls)| (name.as_str(), *calls)).sorted().collect::<Vec<(&str, usize)>>(),
+      vec![("(bootstrap)", 0), ("Main.double", 2), ("Main.triple", 2), ("Sys.init", 1)]);
+    assert_eq!(profile.lines.iter().filter(|(file, _, _, _)| file == "Main").map(|(_, row, _, _)| *row).sorted().collect::<Vec<usize>>(),
+      vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    let collapsed = profile.collapsed_stacks();
+    assert_eq!(collapsed.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect::<Vec<&str>>(), vec![
+      "(bootstrap)",
+      "(bootstrap);Sys.init",
+      "(bootstrap);Sys.init;Main.triple",
+      "(bootstrap);Sys.init;Main.triple;Main.double",
+    ]);
+    assert!(profile.call_tree_report().starts_with("Call tree (total, self, calls):\n  (bootstrap)  "));
+  }
+}
@@ -0,0 +1,422 @@
+use crate::vm_parser::*;
+use crate::memory_layout::MemoryLayout;
+use crate::vm_emitter;
+use std::collections::HashMap;
+
+// The scratch registers that hold values pushed in the current basic block
+const REGISTERS: [usize; 3] = [13, 14, 15];
+// Pending values beyond this are written to the stack
+const MAX_PENDING: usize = 4;
+// Offsets up to this are reached with `A=A+1` steps instead of going through D
+const MAX_STEPS: usize = 3;
+
+// A value pushed in the current basic block that isn't on the stack in RAM yet
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+  Constant(usize),
+  // An entry behind a base pointer, like `local 2` is RAM[LCL + 2]
+  Indirect(&'static str, usize),
+  // A static, temp or pointer entry at a fixed symbol or address
+  Direct(String),
+  // Only ever the top value
+  D,
+  Register(usize),
+}
+
+// Translates straight-line code into direct memory-to-memory code. Pushes are
+// kept as pending operands and consumed by the next commands, so
+// `push local 0`, `push local 1`, `add`, `pop local 2` never touches SP.
+// The pending values are written to the stack before labels, jumps, calls and
+// returns, so the stack in RAM is the same as the plain emitter's at every
+// block boundary. Anything it doesn't handle falls back to the plain templates.
+#[derive(Default)]
+pub struct StackOptimizer {
+  pending: Vec<Operand>,
+}
+
+impl StackOptimizer {
+  pub fn emit(&mut self, layout: &MemoryLayout, statics: &HashMap<(String, usize), usize>, program_name: &str, instruction_index: usize, instruction: &Instruction) -> String {
+    let optimized = match instruction {
+      Instruction::Push { segment, offset } =>
+        Some(self.push(operand(layout, statics, program_name, *segment, *offset))),
+      Instruction::Pop { segment, offset } =>
+        self.pop(operand(layout, statics, program_name, *segment, *offset)),
+      Instruction::Arithmetic(arith_instruction) =>
+        self.arithmetic(instruction_index, arith_instruction),
+      Instruction::IfGoto(label) =>
+        self.if_goto(label),
+      _ => None,
+    };
+    optimized.unwrap_or_else(|| join(vec![
+      self.flush(),
+      vm_emitter::emit_instruction_in(layout, statics, program_name, instruction_index, instruction),
+    ]))
+  }
+
+  // Writes the pending values to the stack
+  pub fn flush(&mut self) -> String {
+    let pending = std::mem::take(&mut self.pending);
+    let mut code = Vec::new();
+    // D goes first since loading the others overwrites it
+    let has_d = pending.last() == Some(&Operand::D);
+    if has_d {
+      code.push(format!("@SP\nA=M{}\nM=D", "\nA=A+1".repeat(pending.len() - 1)));
+    }
+    for operand in pending.iter().filter(|operand| **operand != Operand::D) {
+      code.push(load(operand));
+      code.push("@SP\nAM=M+1\nA=A-1\nM=D".to_string());
+    }
+    if has_d {
+      code.push("@SP\nM=M+1".to_string());
+    }
+    join(code)
+  }
+
+  fn push(&mut self, operand: Operand) -> String {
+    let mut code = Vec::new();
+    if self.pending.len() == MAX_PENDING {
+      code.push(self.flush());
+    }
+    if self.pending.last() == Some(&Operand::D) {
+      code.push(self.spill().unwrap());
+    }
+    self.pending.push(operand);
+    join(code)
+  }
+
+  fn pop(&mut self, target: Operand) -> Option<String> {
+    // A far `Indirect` target needs a register besides the ones under the value
+    let under_top = &self.pending[..self.pending.len().max(1) - 1];
+    if matches!(target, Operand::Indirect(_, offset) if offset > MAX_STEPS)
+      && REGISTERS.iter().all(|register| under_top.contains(&Operand::Register(*register))) {
+      return None;
+    }
+    let top = self.pending.pop()?;
+    let mut code = Vec::new();
+    // Pending reads have to happen before the store might change them
+    if self.pending.iter().any(|operand| matches!(operand, Operand::Indirect(..) | Operand::Direct(_))) {
+      self.pending.push(top);
+      if self.pending.last() == Some(&Operand::D) {
+        code.push(self.spill().unwrap());
+      }
+      let top = self.pending.pop().unwrap();
+      code.push(self.flush());
+      code.push(load(&top));
+    } else {
+      code.push(load(&top));
+    }
+    match target {
+      Operand::Direct(address) =>
+        code.push(format!("@{}\nM=D", address)),
+      Operand::Indirect(base, offset) if offset <= MAX_STEPS =>
+        code.push(format!("@{}\nA=M{}\nM=D", base, "\nA=A+1".repeat(offset))),
+      // RAM[base + offset] = (value + base + offset) - value
+      Operand::Indirect(base, offset) => {
+        let register = self.free_register().unwrap();
+        code.push(format!("@R{}\nM=D\n@{}\nD=D+M\n@{}\nD=D+A\n@R{}\nA=D-M\nM=D-A", register, base, offset, register));
+      }
+      _ =>
+        panic!("`pop constant` should be filtered out by the parser."),
+    }
+    Some(join(code))
+  }
+
+  fn arithmetic(&mut self, instruction_index: usize, arith_instruction: &ArithInstruction) -> Option<String> {
+    match arith_instruction {
+      ArithInstruction::Neg =>
+        self.unary("D=-D"),
+      ArithInstruction::Not =>
+        self.unary("D=!D"),
+      _ if self.pending.len() >= 2 =>
+        Some(self.binary(instruction_index, arith_instruction)),
+      ArithInstruction::Add | ArithInstruction::Sub | ArithInstruction::And | ArithInstruction::Or if self.pending.len() == 1 => {
+        // The first operand is already on the stack
+        let y = self.pending.pop().unwrap();
+        let computation = match arith_instruction {
+          ArithInstruction::Add => "D+M",
+          ArithInstruction::Sub => "M-D",
+          ArithInstruction::And => "D&M",
+          _ => "D|M",
+        };
+        Some(join(vec![load(&y), format!("@SP\nA=M-1\nM={}", computation)]))
+      }
+      _ => None,
+    }
+  }
+
+  fn unary(&mut self, computation: &str) -> Option<String> {
+    let top = self.pending.pop()?;
+    self.pending.push(Operand::D);
+    Some(join(vec![load(&top), computation.to_string()]))
+  }
+
+  // Computes `x op y` into D with one operand in D and the other selected by A
+  fn binary(&mut self, instruction_index: usize, arith_instruction: &ArithInstruction) -> String {
+    let mut code = Vec::new();
+    if self.pending.last() == Some(&Operand::D) && select(&self.pending[self.pending.len() - 2]).is_none() {
+      code.push(self.spill().unwrap());
+    }
+    let y = self.pending.pop().unwrap();
+    let x = self.pending.pop().unwrap();
+    let (d_is_x, selected) = if y == Operand::D {
+      (false, select(&x).unwrap())
+    } else if let Some(selected) = select(&y) {
+      code.push(load(&x));
+      (true, selected)
+    } else if let Some(selected) = select(&x) {
+      code.push(load(&y));
+      (false, selected)
+    } else {
+      code.push(load(&y));
+      let register = self.free_register().unwrap();
+      code.push(format!("@R{}\nM=D", register));
+      code.push(load(&x));
+      (true, select(&Operand::Register(register)).unwrap())
+    };
+    let (selection, other) = selected;
+    code.push(selection);
+    let difference = if d_is_x { format!("D-{}", other) } else { format!("{}-D", other) };
+    let computation = match arith_instruction {
+      ArithInstruction::Add => format!("D+{}", other),
+      ArithInstruction::Sub => difference,
+      ArithInstruction::And => format!("D&{}", other),
+      ArithInstruction::Or => format!("D|{}", other),
+      ArithInstruction::Eq => return self.comparison(code, instruction_index, "EQ", &difference),
+      ArithInstruction::Gt => return self.comparison(code, instruction_index, "GT", &difference),
+      ArithInstruction::Lt => return self.comparison(code, instruction_index, "LT", &difference),
+      ArithInstruction::Neg | ArithInstruction::Not => unreachable!(),
+    };
+    code.push(format!("D={}", computation));
+    self.pending.push(Operand::D);
+    join(code)
+  }
+
+  // Same labels as the plain comparison template
+  fn comparison(&mut self, mut code: Vec<String>, instruction_index: usize, operation_str: &str, difference: &str) -> String {
+    code.push(format!(
+"D={}
+@{}_{}
+D;J{}
+D=0
+@NOT_{}_{}
+0;JMP
+({}_{})
+D=-1
+(NOT_{}_{})", difference, operation_str, instruction_index, operation_str, operation_str, instruction_index,
+      operation_str, instruction_index, operation_str, instruction_index));
+    self.pending.push(Operand::D);
+    join(code)
+  }
+
+  fn if_goto(&mut self, label: &str) -> Option<String> {
+    if self.pending.is_empty() {
+      return None;
+    }
+    let mut code = Vec::new();
+    if self.pending.len() > 1 && self.pending.last() == Some(&Operand::D) {
+      code.push(self.spill()?);
+    }
+    let top = self.pending.pop().unwrap();
+    code.push(self.flush());
+    code.push(load(&top));
+    code.push(format!("@{}\nD;JNE", label));
+    Some(join(code))
+  }
+
+  // Moves the value in D to a free register
+  fn spill(&mut self) -> Option<String> {
+    let register = self.free_register()?;
+    *self.pending.last_mut().unwrap() = Operand::Register(register);
+    Some(format!("@R{}\nM=D", register))
+  }
+
+  fn free_register(&self) -> Option<usize> {
+    REGISTERS.iter().copied().find(|register| !self.pending.contains(&Operand::Register(*register)))
+  }
+}
+
+fn operand(layout: &MemoryLayout, statics: &HashMap<(String, usize), usize>, program_name: &str, segment: Segment, offset: usize) -> Operand {
+  match segment {
+    Segment::Constant => Operand::Constant(offset),
+    Segment::Local => Operand::Indirect("LCL", offset),
+    Segment::Argument => Operand::Indirect("ARG", offset),
+    Segment::This => Operand::Indirect("THIS", offset),
+    Segment::That => Operand::Indirect("THAT", offset),
+    Segment::Static => Operand::Direct(vm_emitter::static_address(layout, statics, program_name, offset)),
+    Segment::Temp => Operand::Direct((layout.temp_base + offset).to_string()),
+    Segment::Pointer => Operand::Direct(if offset == 0 { "THIS" } else { "THAT" }.to_string()),
+  }
+}
+
+// Points A at the operand without touching D. Constants are in A itself.
+fn select(operand: &Operand) -> Option<(String, &'static str)> {
+  match operand {
+    Operand::Constant(number) => Some((format!("@{}", number), "A")),
+    Operand::Direct(address) => Some((format!("@{}", address), "M")),
+    Operand::Register(register) => Some((format!("@R{}", register), "M")),
+    Operand::Indirect(base, offset) if *offset <= MAX_STEPS =>
+      Some((format!("@{}\nA=M{}", base, "\nA=A+1".repeat(*offset)), "M")),
+    Operand::Indirect(..) | Operand::D => None,
+  }
+}
+
+fn load(operand: &Operand) -> String {
+  match operand {
+    Operand::D => String::new(),
+    Operand::Constant(number) if *number <= 1 => format!("D={}", number),
+    Operand::Indirect(base, offset) if *offset > MAX_STEPS => format!("@{}\nD=M\n@{}\nA=D+A\nD=M", base, offset),
+    _ => {
+      let (selection, selected) = select(operand).unwrap();
+      format!("{}\nD={}", selection, selected)
+    }
+  }
+}
+
+fn join(code: Vec<String>) -> String {
+  code.into_iter().filter(|code| !code.is_empty()).collect::<Vec<String>>().join("\n")
+}
+
+#[cfg(test)]
+mod test {
+  use crate::stack_optimizer::*;
+  use crate::vm_emitter::{HackBackend, Optimizations};
+  use crate::backend;
+  use crate::hack_emulator::Emulator;
+
+  // Runs a Hack program for a number of cycles and returns the RAM
+  fn run(assembly: &str, ram: &[(usize, i16)], cycles: usize) -> Vec<i16> {
+    let mut emulator = Emulator::new(crate::hack_assembler::assemble(assembly).unwrap());
+    for (address, value) in ram.iter() {
+      emulator.ram[*address] = *value;
+    }
+    emulator.run(cycles);
+    emulator.ram
+  }
+
+  fn compile(source: &str, optimize: bool) -> String {
+    let files = crate::parse_program(&[("Main".to_string(), source.to_string())]).unwrap();
+    backend::emit(&mut HackBackend::new(MemoryLayout::default(), Optimizations { stack_registers: optimize, tail_calls: false }), &files)
+  }
+
+  #[test]
+  fn test_stack_optimizer() {
+    let expression = "push local 0\npush local 1\nadd\npop local 2\n";
+    assert_eq!(compile(expression, true),
+"@LCL
+A=M
+D=M
+@LCL
+A=M
+A=A+1
+D=D+M
+@LCL
+A=M
+A=A+1
+A=A+1
+M=D");
+    assert!(crate::rom_size(&compile(expression, false)) > 2 * crate::rom_size(&compile(expression, true)));
+
+    // Every command, deep stacks, large offsets, aliasing stores and jumps
+    let source =
+"push constant 7
+push argument 0
+push constant 3
+sub
+push local 5
+push argument 1
+gt
+push static 0
+push static 1
+eq
+not
+and
+or
+neg
+pop local 5
+push local 5
+push local 5
+pop local 5
+push constant 1
+pop local 5
+pop temp 3
+push local 0
+push local 1
+push local 2
+push local 3
+push local 4
+add
+add
+add
+add
+pop local 6
+push argument 0
+push constant 2
+lt
+if-goto SKIP
+push constant 100
+pop static 1
+label SKIP
+push argument 1
+push constant 0
+push local 6
+push local 5
+push constant 9
+and
+or
+sub
+pop pointer 1
+push that 0
+push constant 5
+add
+push local 7
+lt
+pop local 8
+push constant 1
+add
+pop static 2
+label HALT
+goto HALT
+";
+    let ram = [(0, 310), (1, 300), (2, 290), (3, 3000), (4, 3100), (16, 12), (17, 12),
+      (290, 5), (291, -3), (300, 1), (301, 2), (302, 3), (303, 4), (304, 5), (305, 6), (307, 100), (400, 42)];
+    let plain = run(&compile(source, false), &ram, 10000);
+    let optimized = run(&compile(source, true), &ram, 10000);
+    // Only the scratch registers and the stack above SP may differ
+    let stack_pointer = plain[0] as usize;
+    assert_eq!(optimized[0] as usize, stack_pointer);
+    assert_eq!(&plain[..13], &optimized[..13]);
+    assert_eq!(&plain[16..stack_pointer], &optimized[16..stack_pointer]);
+
+    // Calls and returns see the same frames
+    let fibonacci =
+"function Sys.init 0
+push constant 12
+call Main.fibonacci 1
+pop static 0
+label HALT
+goto HALT
+function Main.fibonacci 0
+push argument 0
+push constant 2
+lt
+if-goto BASE_CASE
+push argument 0
+push constant 1
+sub
+call Main.fibonacci 1
+push argument 0
+push constant 2
+sub
+call Main.fibonacci 1
+add
+return
+label BASE_CASE
+push argument 0
+return
+";
+    let optimized = compile(fibonacci, true);
+    assert!(crate::rom_size(&optimized) < crate::rom_size(&compile(fibonacci, false)));
+    assert_eq!(run(&optimized, &[], 100000)[16], 144);
+    assert_eq!(run(&compile(fibonacci, false), &[], 100000)[16], 144);
+  }
+}
@@ -0,0 +1,181 @@
+use crate::call_graph;
+use crate::control_flow;
+use crate::memory_layout::MemoryLayout;
+use crate::stack_verifier::{depths, stack_effect};
+use crate::vm_parser::*;
+use std::collections::HashMap;
+
+// The return address and the saved LCL, ARG, THIS and THAT pushed by every call
+const FRAME_SIZE: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackUsage {
+  // Most words on the stack at any time, counting the frame of the bootstrap's call
+  pub words: usize,
+  // The deepest chain of calls, starting with the entry function
+  pub path: Vec<String>,
+}
+
+// What one function needs on top of its frame
+struct Function {
+  local_vars: usize,
+  // Deepest working stack of the function itself
+  max_depth: usize,
+  // (stack depth right before the call, callee)
+  calls: Vec<(usize, String)>,
+}
+
+// An upper bound on the stack used by a program that starts with the bootstrap
+// calling `entry`. Every function is assumed to take its deepest path, so the
+// bound holds for any input. Recursion or calls to undefined functions leave
+// the stack unbounded and give an error explaining why.
+pub fn estimate(files: &[(String, Vec<Instruction>)], entry: &str) -> Result<StackUsage, String> {
+  let call_graph = call_graph::build(files);
+  if call_graph.function(entry).is_none() {
+    return Err(format!("I can't estimate the stack usage because no function is called {}.", entry));
+  }
+  let recursive = call_graph.recursive_cycles().into_iter().flatten().map(String::from).collect::<Vec<String>>();
+  let mut functions = HashMap::new();
+  for (_, instructions) in files.iter() {
+    for region in control_flow::regions(instructions) {
+      if let Instruction::Function { name, local_vars } = &instructions[region.start] {
+        functions.insert(name.clone(), analyze(instructions, region.clone(), *local_vars));
+      }
+    }
+  }
+  let mut usages = HashMap::new();
+  let (words, path) = usage(entry, &functions, &recursive, &mut usages)?;
+  Ok(StackUsage { words: FRAME_SIZE + words, path })
+}
+
+fn analyze(instructions: &[Instruction], region: std::ops::Range<usize>, local_vars: usize) -> Function {
+  let depths = depths(instructions, region.clone(), |_, _| {});
+  let mut max_depth = 0;
+  let mut calls = Vec::new();
+  for (index, depth) in region.zip(depths) {
+    let depth = match depth {
+      Some(depth) => depth,
+      None => continue,
+    };
+    let (pops, pushes) = stack_effect(&instructions[index]);
+    max_depth = max_depth.max(depth).max(depth.saturating_sub(pops) + pushes);
+    if let Instruction::Call { name, .. } = &instructions[index] {
+      calls.push((depth, name.clone()));
+    }
+  }
+  Function { local_vars, max_depth, calls }
+}
+
+// Words used by `name` above its frame, including its locals, with the deepest
+// chain of calls from it
+fn usage(name: &str, functions: &HashMap<String, Function>, recursive: &[String], usages: &mut HashMap<String, (usize, Vec<String>)>)
+  -> Result<(usize, Vec<String>), String> {
+  if let Some(usage) = usages.get(name) {
+    return Ok(usage.clone());
+  }
+  if recursive.iter().any(|function| function == name) {
+    return Err(format!(
+      "I can't estimate the stack usage because {} is recursive.\nThe stack grows with every level of recursion, which depends on the input.", name
+    ));
+  }
+  let function = &functions[name];
+  let mut words = function.max_depth;
+  let mut path = vec![name.to_string()];
+  for (depth, callee) in function.calls.iter() {
+    if !functions.contains_key(callee) {
+      return Err(format!("I can't estimate the stack usage because {} calls {}, which isn't defined.", name, callee));
+    }
+    let (callee_words, callee_path) = usage(callee, functions, recursive, usages)?;
+    if depth + FRAME_SIZE + callee_words > words {
+      words = depth + FRAME_SIZE + callee_words;
+      path = std::iter::once(name.to_string()).chain(callee_path).collect();
+    }
+  }
+  let usage = (function.local_vars + words, path);
+  usages.insert(name.to_string(), usage.clone());
+  Ok(usage)
+}
+
+// A warning when the stack of a program starting from `Sys.init` can grow past
+// the stack region into the heap. Programs whose usage can't be bounded get none.
+pub fn check(files: &[(String, Vec<Instruction>)], layout: &MemoryLayout) -> Option<String> {
+  let usage = estimate(files, "Sys.init").ok()?;
+  let region_size = layout.heap_base - layout.stack_base;
+  if usage.words > region_size {
+    Some(format!(
+      "The stack can grow to {} words through {} but the stack region only holds {}, from RAM[{}] to RAM[{}].\nTry moving the heap up with `--layout` or using fewer locals in the deepest calls.",
+      usage.words, usage.path.join(" -> "), region_size, layout.stack_base, layout.heap_base - 1
+    ))
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::stack_usage::*;
+
+  #[test]
+  fn test_estimate() {
+    let sources = |main: &str| [
+      ("Sys".to_string(),
+"function Sys.init 0
+push constant 1
+push constant 2
+call Main.main 1
+pop temp 0
+label HALT
+goto HALT
+".to_string()),
+      ("Main".to_string(), main.to_string()),
+    ];
+    let files = crate::parse_program(&sources(
+"function Main.main 3
+push argument 0
+if-goto BIG
+push constant 0
+return
+label BIG
+push constant 7
+push constant 8
+push constant 9
+call Main.sum 3
+return
+function Main.sum 100
+push argument 0
+push argument 1
+push argument 2
+add
+add
+return
+")).unwrap();
+    // Sys.init: 2 values when calling Main.main, which has 3 locals and 3 values when calling Main.sum
+    assert_eq!(estimate(&files, "Sys.init"), Ok(StackUsage {
+      words: 5 + 2 + 5 + 3 + 3 + 5 + 100 + 3,
+      path: vec!["Sys.init".to_string(), "Main.main".to_string(), "Main.sum".to_string()],
+    }));
+    assert_eq!(check(&files, &MemoryLayout::default()), None);
+    assert_eq!(
+      check(&files, &MemoryLayout { heap_base: 256 + 120, heap_limit: 16384, ..MemoryLayout::default() }),
+      Some("The stack can grow to 126 words through Sys.init -> Main.main -> Main.sum but the stack region only holds 120, from RAM[256] to RAM[375].\nTry moving the heap up with `--layout` or using fewer locals in the deepest calls.".to_string())
+    );
+
+    let files = crate::parse_program(&sources(
+"function Main.main 0
+push argument 0
+call Main.main 1
+return
+")).unwrap();
+    assert_eq!(estimate(&files, "Sys.init"), Err("I can't estimate the stack usage because Main.main is recursive.\nThe stack grows with every level of recursion, which depends on the input.".to_string()));
+    assert_eq!(check(&files, &MemoryLayout::default()), None);
+
+    let files = crate::parse_program(&sources(
+"function Main.main 0
+push constant 6
+push constant 7
+call Math.multiply 2
+return
+")).unwrap();
+    assert_eq!(estimate(&files, "Sys.init"), Err("I can't estimate the stack usage because Main.main calls Math.multiply, which isn't defined.".to_string()));
+  }
+}
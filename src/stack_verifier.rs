@@ -0,0 +1,168 @@
+use crate::control_flow;
+use crate::vm_parser::*;
+use itertools::Itertools;
+use lip::Located;
+use std::collections::HashMap;
+use std::ops::Range;
+
+// Follows every path through each function, and through the code before the
+// first function, to find the stack depth at each command. Reports commands that
+// pop more than the function pushed, labels reached with different depths, and
+// `return` with nothing to return. Functions start with an empty working stack
+// since their locals live in the frame. Unreachable code isn't checked.
+pub fn verify(instructions: &[Located<Instruction>]) -> Vec<Diagnostic> {
+  let mut diagnostics = HashMap::new();
+  let values = without_locations(instructions);
+  for region in control_flow::regions(&values) {
+    // Each instruction is reported once
+    depths(&values, region, |index, message| {
+      diagnostics.entry(index).or_insert_with(|| Diagnostic {
+        message,
+        severity: Severity::Error,
+        from: instructions[index].from,
+        to: instructions[index].to,
+      });
+    });
+  }
+  diagnostics.into_iter().sorted_by_key(|(index, _)| *index).map(|(_, diagnostic)| diagnostic).collect()
+}
+
+// The stack depth before each command of `region`, or None for commands no path
+// reaches. Jumps only go to labels in the same region. `report` gets the index
+// and message of each problem, possibly more than once for the same command.
+pub fn depths(instructions: &[Instruction], region: Range<usize>, mut report: impl FnMut(usize, String)) -> Vec<Option<usize>> {
+  let labels = region.clone().filter_map(|index| match &instructions[index] {
+    Instruction::Label(label) => Some((label.clone(), index)),
+    _ => None,
+  }).collect::<HashMap<String, usize>>();
+  let mut depths: Vec<Option<usize>> = vec![None; region.len()];
+  let mut worklist = vec![(region.start, 0)];
+  while let Some((index, depth)) = worklist.pop() {
+    if !region.contains(&index) {
+      continue;
+    }
+    let instruction = &instructions[index];
+    match depths[index - region.start] {
+      Some(recorded) if recorded == depth =>
+        continue,
+      Some(recorded) => {
+        if let Instruction::Label(label) = instruction {
+          report(index, format!(
+            "I found label {} reached with {} on the stack from one path and {} from another.\nTry making every path to {} leave the same number of values on the stack.",
+            label, values(recorded.min(depth)), values(recorded.max(depth)), label
+          ));
+        }
+        continue;
+      }
+      None =>
+        depths[index - region.start] = Some(depth),
+    }
+    if let Instruction::Return = instruction {
+      if depth == 0 {
+        report(index, "I found a `return` with nothing on the stack to return.\nTry pushing a return value first, like `push constant 0` in a void function.".to_string());
+      }
+      continue;
+    }
+    let (pops, pushes) = stack_effect(instruction);
+    if pops > depth {
+      report(index, format!(
+        "I found `{}` which needs {} on the stack but there {} here.\nTry pushing the missing values first or check the paths leading here.",
+        instruction, values(pops), if depth == 1 { "is only 1".to_string() } else { format!("are only {}", depth) }
+      ));
+    }
+    // Keeps going as if the missing values were there to avoid a cascade of errors
+    let depth = depth.saturating_sub(pops) + pushes;
+    match instruction {
+      Instruction::Goto(label) =>
+        worklist.extend(labels.get(label).map(|target| (*target, depth))),
+      Instruction::IfGoto(label) => {
+        worklist.extend(labels.get(label).map(|target| (*target, depth)));
+        worklist.push((index + 1, depth));
+      }
+      _ =>
+        worklist.push((index + 1, depth)),
+    }
+  }
+  depths
+}
+
+// How many values a command pops and then pushes. `return` pops the return
+// value and leaves the function.
+pub fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+  match instruction {
+    Instruction::Push { .. } => (0, 1),
+    Instruction::Pop { .. } | Instruction::IfGoto(_) | Instruction::Return => (1, 0),
+    Instruction::Arithmetic(ArithInstruction::Neg) | Instruction::Arithmetic(ArithInstruction::Not) => (1, 1),
+    Instruction::Arithmetic(_) => (2, 1),
+    Instruction::Call { args, .. } => (*args, 1),
+    _ => (0, 0),
+  }
+}
+
+fn values(count: usize) -> String {
+  if count == 1 { "1 value".to_string() } else { format!("{} values", count) }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::stack_verifier::*;
+
+  // Parses one Main.vm file with `verify` as the only check
+  fn check(source: &str) -> Result<Vec<Instruction>, String> {
+    crate::parse_checked_program(&[("Main".to_string(), source.to_string())], &[verify])
+      .map(|mut files| files.remove(0).1)
+  }
+
+  #[test]
+  fn test_verify() {
+    assert_eq!(check(
+"push constant 1
+push constant 2
+add
+pop static 0
+function Main.max 0
+push argument 0
+push argument 1
+gt
+if-goto FIRST
+push argument 1
+return
+label FIRST
+push argument 0
+return
+").map(|instructions| instructions.len()), Ok(14));
+
+    assert_eq!(check(
+"function Main.broken 1
+push local 0
+if-goto SKIP
+push constant 1
+label SKIP
+add
+call Math.multiply 2
+goto SKIP
+function Main.empty 0
+return
+"), Err(
+"In Main.vm:
+5| label SKIP
+   ^^^^^^^^^^
+⚠️ I found label SKIP reached with 0 values on the stack from one path and 1 value from another.
+Try making every path to SKIP leave the same number of values on the stack.
+
+6| add
+   ^^^
+⚠️ I found `add` which needs 2 values on the stack but there is only 1 here.
+Try pushing the missing values first or check the paths leading here.
+
+7| call Math.multiply 2
+   ^^^^^^^^^^^^^^^^^^^^
+⚠️ I found `call Math.multiply 2` which needs 2 values on the stack but there is only 1 here.
+Try pushing the missing values first or check the paths leading here.
+
+10| return
+    ^^^^^^
+⚠️ I found a `return` with nothing on the stack to return.
+Try pushing a return value first, like `push constant 0` in a void function.".to_string()));
+  }
+}
@@ -0,0 +1,90 @@
+use crate::control_flow;
+use crate::vm_parser::*;
+use lip::Located;
+
+// Checks that a file is a proper program of Project 8 and later instead of a
+// Project 7 test script: no commands outside of functions when the file has
+// functions, no `return` outside of a function, and no function running off its
+// end without a `return`.
+pub fn lint(instructions: &[Located<Instruction>]) -> Vec<Diagnostic> {
+  let error = |instruction: &Located<Instruction>, message: String|
+    Diagnostic { message, severity: Severity::Error, from: instruction.from, to: instruction.to };
+  let has_functions = instructions.iter().any(|instruction| matches!(instruction.value, Instruction::Function { .. }));
+  let top_level = instructions.iter().take_while(|instruction| !matches!(instruction.value, Instruction::Function { .. }));
+  let mut diagnostics = top_level.filter_map(|instruction| match instruction.value {
+    Instruction::Return =>
+      Some(error(instruction, "I found a `return` outside of any function so there's no caller to return to.\nTry moving it into a function.".to_string())),
+    _ if has_functions =>
+      Some(error(instruction, format!(
+        "I found `{}` outside of any function.\nTry moving it into a function. Only the tests of Project 7 have commands outside of functions.",
+        instruction.value
+      ))),
+    _ => None,
+  }).collect::<Vec<Diagnostic>>();
+
+  let values = without_locations(instructions);
+  for graph in control_flow::build("", &values).iter() {
+    let last = graph.blocks.len() - 1;
+    let end = &instructions[graph.blocks[last].instructions.end - 1];
+    let is_function = matches!(values[graph.blocks[0].instructions.start], Instruction::Function { .. });
+    let runs_off_end = !graph.unreachable_blocks().contains(&last)
+      && !matches!(end.value, Instruction::Goto(_) | Instruction::Return);
+    if is_function && runs_off_end {
+      diagnostics.push(error(end, format!(
+        "I found the end of {} but it can get here without a `return`.\nTry adding a `return` at the end so the commands after it don't run.",
+        graph.name
+      )));
+    }
+  }
+  diagnostics
+}
+
+#[cfg(test)]
+mod test {
+  use crate::strict_mode::*;
+
+  fn check(source: &str) -> Result<(), String> {
+    let diagnostics = lint(&parse_located(source).unwrap());
+    if diagnostics.is_empty() { Ok(()) } else { Err(display_diagnostics(source, &diagnostics)) }
+  }
+
+  #[test]
+  fn test_lint() {
+    // A Project 7 test script
+    assert_eq!(check("push constant 7\npush constant 8\nadd\n"), Ok(()));
+    assert_eq!(check(
+"push constant 7
+return
+function Main.loop 0
+label LOOP
+goto LOOP
+function Main.branch 0
+push argument 0
+if-goto DONE
+push constant 0
+return
+label DONE
+push constant 1
+function Main.empty 0
+"), Err(
+"1| push constant 7
+   ^^^^^^^^^^^^^^^
+⚠️ I found `push constant 7` outside of any function.
+Try moving it into a function. Only the tests of Project 7 have commands outside of functions.
+
+2| return
+   ^^^^^^
+⚠️ I found a `return` outside of any function so there's no caller to return to.
+Try moving it into a function.
+
+12| push constant 1
+    ^^^^^^^^^^^^^^^
+⚠️ I found the end of Main.branch but it can get here without a `return`.
+Try adding a `return` at the end so the commands after it don't run.
+
+13| function Main.empty 0
+    ^^^^^^^^^^^^^^^^^^^^^
+⚠️ I found the end of Main.empty but it can get here without a `return`.
+Try adding a `return` at the end so the commands after it don't run.".to_string()));
+  }
+}
@@ -0,0 +1,86 @@
+use crate::vm_parser::{parse_located, Instruction};
+use lip::Located;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tells apart the directories of tests running at the same time
+static NEXT_DIRECTORY: AtomicUsize = AtomicUsize::new(0);
+
+const MAIN: &str =
+"function Main.fibonacci 0
+push argument 0
+push constant 2
+lt
+if-goto BASE_CASE
+push argument 0
+push constant 1
+sub
+call Main.fibonacci 1
+push argument 0
+push constant 2
+sub
+call Main.fibonacci 1
+add
+return
+label BASE_CASE
+push argument 0
+return
+";
+
+const SYS: &str =
+"function Sys.init 1
+push constant 20
+call Main.fibonacci 1
+pop static 0
+push constant 5
+neg
+push constant 3
+and
+pop local 0
+push local 0
+pop static 1
+";
+
+// Ends Sys.init in an endless loop
+pub const HALT: &str =
+"label HALT
+goto HALT
+";
+
+// Sys.init stores fibonacci(20) = 6765 in Sys.0 and -5 & 3 = 3 in Sys.1, the
+// first two statics, then runs `ending`
+pub fn fibonacci_program(ending: &str) -> Vec<(String, Vec<Instruction>)> {
+  crate::parse_program(&[("Main".to_string(), MAIN.to_string()), ("Sys".to_string(), format!("{}{}", SYS, ending))]).unwrap()
+}
+
+// Parses (program name, source) pairs for the tools that run located instructions
+pub fn located_program(sources: &[(&str, &str)]) -> Vec<(String, Vec<Located<Instruction>>)> {
+  sources.iter().map(|(program_name, source)| (program_name.to_string(), parse_located(source).unwrap())).collect()
+}
+
+// Writes `files` to a fresh directory and calls `run` with it, for running the
+// output of a backend. Returns None and tells what was skipped when `run` can't
+// start `tool` because it isn't installed.
+pub fn run_if_installed<T>(tool: &str, skipped: &str, files: &[(&str, &[u8])], run: impl FnOnce(&Path) -> io::Result<T>) -> Option<T> {
+  let directory = std::env::temp_dir().join(format!(
+    "vm-compiler-{}-{}-{}", tool, std::process::id(), NEXT_DIRECTORY.fetch_add(1, Ordering::SeqCst)
+  ));
+  fs::create_dir_all(&directory).unwrap();
+  for (name, contents) in files.iter() {
+    fs::write(directory.join(name), contents).unwrap();
+  }
+  let result = run(&directory);
+  fs::remove_dir_all(&directory).unwrap();
+  match result {
+    Ok(result) =>
+      Some(result),
+    Err(why) if why.kind() == io::ErrorKind::NotFound => {
+      eprintln!("Skipped running {} because {} isn't installed.", skipped, tool);
+      None
+    }
+    Err(why) =>
+      panic!("I couldn't run {}: {}", tool, why),
+  }
+}
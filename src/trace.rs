@@ -0,0 +1,404 @@
+use crate::debugger::{show, Breakpoints};
+use crate::hack_emulator::RAM_SIZE;
+use crate::vm_interpreter::{Machine, State};
+use crate::vm_parser::*;
+use lip::Located;
+
+// Starts every trace file
+const MAGIC: &[u8] = b"VMTRACE1";
+// Steps between the full stacks kept in memory so seeking only replays a few
+// deltas
+const KEYFRAME_INTERVAL: usize = 1024;
+
+const HELP: &str =
+"step [COUNT]     go forward COUNT steps, 1 without COUNT (also `s`)
+back [COUNT]     go back COUNT steps, 1 without COUNT
+seek STEP        go to step STEP
+break NAME       stop at function or label NAME, or list the breakpoints without NAME
+delete NAME      remove the breakpoint at NAME
+continue         go forward to the next breakpoint or the end (also `c`)
+reverse          go back to the last breakpoint or the start (also `rc`)
+stack            show the whole stack at this step
+quit             leave the replay (also `q`)";
+
+// Where a program was and its stack before each command it ran, and once more
+// after the last one. A step only stores how its stack differs from the one
+// before, as the number of values it keeps and the values pushed on top, since
+// most commands push or pop a single value.
+pub struct Trace {
+  // The number of commands in the program, to catch traces of other programs
+  pub commands: usize,
+  pcs: Vec<u32>,
+  // The kept count, pushed count and pushed values of each step as varints
+  deltas: Vec<u8>,
+  keyframes: Vec<Keyframe>,
+  // The stack of the last step
+  stack: Vec<i16>,
+}
+
+// The stack at step `n * KEYFRAME_INTERVAL` and where the delta of the step
+// after it starts
+struct Keyframe {
+  stack: Vec<i16>,
+  offset: usize,
+}
+
+impl Trace {
+  pub fn new(commands: usize) -> Self {
+    Trace { commands, pcs: vec![], deltas: vec![], keyframes: vec![], stack: vec![] }
+  }
+
+  pub fn len(&self) -> usize {
+    self.pcs.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pcs.is_empty()
+  }
+
+  // Adds a step
+  pub fn record(&mut self, pc: usize, stack: &[i16]) {
+    let kept = self.stack.iter().zip(stack.iter()).take_while(|(before, after)| before == after).count();
+    write_varint(&mut self.deltas, kept as u64);
+    write_varint(&mut self.deltas, (stack.len() - kept) as u64);
+    for value in stack[kept..].iter() {
+      write_varint(&mut self.deltas, zigzag(*value));
+    }
+    self.stack.truncate(kept);
+    self.stack.extend_from_slice(&stack[kept..]);
+    if self.pcs.len().is_multiple_of(KEYFRAME_INTERVAL) {
+      self.keyframes.push(Keyframe { stack: self.stack.clone(), offset: self.deltas.len() });
+    }
+    self.pcs.push(pc as u32);
+  }
+
+  // The command to run next at a step, which is past the last command once the
+  // program halted
+  pub fn pc(&self, step: usize) -> Option<usize> {
+    self.pcs.get(step).map(|pc| *pc as usize)
+  }
+
+  pub fn stack(&self, step: usize) -> Option<Vec<i16>> {
+    let keyframe = self.keyframes.get(step / KEYFRAME_INTERVAL).filter(|_| step < self.len())?;
+    let mut stack = keyframe.stack.clone();
+    let mut offset = keyframe.offset;
+    for _ in 0..step % KEYFRAME_INTERVAL {
+      apply(&self.deltas, &mut offset, &mut stack)?;
+    }
+    Some(stack)
+  }
+
+  // The trace file: the magic bytes, the number of commands and of steps, the
+  // command of every step and then the deltas, all numbers as varints
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    write_varint(&mut bytes, self.commands as u64);
+    write_varint(&mut bytes, self.len() as u64);
+    for pc in self.pcs.iter() {
+      write_varint(&mut bytes, *pc as u64);
+    }
+    bytes.extend_from_slice(&self.deltas);
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Trace, String> {
+    let invalid = || "I can't read this trace. It's either damaged or not a trace file.\nTry recording it again.".to_string();
+    if !bytes.starts_with(MAGIC) {
+      return Err(invalid());
+    }
+    let mut offset = MAGIC.len();
+    let commands = read_varint(bytes, &mut offset).ok_or_else(invalid)? as usize;
+    let steps = read_varint(bytes, &mut offset).ok_or_else(invalid)?;
+    let mut pcs = vec![];
+    for _ in 0..steps {
+      match read_varint(bytes, &mut offset) {
+        Some(pc) if pc as usize <= commands => pcs.push(pc as usize),
+        _ => return Err(invalid()),
+      }
+    }
+    let mut trace = Trace::new(commands);
+    let mut stack = vec![];
+    for pc in pcs {
+      apply(bytes, &mut offset, &mut stack).ok_or_else(invalid)?;
+      trace.record(pc, &stack);
+    }
+    if offset == bytes.len() { Ok(trace) } else { Err(invalid()) }
+  }
+}
+
+// Runs the machine for at most `steps` commands while recording a trace. Also
+// returns the state it stopped in or the error that stopped it, which is often
+// why there's a trace in the first place.
+pub fn record(machine: &mut Machine, steps: usize) -> (Trace, Result<State, String>) {
+  let mut trace = Trace::new(machine.commands.len());
+  trace.record(machine.pc, machine.stack());
+  let mut state = Ok(State::Running);
+  for _ in 0..steps {
+    state = machine.step();
+    if state.is_err() {
+      break;
+    }
+    trace.record(machine.pc, machine.stack());
+    if state == Ok(State::Halted) {
+      break;
+    }
+  }
+  (trace, state)
+}
+
+// Moves through a trace forwards and backwards. Each line typed at the prompt
+// goes to `execute`.
+pub struct Replay {
+  // Only for finding commands, never run
+  machine: Machine,
+  trace: Trace,
+  pub step: usize,
+  breakpoints: Breakpoints,
+}
+
+impl Replay {
+  pub fn new(files: Vec<(String, Vec<Located<Instruction>>)>, trace: Trace) -> Result<Self, String> {
+    let machine = Machine::new(files);
+    if trace.commands != machine.commands.len() {
+      return Err(format!(
+        "I found a trace of a program with {} commands but this program has {}.\nTry recording the trace again.",
+        trace.commands, machine.commands.len()
+      ));
+    }
+    if trace.is_empty() {
+      return Err("I found a trace without any steps.\nTry recording it again.".to_string());
+    }
+    Ok(Replay { machine, trace, step: 0, breakpoints: Breakpoints::default() })
+  }
+
+  // The step we are at, like `Step 3 of 10: Main.vm:3  push constant 2`
+  pub fn status(&self) -> String {
+    let pc = self.trace.pc(self.step).unwrap();
+    format!("Step {} of {}: {}", self.step, self.trace.len() - 1, if pc < self.machine.commands.len() {
+      format!("{}  {}", self.machine.location(pc), self.machine.instruction(pc).value)
+    } else {
+      "the program halted".to_string()
+    })
+  }
+
+  // Runs one replay command and returns what to show
+  pub fn execute(&mut self, line: &str) -> String {
+    let words = line.split_whitespace().collect::<Vec<&str>>();
+    let last = self.trace.len() - 1;
+    match words.as_slice() {
+      [] =>
+        String::new(),
+      ["help"] =>
+        HELP.to_string(),
+      ["step"] | ["s"] =>
+        self.seek(self.step.saturating_add(1).min(last)),
+      ["back"] =>
+        self.seek(self.step.saturating_sub(1)),
+      [command @ "step", count] | [command @ "s", count] | [command @ "back", count] =>
+        match count.parse::<usize>() {
+          Ok(count) if *command == "back" => self.seek(self.step.saturating_sub(count)),
+          Ok(count) => self.seek(self.step.saturating_add(count).min(last)),
+          Err(_) => format!("I'm expecting a number of steps after `{}` but found `{}`.", command, count),
+        },
+      ["seek", step] =>
+        match step.parse::<usize>() {
+          Ok(step) if step <= last => self.seek(step),
+          _ => format!("I'm expecting a step from 0 to {} after `seek` but found `{}`.", last, step),
+        },
+      ["break"] | ["b"] =>
+        self.breakpoints.list(&self.machine),
+      ["break", name] | ["b", name] =>
+        self.breakpoints.add(&self.machine, name),
+      ["delete", name] =>
+        self.breakpoints.delete(name),
+      ["continue"] | ["c"] => {
+        let breakpoint = (self.step + 1..=last).find(|step| self.breakpoint(*step).is_some());
+        self.stop_at(breakpoint, last, "Reached the end of the trace.")
+      }
+      ["reverse"] | ["rc"] => {
+        let breakpoint = (0..self.step).rev().find(|step| self.breakpoint(*step).is_some());
+        self.stop_at(breakpoint, 0, "Reached the start of the trace.")
+      }
+      ["stack"] =>
+        format!("stack: {}", show(&self.trace.stack(self.step).unwrap())),
+      _ =>
+        format!("I don't know the command `{}`.\nTry `help` to see the commands.", line.trim()),
+    }
+  }
+
+  fn seek(&mut self, step: usize) -> String {
+    self.step = step;
+    self.status()
+  }
+
+  // Goes to the step of a breakpoint, or else to the end in that direction
+  fn stop_at(&mut self, breakpoint: Option<usize>, end: usize, end_message: &str) -> String {
+    match breakpoint {
+      Some(step) => {
+        let name = self.breakpoint(step).unwrap().to_string();
+        format!("Breakpoint at {}\n{}", name, self.seek(step))
+      }
+      None => format!("{}\n{}", end_message, self.seek(end)),
+    }
+  }
+
+  fn breakpoint(&self, step: usize) -> Option<&str> {
+    self.breakpoints.at(self.trace.pc(step).unwrap())
+  }
+}
+
+// Applies the delta at `offset` to a stack and moves past it, or None if the
+// delta doesn't make sense
+fn apply(bytes: &[u8], offset: &mut usize, stack: &mut Vec<i16>) -> Option<()> {
+  let kept = read_varint(bytes, offset)? as usize;
+  let pushed = read_varint(bytes, offset)? as usize;
+  if kept > stack.len() {
+    return None;
+  }
+  kept.checked_add(pushed).filter(|total| *total <= RAM_SIZE)?;
+  stack.truncate(kept);
+  for _ in 0..pushed {
+    stack.push(unzigzag(read_varint(bytes, offset)?));
+  }
+  Some(())
+}
+
+// 7 bits per byte, lowest first, with the high bit set on all but the last byte
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    bytes.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<u64> {
+  let mut value = 0;
+  for shift in (0..64).step_by(7) {
+    let byte = *bytes.get(*offset)?;
+    *offset += 1;
+    value |= ((byte & 0x7f) as u64) << shift;
+    if byte & 0x80 == 0 {
+      return Some(value);
+    }
+  }
+  None
+}
+
+// Small negative values get small varints too: 0, -1, 1, -2, 2 become 0, 1, 2, 3, 4
+fn zigzag(value: i16) -> u64 {
+  ((value << 1) ^ (value >> 15)) as u16 as u64
+}
+
+fn unzigzag(value: u64) -> i16 {
+  ((value >> 1) as i16) ^ -((value & 1) as i16)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::trace::*;
+  use crate::test_support::located_program;
+
+  #[test]
+  fn test_replay() {
+    let sources = [
+      ("Sys", "function Sys.init 0\npush constant 3\ncall Main.countdown 1\npop static 0\nlabel HALT\ngoto HALT\n"),
+      ("Main",
+"// Counts down to 0 by 1 and returns -1
+function Main.countdown 0
+label LOOP
+push argument 0
+push constant 1
+sub
+pop argument 0
+push argument 0
+if-goto LOOP
+push constant 1
+neg
+return
+"),
+    ];
+    let files = located_program(&sources);
+    let mut machine = Machine::new(files.clone());
+    let (trace, state) = record(&mut machine, 1000);
+    assert_eq!(state, Ok(State::Halted));
+    assert_eq!(machine.ram[16], -1);
+    let trace = Trace::from_bytes(&trace.to_bytes()).unwrap();
+    // A pc past the commands, and a second step pushing 2^64 - 1 values
+    for bytes in [&b"VMTRACE1\x01\x01\x07"[..], &b"VMTRACE1\x01\x02\x00\x00\x00\x01\x00\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"[..]] {
+      assert_eq!(Trace::from_bytes(bytes).err(), Some("I can't read this trace. It's either damaged or not a trace file.\nTry recording it again.".to_string()));
+    }
+
+    let mut replay = Replay::new(files.clone(), trace).unwrap();
+    let session = [
+      "stack",
+      "break LOOP",
+      "c",
+      "c",
+      "stack",
+      "back",
+      "back 2",
+      "stack",
+      "rc",
+      "rc",
+      "rc",
+      "seek 31",
+      "step",
+      "seek 32",
+    ].iter().map(|line| format!("> {}\n{}", line, replay.execute(line))).collect::<Vec<String>>().join("\n");
+    assert_eq!(session,
+"> stack
+stack: [17, 0, 0, 0, 0]
+> break LOOP
+Breakpoint at LOOP in Main.vm:3
+> c
+Breakpoint at LOOP
+Step 4 of 31: Main.vm:3  label LOOP
+> c
+Breakpoint at LOOP
+Step 11 of 31: Main.vm:3  label LOOP
+> stack
+stack: [17, 0, 0, 0, 0, 2, 3, 261, 256, 0, 0]
+> back
+Step 10 of 31: Main.vm:9  if-goto LOOP
+> back 2
+Step 8 of 31: Main.vm:7  pop argument 0
+> stack
+stack: [17, 0, 0, 0, 0, 3, 3, 261, 256, 0, 0, 2]
+> rc
+Breakpoint at LOOP
+Step 4 of 31: Main.vm:3  label LOOP
+> rc
+Reached the start of the trace.
+Step 0 of 31: Sys.vm:1  function Sys.init 0
+> rc
+Reached the start of the trace.
+Step 0 of 31: Sys.vm:1  function Sys.init 0
+> seek 31
+Step 31 of 31: Sys.vm:6  goto HALT
+> step
+Step 31 of 31: Sys.vm:6  goto HALT
+> seek 32
+I'm expecting a step from 0 to 31 after `seek` but found `32`.");
+
+    // Seeking across keyframes gives back the stacks that were recorded
+    let long_files = located_program(&[("Sys", &sources[0].1.replace("push constant 3", "push constant 400")), sources[1]]);
+    let mut machine = Machine::new(long_files);
+    let mut trace = Trace::new(machine.commands.len());
+    let mut stacks = vec![];
+    let mut state = State::Running;
+    while state == State::Running {
+      trace.record(machine.pc, machine.stack());
+      stacks.push(machine.stack().to_vec());
+      state = machine.step().unwrap();
+    }
+    let trace = Trace::from_bytes(&trace.to_bytes()).unwrap();
+    assert!(trace.len() > 2 * KEYFRAME_INTERVAL);
+    assert!((0..trace.len()).all(|step| trace.stack(step).as_ref() == Some(&stacks[step])));
+    assert_eq!(trace.stack(trace.len()), None);
+
+    let mut machine = Machine::new(files[1..].to_vec());
+    let (trace, _) = record(&mut machine, 1000);
+    assert_eq!(Replay::new(files, trace).err(), Some("I found a trace of a program with 11 commands but this program has 17.\nTry recording the trace again.".to_string()));
+  }
+}
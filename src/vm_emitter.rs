@@ -1,86 +1,259 @@
 use crate::vm_parser::*;
+use crate::backend::{self, Backend};
+use crate::hack_assembler::VARIABLE_BASE;
+use crate::memory_layout::MemoryLayout;
+use crate::stack_optimizer::StackOptimizer;
+use std::collections::HashMap;
 
 pub fn emit(program_name: &str, instructions: Vec<Instruction>) -> String {
   instructions
     .iter()
-    .filter(|instruction| match instruction { Instruction::Ignored => false, _ => true } )
+    .filter(|instruction| !matches!(instruction, Instruction::Ignored))
     .enumerate()
-    .map(|(instruction_index, instruction)| match instruction {
-      Instruction::Arithmetic(arith_instruction) =>
-        match arith_instruction {
-          ArithInstruction::Add =>
-            emit_binary_arithmetic("M=M+D"),
-          ArithInstruction::Sub =>
-            emit_binary_arithmetic("M=M-D"),
-          ArithInstruction::Eq =>
-            emit_comparison(instruction_index, "EQ"),
-          ArithInstruction::Gt =>
-            emit_comparison(instruction_index, "GT"),
-          ArithInstruction::Lt =>
-            emit_comparison(instruction_index, "LT"),
-          ArithInstruction::And =>
-            emit_binary_arithmetic("M=D&M"),
-          ArithInstruction::Or =>
-            emit_binary_arithmetic("M=D|M"),
-          ArithInstruction::Neg =>
-            emit_unary_arithmetic("M=-M"),
-          ArithInstruction::Not =>
-            emit_unary_arithmetic("M=!M"),
-        },
-      Instruction::Push { segment, offset } =>
-        match segment {
-          Segment::Local =>
-            emit_push_fixed_segment("LCL", offset),
-          Segment::Argument =>
-            emit_push_fixed_segment("ARG", offset),
-          Segment::This =>
-            emit_push_fixed_segment("THIS", offset),
-          Segment::That =>
-            emit_push_fixed_segment("THAT", offset),
-          Segment::Constant =>
-            emit_push_constant_segment(offset),
-          Segment::Static =>
-            emit_push_static_segment(program_name, offset),
-          Segment::Temp =>
-            emit_push_temp_segment(offset),
-          Segment::Pointer =>
-            emit_push_pointer_segment(offset),
-        },
-      Instruction::Pop { segment, offset} =>
-        match segment {
-          Segment::Local =>
-            emit_pop_fixed_segment("LCL", offset),
-          Segment::Argument =>
-            emit_pop_fixed_segment("ARG", offset),
-          Segment::This =>
-            emit_pop_fixed_segment("THIS", offset),
-          Segment::That =>
-            emit_pop_fixed_segment("THAT", offset),
-          Segment::Constant =>
-            panic!("`pop constant {}` is an invalid command.\nYou can't store a popped value into a constant. The parser should filter out this impossible case before emitting.", offset),
-          Segment::Static =>
-            emit_pop_static_segment(program_name, offset),
-          Segment::Temp =>
-            emit_pop_temp_segment(offset),
-          Segment::Pointer =>
-            emit_pop_pointer_segment(offset),
-        },
-      Instruction::Ignored =>
-        panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
-      Instruction::Label(label) =>
-        emit_label(label),
-      Instruction::Goto(label) =>
-        emit_goto(label),
-      Instruction::IfGoto(label) =>
-        emit_if_goto(label),
-      Instruction::Function { name, local_vars } =>
-        emit_function(name, *local_vars),
-      Instruction::Return =>
-        emit_return(),
-    }).collect::<Vec<String>>()
+    .map(|(instruction_index, instruction)| emit_instruction(program_name, instruction_index, instruction))
+    .collect::<Vec<String>>()
     .join("\n")
 }
 
+// Emits several files as one program. Instruction indices keep counting across
+// files so the generated labels stay unique. The bootstrap code is added when
+// one of the files defines `Sys.init`.
+pub fn emit_program(files: Vec<(String, Vec<Instruction>)>) -> String {
+  backend::emit(&mut HackBackend::default(), &files)
+}
+
+// Opt-in rewrites of the emitted code
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Optimizations {
+  // Keep straight-line code off the stack, see `StackOptimizer`
+  pub stack_registers: bool,
+  // Reuse the frame for a `call` right before a `return`
+  pub tail_calls: bool,
+}
+
+#[derive(Default)]
+pub struct HackBackend {
+  layout: MemoryLayout,
+  // Static addresses when the layout moves the statics away from the assembler's variables
+  statics: HashMap<(String, usize), usize>,
+  // Set when straight-line code should skip the stack
+  optimizer: Option<StackOptimizer>,
+  tail_calls: bool,
+  // A call held back until the next command shows whether it's a tail call,
+  // with its instruction index
+  pending_call: Option<(Instruction, usize)>,
+  program_name: String,
+  instruction_index: usize,
+  output: Vec<String>,
+}
+
+impl HackBackend {
+  // The layout should already be validated and checked against the program
+  pub fn new(layout: MemoryLayout, optimizations: Optimizations) -> Self {
+    let optimizer = if optimizations.stack_registers { Some(StackOptimizer::default()) } else { None };
+    HackBackend { layout, optimizer, tail_calls: optimizations.tail_calls, ..HackBackend::default() }
+  }
+
+  // Takes the code emitted so far, for measuring the code of each command
+  pub fn take_output(&mut self) -> String {
+    std::mem::take(&mut self.output).join("\n")
+  }
+
+  fn emit_at(&mut self, instruction_index: usize, instruction: &Instruction) {
+    let code = match &mut self.optimizer {
+      Some(optimizer) => optimizer.emit(&self.layout, &self.statics, &self.program_name, instruction_index, instruction),
+      None => emit_instruction_in(&self.layout, &self.statics, &self.program_name, instruction_index, instruction),
+    };
+    // Optimized pushes may not emit anything yet
+    if !code.is_empty() {
+      self.output.push(code);
+    }
+  }
+
+  fn flush(&mut self) {
+    if let Some((call, instruction_index)) = self.pending_call.take() {
+      self.emit_at(instruction_index, &call);
+    }
+    if let Some(optimizer) = &mut self.optimizer {
+      let code = optimizer.flush();
+      if !code.is_empty() {
+        self.output.push(code);
+      }
+    }
+  }
+}
+
+impl Backend for HackBackend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+    self.statics = static_addresses_from(self.layout.static_base, files);
+    let has_sys_init = files.iter().any(|(_, instructions)| instructions.iter().any(|instruction|
+      matches!(instruction, Instruction::Function { name, .. } if name == "Sys.init")
+    ));
+    if has_sys_init {
+      self.output.push(emit_bootstrap(self.layout.stack_base, self.instruction_index));
+      self.instruction_index += 1;
+    }
+  }
+
+  fn begin_file(&mut self, program_name: &str) {
+    self.flush();
+    self.program_name = program_name.to_string();
+  }
+
+  fn emit_instruction(&mut self, instruction: &Instruction) {
+    let instruction_index = self.instruction_index;
+    self.instruction_index += 1;
+    if let Some((call, call_index)) = self.pending_call.take() {
+      match (&call, instruction) {
+        // Nothing can jump to the `return` so it goes away with the call
+        (Instruction::Call { name, args }, Instruction::Return) => {
+          self.flush();
+          self.output.push(emit_tail_call(name, *args));
+          return;
+        }
+        _ =>
+          self.emit_at(call_index, &call),
+      }
+    }
+    if self.tail_calls && matches!(instruction, Instruction::Call { .. }) {
+      self.pending_call = Some((instruction.clone(), instruction_index));
+    } else {
+      self.emit_at(instruction_index, instruction);
+    }
+  }
+
+  fn finish(&mut self) -> String {
+    self.flush();
+    self.output.join("\n")
+  }
+}
+
+// The RAM address the assembler gives each (file, static index). Statics are the
+// only variables in the emitted code so they get addresses from RAM[16] on in
+// order of first use. Other backends use this to keep the same memory layout.
+pub fn static_addresses(files: &[(String, Vec<Instruction>)]) -> HashMap<(String, usize), usize> {
+  static_addresses_from(VARIABLE_BASE as usize, files)
+}
+
+fn static_addresses_from(static_base: usize, files: &[(String, Vec<Instruction>)]) -> HashMap<(String, usize), usize> {
+  let mut addresses = HashMap::new();
+  for (program_name, instructions) in files.iter() {
+    for instruction in instructions.iter() {
+      if let Instruction::Push { segment: Segment::Static, offset } | Instruction::Pop { segment: Segment::Static, offset } = instruction {
+        let address = static_base + addresses.len();
+        addresses.entry((program_name.clone(), *offset)).or_insert(address);
+      }
+    }
+  }
+  addresses
+}
+
+// A `File.i` symbol when the statics are the assembler's variables, otherwise the RAM address
+pub fn static_address(layout: &MemoryLayout, statics: &HashMap<(String, usize), usize>, program_name: &str, offset: usize) -> String {
+  if layout.static_base == VARIABLE_BASE as usize {
+    format!("{}.{}", program_name, offset)
+  } else {
+    statics[&(program_name.to_string(), offset)].to_string()
+  }
+}
+
+// SP = stack_base
+// call Sys.init 0
+pub fn emit_bootstrap(stack_base: usize, instruction_index: usize) -> String {
+format!(
+"@{}
+D=A
+@SP
+M=D
+{}", stack_base, emit_call("Sys.init", 0, instruction_index))
+}
+
+// `instruction_index` keeps the generated labels of comparisons and calls unique
+pub fn emit_instruction(program_name: &str, instruction_index: usize, instruction: &Instruction) -> String {
+  emit_instruction_in(&MemoryLayout::default(), &HashMap::new(), program_name, instruction_index, instruction)
+}
+
+// Emits with the temp and static segments where `layout` puts them. `statics` is only
+// used when the statics don't start at the assembler's first variable.
+pub fn emit_instruction_in(layout: &MemoryLayout, statics: &HashMap<(String, usize), usize>, program_name: &str, instruction_index: usize, instruction: &Instruction) -> String {
+  let static_address = |offset: &usize| static_address(layout, statics, program_name, *offset);
+  match instruction {
+    Instruction::Arithmetic(arith_instruction) =>
+      match arith_instruction {
+        ArithInstruction::Add =>
+          emit_binary_arithmetic("M=M+D"),
+        ArithInstruction::Sub =>
+          emit_binary_arithmetic("M=M-D"),
+        ArithInstruction::Eq =>
+          emit_comparison(instruction_index, "EQ"),
+        ArithInstruction::Gt =>
+          emit_comparison(instruction_index, "GT"),
+        ArithInstruction::Lt =>
+          emit_comparison(instruction_index, "LT"),
+        ArithInstruction::And =>
+          emit_binary_arithmetic("M=D&M"),
+        ArithInstruction::Or =>
+          emit_binary_arithmetic("M=D|M"),
+        ArithInstruction::Neg =>
+          emit_unary_arithmetic("M=-M"),
+        ArithInstruction::Not =>
+          emit_unary_arithmetic("M=!M"),
+      },
+    Instruction::Push { segment, offset } =>
+      match segment {
+        Segment::Local =>
+          emit_push_fixed_segment("LCL", offset),
+        Segment::Argument =>
+          emit_push_fixed_segment("ARG", offset),
+        Segment::This =>
+          emit_push_fixed_segment("THIS", offset),
+        Segment::That =>
+          emit_push_fixed_segment("THAT", offset),
+        Segment::Constant =>
+          emit_push_constant_segment(offset),
+        Segment::Static =>
+          emit_push_static_segment(&static_address(offset)),
+        Segment::Temp =>
+          emit_push_temp_segment(layout.temp_base, offset),
+        Segment::Pointer =>
+          emit_push_pointer_segment(offset),
+      },
+    Instruction::Pop { segment, offset} =>
+      match segment {
+        Segment::Local =>
+          emit_pop_fixed_segment("LCL", offset),
+        Segment::Argument =>
+          emit_pop_fixed_segment("ARG", offset),
+        Segment::This =>
+          emit_pop_fixed_segment("THIS", offset),
+        Segment::That =>
+          emit_pop_fixed_segment("THAT", offset),
+        Segment::Constant =>
+          panic!("`pop constant {}` is an invalid command.\nYou can't store a popped value into a constant. The parser should filter out this impossible case before emitting.", offset),
+        Segment::Static =>
+          emit_pop_static_segment(&static_address(offset)),
+        Segment::Temp =>
+          emit_pop_temp_segment(layout.temp_base, offset),
+        Segment::Pointer =>
+          emit_pop_pointer_segment(offset),
+      },
+    Instruction::Ignored =>
+      panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
+    Instruction::Label(label) =>
+      emit_label(label),
+    Instruction::Goto(label) =>
+      emit_goto(label),
+    Instruction::IfGoto(label) =>
+      emit_if_goto(label),
+    Instruction::Function { name, local_vars } =>
+      emit_function(name, *local_vars),
+    Instruction::Call { name, args } =>
+      emit_call(name, *args, instruction_index),
+    Instruction::Return =>
+      emit_return(),
+  }
+}
+
 fn emit_binary_arithmetic(operation_str: &str) -> String {
   format!(
 "@SP
@@ -126,31 +299,60 @@ A=M
 M=M+1", operation_str)
 }
 
+// base + 0 and base + 1 are `A=M` and `A=M+1`, and a couple more
+// `A=A+1` steps are still shorter than adding the offset through D
+pub const PUSH_STEP_LIMIT: usize = 2;
+pub const POP_STEP_LIMIT: usize = 5;
+
 fn emit_push_fixed_segment(base_address: &str, offset: &usize) -> String {
-  format!(
+  if *offset <= PUSH_STEP_LIMIT {
+    format!(
+"@{}
+{}
+D=M
+{}", base_address, emit_offset_steps(*offset), emit_push_d_to_stack())
+  } else {
+    format!(
 "@{}
 D=M
 @{}
-D=D+A
-A=D
+A=D+A
 D=M
 {}", base_address, offset, emit_push_d_to_stack())
+  }
 }
 
 fn emit_pop_fixed_segment(base_address: &str, offset: &usize) -> String {
-  format!(
+  if *offset <= POP_STEP_LIMIT {
+    format!(
+"{}
+@{}
+{}
+M=D", emit_pop_stack_to_d(), base_address, emit_offset_steps(*offset))
+  } else {
+    // Keeps the address in R13 while popping into D
+    format!(
 "@{}
 D=M
 @{}
 D=D+A
+@R13
+M=D
 @SP
-M=M-1
-A=M
-D=D+M
-@SP
+AM=M-1
+D=M
+@R13
 A=M
-A=D-M
-M=D-A", base_address, offset)
+M=D", base_address, offset)
+  }
+}
+
+// A = M + offset
+fn emit_offset_steps(offset: usize) -> String {
+  match offset {
+    0 => "A=M".to_string(),
+    _ => format!("A=M+1{}", "\nA=A+1".repeat(offset - 1)),
+  }
 }
 
 fn emit_push_constant_segment(number: &usize) -> String {
@@ -160,44 +362,33 @@ D=A
 {}", number, emit_push_d_to_stack())
 }
 
-fn emit_push_static_segment(file_name: &str, offset: &usize) -> String {
+// `address` is either a `File.i` symbol or a RAM address
+fn emit_push_static_segment(address: &str) -> String {
   format!(
-"@{}.{}
+"@{}
 D=M
-{}", file_name, offset, emit_push_d_to_stack())
+{}", address, emit_push_d_to_stack())
 }
 
-fn emit_pop_static_segment(file_name: &str, offset: &usize) -> String {
+fn emit_pop_static_segment(address: &str) -> String {
   format!(
 "{}
-@{}.{}
-M=D", emit_pop_stack_to_d(), file_name, offset)
+@{}
+M=D", emit_pop_stack_to_d(), address)
 }
 
-fn emit_push_temp_segment(offset: &usize) -> String {
-format!("@5
-D=A
-@{}
-D=D+A
-A=D
+// Temp entries have fixed addresses
+fn emit_push_temp_segment(temp_base: usize, offset: &usize) -> String {
+format!("@{}
 D=M
-{}", offset, emit_push_d_to_stack())
+{}", temp_base + offset, emit_push_d_to_stack())
 }
 
-fn emit_pop_temp_segment(offset: &usize) -> String {
+fn emit_pop_temp_segment(temp_base: usize, offset: &usize) -> String {
 format!(
-"@5
-D=A
+"{}
 @{}
-D=D+A
-@SP
-M=M-1
-A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A", offset)
+M=D", emit_pop_stack_to_d(), temp_base + offset)
 }
 
 fn emit_push_pointer_segment(offset: &usize) -> String {
@@ -249,7 +440,7 @@ M=M-1
 A=M
 D=M
 @{}
-D;JGT", label)
+D;JNE", label)
 }
 
 // (function_label)
@@ -259,7 +450,94 @@ fn emit_function(name: &str, local_vars: usize) -> String {
 format!(
 "({})
 @SP
-{}", name, std::iter::repeat("M=M+1").take(local_vars).collect::<Vec<&str>>().join("\n"))
+{}", name, std::iter::repeat_n("M=M+1", local_vars).collect::<Vec<&str>>().join("\n"))
+}
+
+// Reuses the current frame for `call name args` followed by `return`. The saved
+// frame is copied above the new arguments, both move down to ARG, and the callee
+// starts with LCL and SP right after them, so it returns straight to our caller.
+fn emit_tail_call(name: &str, args: usize) -> String {
+  // RAM[R14++] = RAM[R13++]
+  let copy_word =
+"@R13
+AM=M+1
+A=A-1
+D=M
+@R14
+AM=M+1
+A=A-1
+M=D";
+format!(
+"@LCL
+D=M
+@5
+D=D-A
+@R13
+M=D
+@SP
+D=M
+@R14
+M=D
+{}
+@SP
+D=M
+@{}
+D=D-A
+@R13
+M=D
+@ARG
+D=M
+@R14
+M=D
+{}
+@R14
+D=M
+@LCL
+M=D
+@SP
+M=D
+@{}
+0;JMP", [copy_word; 5].join("\n"), args, vec![copy_word; args + 5].join("\n"), name)
+}
+
+// push returnAddress
+// push LCL
+// push ARG
+// push THIS
+// push THAT
+// ARG = SP - 5 - args
+// LCL = SP
+// goto function_label
+// (returnAddress)
+fn emit_call(name: &str, args: usize, instruction_index: usize) -> String {
+  let return_label = &format!("{}$ret.{}", name, instruction_index);
+  let push_pointer = |pointer: &str| format!(
+"@{}
+D=M
+{}", pointer, emit_push_d_to_stack());
+format!(
+"@{}
+D=A
+{}
+{}
+{}
+{}
+{}
+@SP
+D=M
+@{}
+D=D-A
+@ARG
+M=D
+@SP
+D=M
+@LCL
+M=D
+@{}
+0;JMP
+({})", return_label, emit_push_d_to_stack(),
+  push_pointer("LCL"), push_pointer("ARG"), push_pointer("THIS"), push_pointer("THAT"),
+  5 + args, name, return_label)
 }
 
 // endFrame = LCL
@@ -321,3 +599,114 @@ M=D
 A=M
 0;JMP", emit_pop_stack_to_d())
 }
+
+#[cfg(test)]
+mod test {
+  use crate::vm_emitter::*;
+  use crate::hack_emulator::Emulator;
+
+  #[test]
+  fn test_segment_templates() {
+    // (command, instructions of the templates before offsets were taken into account, instructions now)
+    let benchmark = [
+      ("push local 0", 11, 8),
+      ("push argument 1", 11, 8),
+      ("push this 2", 11, 9),
+      ("push that 7", 11, 10),
+      ("pop local 0", 12, 7),
+      ("pop argument 1", 12, 7),
+      ("pop this 3", 12, 9),
+      ("pop that 5", 12, 11),
+      ("pop local 9", 12, 12),
+      ("push temp 3", 11, 7),
+      ("pop temp 3", 12, 6),
+      ("push pointer 1", 7, 7),
+      ("pop pointer 0", 6, 6),
+    ];
+    for (command, before, now) in benchmark.iter() {
+      let instructions = parse(&format!("{}\n", command)).unwrap();
+      assert_eq!((command, crate::rom_size(&emit("Main", instructions))), (command, *now));
+      assert!(now <= before, "{} got longer", command);
+    }
+    assert_eq!(emit("Main", parse("pop this 2\n").unwrap()),
+"@SP
+M=M-1
+A=M
+D=M
+@THIS
+A=M+1
+A=A+1
+M=D");
+    assert_eq!(emit("Main", parse("pop local 9\n").unwrap()),
+"@LCL
+D=M
+@9
+D=D+A
+@R13
+M=D
+@SP
+AM=M-1
+D=M
+@R13
+A=M
+M=D");
+  }
+
+  #[test]
+  fn test_tail_calls() {
+    let source =
+"function Sys.init 0
+push constant 1234
+pop pointer 0
+push constant 200
+call Main.count 1
+pop static 0
+push pointer 0
+pop static 1
+label HALT
+goto HALT
+function Main.count 0
+push argument 0
+push constant 0
+push constant 7
+call Main.sum 3
+return
+function Main.sum 1
+push constant 99
+pop pointer 0
+push argument 0
+if-goto MORE
+push argument 1
+return
+label MORE
+push argument 0
+push constant 1
+sub
+push argument 1
+push argument 0
+add
+push argument 2
+call Main.sum 3
+return
+";
+    let files = crate::parse_program(&[("Main".to_string(), source.to_string())]).unwrap();
+    // (result, THIS after the calls, highest SP)
+    let run = |tail_calls: bool| {
+      let optimizations = Optimizations { tail_calls, ..Optimizations::default() };
+      let assembly = backend::emit(&mut HackBackend::new(MemoryLayout::default(), optimizations), &files);
+      let mut emulator = Emulator::new(crate::hack_assembler::assemble(&assembly).unwrap());
+      let mut highest_stack_pointer = 0;
+      for _ in 0..1000000 {
+        emulator.step();
+        highest_stack_pointer = highest_stack_pointer.max(emulator.ram[0]);
+      }
+      (emulator.ram[16], emulator.ram[17], highest_stack_pointer)
+    };
+    let (result, this, highest_stack_pointer) = run(false);
+    assert_eq!((result, this), (20100, 1234));
+    assert!(highest_stack_pointer > 2000);
+    let (result, this, highest_stack_pointer) = run(true);
+    assert_eq!((result, this), (20100, 1234));
+    assert!(highest_stack_pointer < 300);
+  }
+}
@@ -0,0 +1,339 @@
+use crate::hack_emulator::RAM_SIZE;
+use crate::memory_layout::MemoryLayout;
+use crate::vm_emitter::static_addresses;
+use crate::vm_parser::*;
+use lip::Located;
+use std::collections::HashMap;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+
+// A function running on the machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+  pub function: String,
+  pub args: usize,
+  // Set once its `function` command runs
+  pub local_vars: usize,
+  // The `call` command, or None for the bootstrap's call to `Sys.init`
+  pub call_site: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+  Running,
+  // Ran past the last command, returned from `Sys.init` or reached a `goto` to
+  // the label right before it like `label END` `goto END`
+  Halted,
+}
+
+// Runs VM commands directly with the memory of the Hack computer, so the
+// segments, frames and statics sit where the Hack code would put them. Return
+// addresses on the stack are command indices.
+pub struct Machine {
+  pub files: Vec<(String, Vec<Located<Instruction>>)>,
+  // (file, instruction) of every command in program order
+  pub commands: Vec<(usize, usize)>,
+  // The command to run next
+  pub pc: usize,
+  pub ram: Vec<i16>,
+  // The outermost function first. Empty for commands outside of functions.
+  pub frames: Vec<Frame>,
+  pub steps: usize,
+  labels: HashMap<(usize, String), usize>,
+  functions: HashMap<String, usize>,
+  statics: HashMap<(String, usize), usize>,
+}
+
+impl Machine {
+  // Starts like the bootstrap code with SP = 256 and a call to `Sys.init` when
+  // the program has one, otherwise at the first command
+  pub fn new(files: Vec<(String, Vec<Located<Instruction>>)>) -> Self {
+    let plain_files = files_without_locations(&files);
+    let commands = files.iter().enumerate().flat_map(|(file, (_, instructions))|
+      instructions.iter().enumerate()
+        .filter(|(_, instruction)| !matches!(instruction.value, Instruction::Ignored))
+        .map(move |(instruction, _)| (file, instruction))
+    ).collect::<Vec<(usize, usize)>>();
+    let mut labels = HashMap::new();
+    let mut functions = HashMap::new();
+    for (pc, (file, instruction)) in commands.iter().enumerate() {
+      match &files[*file].1[*instruction].value {
+        Instruction::Label(label) => {
+          labels.insert((*file, label.clone()), pc);
+        }
+        Instruction::Function { name, .. } => {
+          functions.insert(name.clone(), pc);
+        }
+        _ => {}
+      }
+    }
+    let mut machine = Machine {
+      files,
+      commands,
+      pc: 0,
+      ram: vec![0; RAM_SIZE],
+      frames: vec![],
+      steps: 0,
+      labels,
+      functions,
+      statics: static_addresses(&plain_files),
+    };
+    machine.ram[SP] = MemoryLayout::default().stack_base as i16;
+    if let Some(sys_init) = machine.functions.get("Sys.init").copied() {
+      machine.enter("Sys.init", 0, machine.commands.len() as i16);
+      machine.pc = sys_init;
+    }
+    machine
+  }
+
+  pub fn instruction(&self, pc: usize) -> &Located<Instruction> {
+    let (file, instruction) = self.commands[pc];
+    &self.files[file].1[instruction]
+  }
+
+  // Where a command comes from, like `Main.vm:3`
+  pub fn location(&self, pc: usize) -> String {
+    let (file, _) = self.commands[pc];
+    format!("{}.vm:{}", self.files[file].0, self.instruction(pc).from.row)
+  }
+
+  // The command to run next, or None once the program is past its end
+  pub fn current(&self) -> Option<&Located<Instruction>> {
+    if self.pc < self.commands.len() { Some(self.instruction(self.pc)) } else { None }
+  }
+
+  // The index of the `function` command of a function
+  pub fn function_start(&self, name: &str) -> Option<usize> {
+    self.functions.get(name).copied()
+  }
+
+  // The `function` command of function `name`, or else every `label` command
+  // called `name` in any file
+  pub fn commands_named(&self, name: &str) -> Vec<usize> {
+    if let Some(pc) = self.function_start(name) {
+      return vec![pc];
+    }
+    let mut pcs = self.labels.iter().filter(|((_, label), _)| label == name).map(|(_, pc)| *pc).collect::<Vec<usize>>();
+    pcs.sort_unstable();
+    pcs
+  }
+
+  // The RAM address of static `offset` of a file, if the program uses it
+  pub fn static_address(&self, program_name: &str, offset: usize) -> Option<usize> {
+    self.statics.get(&(program_name.to_string(), offset)).copied()
+  }
+
+  // RAM[pointer] as an address
+  pub fn pointer(&self, pointer: usize) -> usize {
+    self.ram[pointer] as u16 as usize & (RAM_SIZE - 1)
+  }
+
+  // The values the current function pushed, bottom first
+  pub fn working_stack(&self) -> &[i16] {
+    let base = match self.frames.last() {
+      Some(frame) => self.pointer(LCL) + frame.local_vars,
+      None => MemoryLayout::default().stack_base,
+    };
+    self.stack_from(base)
+  }
+
+  // The whole stack with the frames of every call, bottom first
+  pub fn stack(&self) -> &[i16] {
+    self.stack_from(MemoryLayout::default().stack_base)
+  }
+
+  fn stack_from(&self, base: usize) -> &[i16] {
+    let top = self.pointer(SP);
+    if base <= top { &self.ram[base..top] } else { &[] }
+  }
+
+  // Runs the next command
+  pub fn step(&mut self) -> Result<State, String> {
+    let (file, instruction) = match self.commands.get(self.pc) {
+      Some(command) => *command,
+      None => return Ok(State::Halted),
+    };
+    let instruction = self.files[file].1[instruction].value.clone();
+    self.steps += 1;
+    let mut next = self.pc + 1;
+    match &instruction {
+      Instruction::Push { segment, offset } => {
+        let value = match segment {
+          Segment::Constant => *offset as i16,
+          _ => self.ram[self.segment_address(file, segment, *offset)],
+        };
+        self.push(value);
+      }
+      Instruction::Pop { segment, offset } => {
+        let value = self.pop();
+        let address = self.segment_address(file, segment, *offset);
+        self.ram[address] = value;
+      }
+      Instruction::Arithmetic(arith_instruction) => {
+        let y = self.pop();
+        let value = match arith_instruction {
+          ArithInstruction::Neg => y.wrapping_neg(),
+          ArithInstruction::Not => !y,
+          _ => {
+            let x = self.pop();
+            match arith_instruction {
+              ArithInstruction::Add => x.wrapping_add(y),
+              ArithInstruction::Sub => x.wrapping_sub(y),
+              ArithInstruction::And => x & y,
+              ArithInstruction::Or => x | y,
+              ArithInstruction::Eq => -((x == y) as i16),
+              ArithInstruction::Gt => -((x > y) as i16),
+              ArithInstruction::Lt => -((x < y) as i16),
+              ArithInstruction::Neg | ArithInstruction::Not => unreachable!(),
+            }
+          }
+        };
+        self.push(value);
+      }
+      Instruction::Goto(label) => {
+        next = self.label(file, label)?;
+        if next + 1 == self.pc {
+          return Ok(State::Halted);
+        }
+      }
+      Instruction::IfGoto(label) =>
+        if self.pop() != 0 {
+          next = self.label(file, label)?;
+        },
+      Instruction::Function { local_vars, .. } => {
+        for _ in 0..*local_vars {
+          self.push(0);
+        }
+        if let Some(frame) = self.frames.last_mut() {
+          frame.local_vars = *local_vars;
+        }
+      }
+      Instruction::Call { name, args } => {
+        next = self.function_start(name).ok_or_else(|| format!(
+          "I can't find function {} called at {}.\nTry adding the file that defines it.", name, self.location(self.pc)
+        ))?;
+        let call_site = self.pc;
+        self.enter(name, *args, (call_site + 1) as i16);
+        self.frames.last_mut().unwrap().call_site = Some(call_site);
+      }
+      Instruction::Return => {
+        let frame = self.pointer(LCL);
+        let return_address = self.ram[(frame + RAM_SIZE - 5) % RAM_SIZE] as u16 as usize;
+        let value = self.pop();
+        let argument = self.pointer(ARG);
+        self.ram[argument] = value;
+        self.ram[SP] = argument as i16 + 1;
+        for (offset, pointer) in [THAT, THIS, ARG, LCL].iter().enumerate() {
+          self.ram[*pointer] = self.ram[(frame + RAM_SIZE - 1 - offset) % RAM_SIZE];
+        }
+        self.frames.pop();
+        next = return_address;
+      }
+      Instruction::Label(_) | Instruction::Ignored => {}
+    }
+    self.pc = next;
+    Ok(if self.pc >= self.commands.len() { State::Halted } else { State::Running })
+  }
+
+  // Pushes the frame of a call whose arguments are on the stack
+  fn enter(&mut self, function: &str, args: usize, return_address: i16) {
+    self.push(return_address);
+    for pointer in [LCL, ARG, THIS, THAT].iter() {
+      self.push(self.ram[*pointer]);
+    }
+    self.ram[ARG] = self.ram[SP] - 5 - args as i16;
+    self.ram[LCL] = self.ram[SP];
+    self.frames.push(Frame { function: function.to_string(), args, local_vars: 0, call_site: None });
+  }
+
+  fn segment_address(&self, file: usize, segment: &Segment, offset: usize) -> usize {
+    let address = match segment {
+      Segment::Local => self.pointer(LCL) + offset,
+      Segment::Argument => self.pointer(ARG) + offset,
+      Segment::This => self.pointer(THIS) + offset,
+      Segment::That => self.pointer(THAT) + offset,
+      Segment::Pointer => THIS + offset,
+      Segment::Temp => MemoryLayout::default().temp_base + offset,
+      Segment::Static => self.statics[&(self.files[file].0.clone(), offset)],
+      Segment::Constant => unreachable!("constants aren't in memory"),
+    };
+    address & (RAM_SIZE - 1)
+  }
+
+  // Jumps can also go to a function, like they do in the Hack output
+  fn label(&self, file: usize, label: &str) -> Result<usize, String> {
+    self.labels.get(&(file, label.to_string())).or_else(|| self.functions.get(label)).copied()
+      .ok_or_else(|| format!("I can't find label {} used at {}.", label, self.location(self.pc)))
+  }
+
+  fn push(&mut self, value: i16) {
+    let top = self.pointer(SP);
+    self.ram[top] = value;
+    self.ram[SP] = self.ram[SP].wrapping_add(1);
+  }
+
+  fn pop(&mut self) -> i16 {
+    self.ram[SP] = self.ram[SP].wrapping_sub(1);
+    self.ram[self.pointer(SP)]
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::vm_interpreter::*;
+  use crate::test_support::located_program;
+
+  #[test]
+  fn test_step() {
+    let sources = [
+      ("Sys", "function Sys.init 0\npush constant 10\ncall Main.fib 1\npop static 0\nlabel HALT\ngoto HALT\n"),
+      ("Main",
+"function Main.fib 1
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push constant 1
+sub
+call Main.fib 1
+pop local 0
+push argument 0
+push constant 2
+sub
+call Main.fib 1
+push local 0
+add
+return
+label BASE
+push argument 0
+return
+"),
+    ];
+    let mut machine = Machine::new(located_program(&sources));
+    assert_eq!(machine.frames.iter().map(|frame| frame.function.as_str()).collect::<Vec<&str>>(), vec!["Sys.init"]);
+    let mut state = State::Running;
+    while state == State::Running {
+      state = machine.step().unwrap();
+    }
+    assert_eq!(machine.ram[16], 55);
+    assert_eq!(machine.location(machine.pc), "Sys.vm:6");
+    // The stack is back to the frame of Sys.init
+    assert_eq!(machine.pointer(SP), 256 + 5);
+    assert!(machine.working_stack().is_empty());
+
+    // Jumping to a function runs its `function` command like a label
+    let source = "function Sys.init 0\ngoto Sys.next\nfunction Sys.next 1\npush constant 7\npop static 0\nlabel HALT\ngoto HALT\n";
+    let mut machine = Machine::new(located_program(&[("Sys", source)]));
+    let mut state = State::Running;
+    while state == State::Running {
+      state = machine.step().unwrap();
+    }
+    assert_eq!(machine.ram[16], 7);
+    assert_eq!(machine.pointer(SP), 256 + 5 + 1);
+  }
+}
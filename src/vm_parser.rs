@@ -35,6 +35,10 @@ pub enum Instruction {
     name: String,
     local_vars: usize,
   },
+  Call {
+    name: String,
+    args: usize,
+  },
   Return,
   Ignored,
 }
@@ -52,7 +56,7 @@ pub enum ArithInstruction {
   Not,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Segment {
   Local,
   Argument,
@@ -64,9 +68,64 @@ pub enum Segment {
   Pointer,
 }
 
+// Prints the instruction back in VM syntax like `push local 2`
+impl std::fmt::Display for Instruction {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Instruction::Arithmetic(arith_instruction) =>
+        write!(f, "{}", arith_instruction),
+      Instruction::Push { segment, offset } =>
+        write!(f, "push {} {}", segment, offset),
+      Instruction::Pop { segment, offset } =>
+        write!(f, "pop {} {}", segment, offset),
+      Instruction::Label(label) =>
+        write!(f, "label {}", label),
+      Instruction::Goto(label) =>
+        write!(f, "goto {}", label),
+      Instruction::IfGoto(label) =>
+        write!(f, "if-goto {}", label),
+      Instruction::Function { name, local_vars } =>
+        write!(f, "function {} {}", name, local_vars),
+      Instruction::Call { name, args } =>
+        write!(f, "call {} {}", name, args),
+      Instruction::Return =>
+        write!(f, "return"),
+      Instruction::Ignored =>
+        Ok(()),
+    }
+  }
+}
+
+impl std::fmt::Display for ArithInstruction {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}", format!("{:?}", self).to_lowercase())
+  }
+}
+
+impl std::fmt::Display for Segment {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{}", format!("{:?}", self).to_lowercase())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+  pub message: String,
+  pub severity: Severity,
+  pub from: Location,
+  pub to: Location,
+}
+
 #[derive(Clone, Debug)]
 pub struct State {
   defined_labels: HashSet<VMLocatedString>,
+  defined_functions: HashSet<VMLocatedString>,
   used_labels: HashSet<VMLocatedString>,
 }
 
@@ -78,14 +137,45 @@ lazy_static! {
 // push constant 10
 // pop local 0
 // add
-pub fn parse<'a>(source: &'a str) -> Result<Vec<Instruction>, String> {
+pub fn parse(source: &str) -> Result<Vec<Instruction>, String> {
+  parse_located(source)
+    .map(|instructions| instructions.into_iter().map(|instruction| instruction.value).collect())
+    .map_err(|diagnostics| display_diagnostics(source, &diagnostics))
+}
+
+// Same as `parse` but remembers where each instruction came from
+// and keeps the diagnostics separate for tools like the language server.
+pub fn parse_located(source: &str) -> Result<Vec<Located<Instruction>>, Vec<Diagnostic>> {
+  let (instructions, diagnostics) = parse_with_diagnostics(source);
+  if diagnostics.is_empty() {
+    Ok(instructions)
+  } else {
+    Err(diagnostics)
+  }
+}
+
+// The instructions of `parse_located` without their locations, for the
+// analyses and backends that work on plain instructions
+pub fn without_locations(instructions: &[Located<Instruction>]) -> Vec<Instruction> {
+  instructions.iter().map(|instruction| instruction.value.clone()).collect()
+}
+
+// Same as `without_locations` for every file of a program
+pub fn files_without_locations(files: &[(String, Vec<Located<Instruction>>)]) -> Vec<(String, Vec<Instruction>)> {
+  files.iter().map(|(program_name, instructions)| (program_name.clone(), without_locations(instructions))).collect()
+}
+
+// Label problems don't stop the parse, so the instructions are still returned
+// alongside the diagnostics. A syntax error returns no instructions.
+pub fn parse_with_diagnostics(source: &str) -> (Vec<Located<Instruction>>, Vec<Diagnostic>) {
   let initial_state = State {
     defined_labels: HashSet::new(),
+    defined_functions: HashSet::new(),
     used_labels: HashSet::new(),
   };
   let output = one_or_more(
     left(
-      one_of!(
+      located(one_of!(
         push_instruction(),
         pop_instruction(),
         arith_instruction(),
@@ -93,51 +183,61 @@ pub fn parse<'a>(source: &'a str) -> Result<Vec<Instruction>, String> {
         goto_instruction(),
         if_goto_instruction(),
         function_declaration(),
+        call_instruction(),
         return_statement(),
         comment_or_spaces()
-      ),
+      )),
       newline_with_comment("//")
     )
   ).end().parse(source, Location { row: 1, col: 1 }, initial_state)
   .map(| instructions |
-    instructions.into_iter().filter(|instruction| match instruction { Instruction::Ignored => false, _ => true } ).collect()
+    instructions.into_iter().filter(|instruction| !matches!(instruction.value, Instruction::Ignored)).collect()
   );
   match output {
-    ParseResult::Ok { output, state, .. } => {
-      let defined_labels = state.defined_labels;
-      let defined_label_names = defined_labels.clone().into_iter().map(|located_label| located_label.value).collect::<HashSet<String>>();
-      let used_labels = state.used_labels;
-      let used_label_names = used_labels.clone().into_iter().map(|located_label| located_label.value).collect::<HashSet<String>>();
-      let label_name_difference = defined_label_names.difference(used_label_names);
-      let label_difference = used_labels.into_iter().filter(|located_label|
-        label_name_difference.contains(&located_label.value)
-        ).collect::<HashSet<VMLocatedString>>();
-      if label_difference.is_empty() {
-        Ok(output)
-      } else {
-        Err(
-          label_difference.iter().sorted_by_key(|located_label| located_label.from.row)
-          .map(|located_label|
-            display_error(source, 
-            format!(
-              "I found an undefined label named {}. Try removing it or define it somewhere.",
-              located_label.value
-            ),
-            to_location(located_label.from.clone()), to_location(located_label.to.clone())
-            )
-          ).collect::<Vec<String>>().join("\n\n")
-        )
-      }
-    },
+    ParseResult::Ok { output, state, .. } =>
+      (output, check_labels(state)),
     ParseResult::Err {
-      message: error_message,
+      message,
       from,
       to,
       ..
-    } => Err(display_error(source, error_message, from, to)),
+    } => (vec![], vec![Diagnostic { message, severity: Severity::Error, from, to }]),
   }
 }
 
+pub fn display_diagnostics(source: &str, diagnostics: &[Diagnostic]) -> String {
+  diagnostics.iter().map(|diagnostic|
+    display_error(source, diagnostic.message.clone(), diagnostic.from, diagnostic.to)
+  ).collect::<Vec<String>>().join("\n\n")
+}
+
+fn check_labels(state: State) -> Vec<Diagnostic> {
+  let defined_label_names = state.defined_labels.iter().map(|located_label| located_label.value.clone())
+    .chain(state.defined_functions.iter().map(|located_function| located_function.value.clone()))
+    .collect::<HashSet<String>>();
+  let used_label_names = state.used_labels.iter().map(|located_label| located_label.value.clone()).collect::<HashSet<String>>();
+  let unused_labels = state.defined_labels.iter().filter(|located_label|
+    !used_label_names.contains(&located_label.value)
+  ).map(|located_label| (located_label,
+    format!("I found an unused label named {}. Try removing it or use it somewhere.", located_label.value),
+    Severity::Warning,
+  ));
+  let undefined_labels = state.used_labels.iter().filter(|located_label|
+    !defined_label_names.contains(&located_label.value)
+  ).map(|located_label| (located_label,
+    format!("I found an undefined label named {}. Try removing it or define it somewhere.", located_label.value),
+    Severity::Error,
+  ));
+  unused_labels.chain(undefined_labels)
+    .sorted_by_key(|(located_label, _, _)| (located_label.from.row, located_label.from.col))
+    .map(|(located_label, message, severity)| Diagnostic {
+      message,
+      severity,
+      from: to_location(located_label.from.clone()),
+      to: to_location(located_label.to.clone()),
+    }).collect()
+}
+
 fn push_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
   chain!(
     token("push"),
@@ -145,10 +245,9 @@ fn push_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
     segment_label(),
     space1(),
     int()
-  ).map(|output| match output {
-    (_, (_, (segment, (_, offset)))) =>
-      Instruction::Push { segment, offset }
-  })
+  ).map(|(_, (_, (segment, (_, offset))))|
+    Instruction::Push { segment, offset }
+  )
 }
 
 fn pop_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
@@ -158,48 +257,45 @@ fn pop_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
     segment_label(),
     space1(),
     int()
-  ).and_then(|output|
+  ).and_then(|(_, (_, (segment, (_, offset))))|
     move |input, location, state|
-      match output.clone() {
-      (_, (_, (segment, (_, offset)))) =>
-        match segment {
-          Segment::Constant =>
+      match segment {
+        Segment::Constant =>
+          ParseResult::Err {
+            message: format!("You can't store a popped value into a constant.\nTry pushing a constant onto the stack using `push constant {}` or consider push/pop other memory segments like `local` and `argument`.", offset),
+            from: Location {
+              col: 1,
+              ..location
+            },
+            to: location,
+            state,
+          },
+        Segment::Pointer =>
+          if offset > 1 {
             ParseResult::Err {
-              message: format!("You can't store a popped value into a constant.\nTry pushing a constant onto the stack using `push constant {}` or consider push/pop other memory segments like `local` and `argument`.", offset),
+              message: format!("I found that {} is outside the allowed range of pointers.\nYou can only push/pop pointer 0 or 1. Pointer 0 refers to `this` and pointer 1 refers to `that`.", offset),
               from: Location {
                 col: 1,
                 ..location
               },
               to: location,
               state,
-            },
-          Segment::Pointer =>
-            if offset > 1 {
-              ParseResult::Err {
-                message: format!("I found that {} is outside the allowed range of pointers.\nYou can only push/pop pointer 0 or 1. Pointer 0 refers to `this` and pointer 1 refers to `that`.", offset),
-                from: Location {
-                  col: 1,
-                  ..location
-                },
-                to: location,
-                state,
-              }
-            } else {
-              ParseResult::Ok {
-                input,
-                output: Instruction::Pop { segment, offset },
-                location,
-                state,
-              }
             }
-          _ =>
+          } else {
             ParseResult::Ok {
               input,
               output: Instruction::Pop { segment, offset },
               location,
               state,
             }
-        }
+          }
+        _ =>
+          ParseResult::Ok {
+            input,
+            output: Instruction::Pop { segment, offset },
+            location,
+            state,
+          }
       }
   )
 }
@@ -215,7 +311,7 @@ fn arith_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
     token("and").map(|_| ArithInstruction::And),
     token("or").map(|_| ArithInstruction::Or),
     token("not").map(|_| ArithInstruction::Not)
-  ).map(|output| Instruction::Arithmetic(output))
+  ).map(Instruction::Arithmetic)
 }
 
 // label LOOP_START
@@ -224,31 +320,29 @@ fn label_declaration<'a>() -> BoxedParser<'a, Instruction, State> {
     token("label"),
     space1(),
     located(label())
-  ).update(|input, output, location, state| match output {
-    (_, (_, label)) =>
-      if state.defined_labels.iter().map(|located_label| located_label.value.clone())
-        .collect::<HashSet<String>>().contains(&label.value) {
-        ParseResult::Err {
-          message: format!("I found a duplicated label name `{}`. Try renaming it.", &label.value),
-          from: Location {
-            col: location.col - label.value.len(),
-            ..location
-          },
-          to: location,
-          state,
-        }
-      } else {
-        ParseResult::Ok {
-          input,
-          output: Instruction::Label(label.value.clone()),
-          location,
-          state: State {
-            defined_labels: state.defined_labels.update(to_vmlocated_string(label)),
-            ..state
-          }
+  ).update(|input, (_, (_, label)), location, state|
+    if is_defined(&state, &label.value) {
+      ParseResult::Err {
+        message: format!("I found a duplicated label name `{}`. Try renaming it.", &label.value),
+        from: Location {
+          col: location.col - label.value.len(),
+          ..location
+        },
+        to: location,
+        state,
+      }
+    } else {
+      ParseResult::Ok {
+        input,
+        output: Instruction::Label(label.value.clone()),
+        location,
+        state: State {
+          defined_labels: state.defined_labels.update(to_vmlocated_string(label)),
+          ..state
         }
       }
-  })
+    }
+  )
 }
 
 fn label<'a>() -> impl Parser<'a, String, State> {
@@ -266,18 +360,17 @@ fn goto_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
     token("goto"),
     space1(),
     located(label())
-  ).update(|input, output, location, state| match output {
-    (_, (_, located_label)) =>
-      ParseResult::Ok {
-        input,
-        output: Instruction::Goto(located_label.value.clone()),
-        location,
-        state: State {
-          used_labels: state.used_labels.update(to_vmlocated_string(located_label)),
-          ..state
-        }
+  ).update(|input, (_, (_, located_label)), location, state|
+    ParseResult::Ok {
+      input,
+      output: Instruction::Goto(located_label.value.clone()),
+      location,
+      state: State {
+        used_labels: state.used_labels.update(to_vmlocated_string(located_label)),
+        ..state
       }
-  })
+    }
+  )
 }
 
 fn if_goto_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
@@ -285,55 +378,70 @@ fn if_goto_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
     token("if-goto"),
     space1(),
     located(label())
-  ).update(|input, output, location, state| match output {
-    (_, (_, located_label)) =>
+  ).update(|input, (_, (_, located_label)), location, state|
+    ParseResult::Ok {
+      input,
+      output: Instruction::IfGoto(located_label.value.clone()),
+      location,
+      state: State {
+        used_labels: state.used_labels.update(to_vmlocated_string(located_label)),
+        ..state
+      }
+    }
+  )
+}
+
+fn function_declaration<'a>() -> BoxedParser<'a, Instruction, State> {
+  chain!(
+    token("function"),
+    space1(),
+    located(label()),
+    space1(),
+    int()
+  ).update(|input, (_, (_, (label, (_, local_vars)))), location, state|
+    if is_defined(&state, &label.value) {
+      ParseResult::Err {
+        message: format!("I found a duplicated label name `{}`. Try renaming it.", &label.value),
+        from: Location {
+          col: location.col - label.value.len(),
+          ..location
+        },
+        to: location,
+        state,
+      }
+    } else {
       ParseResult::Ok {
         input,
-        output: Instruction::IfGoto(located_label.value.clone()),
+        output: Instruction::Function {
+          name: label.value.clone(),
+          local_vars,
+        },
         location,
         state: State {
-          used_labels: state.used_labels.update(to_vmlocated_string(located_label)),
+          defined_functions: state.defined_functions.update(to_vmlocated_string(label)),
           ..state
         }
       }
-  })
+    }
+  )
 }
 
-fn function_declaration<'a>() -> BoxedParser<'a, Instruction, State> {
+// call Main.fibonacci 1
+fn call_instruction<'a>() -> BoxedParser<'a, Instruction, State> {
   chain!(
-    token("function"),
+    token("call"),
     space1(),
-    located(label()),
+    label(),
     space1(),
     int()
-  ).update(|input, output, location, state| match output {
-    (_, (_, (label, (_, local_vars)))) =>
-      if state.defined_labels.iter().map(|located_label| located_label.value.clone())
-        .collect::<HashSet<String>>().contains(&label.value) {
-        ParseResult::Err {
-          message: format!("I found a duplicated label name `{}`. Try renaming it.", &label.value),
-          from: Location {
-            col: location.col - label.value.len(),
-            ..location
-          },
-          to: location,
-          state,
-        }
-      } else {
-        ParseResult::Ok {
-          input,
-          output: Instruction::Function {
-            name: label.value.clone(),
-            local_vars,
-          },
-          location,
-          state: State {
-            defined_labels: state.defined_labels.update(to_vmlocated_string(label)),
-            ..state
-          }
-        }
-      }
-  })
+  ).map(|(_, (_, (name, (_, args))))|
+    Instruction::Call { name, args }
+  )
+}
+
+fn is_defined(state: &State, name: &str) -> bool {
+  state.defined_labels.iter().chain(state.defined_functions.iter())
+    .any(|located_label| located_label.value == name)
 }
 
 fn return_statement<'a>() -> BoxedParser<'a, Instruction, State> {
@@ -0,0 +1,343 @@
+use crate::hack_assembler::{assemble_computation, PREDEFINED_SYMBOLS, VARIABLE_BASE};
+use crate::memory_layout::MemoryLayout;
+use crate::vm_emitter;
+use crate::vm_parser::*;
+use std::collections::HashMap;
+
+// Placeholders passed to the emitter to build the templates. Numbers from
+// `NUMBER_PLACEHOLDER` up stand for a captured number plus the difference,
+// like the `@{5 + args}` in a call, and operands containing
+// `NAME_PLACEHOLDER` stand for captured labels and names.
+const NUMBER_PLACEHOLDER: usize = 30000;
+const NAME_PLACEHOLDER: &str = "RECOGNIZER";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recognized {
+  Instruction(Instruction),
+  // Assembly that doesn't come from any emitter template
+  Assembly(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+  Address(String),
+  Label(String),
+  // The machine code of a computation, so different spellings of the same computation match
+  Computation(u16),
+}
+
+// Builds the instruction from the captured operands
+type Build = Box<dyn Fn(&HashMap<String, String>) -> Option<Instruction>>;
+
+struct Template {
+  lines: Vec<Line>,
+  build: Build,
+}
+
+// Reconstructs the VM commands from assembly produced by `vm_emitter`,
+// either as written by the emitter or as disassembled from `.hack` machine code
+// where labels and variables became plain addresses.
+pub fn recognize(assembly: &str) -> Vec<Recognized> {
+  let lines = assembly.split('\n').filter_map(|line| {
+    let line = match line.find("//") {
+      Some(comment_start) => &line[..comment_start],
+      None => line,
+    }.trim();
+    if line.is_empty() {
+      None
+    } else {
+      Some((line.to_string(), parse_line(line)))
+    }
+  }).collect::<Vec<(String, Option<Line>)>>();
+  let templates = templates();
+  let mut recognized = Vec::new();
+  let mut index = 0;
+  while index < lines.len() {
+    let matched = match_function(&lines[index..]).or_else(||
+      templates.iter().find_map(|template|
+        match_template(&template.lines, &lines[index..]).and_then(|(captures, length)|
+          (template.build)(&captures).map(|instruction| (instruction, length))
+        )
+      )
+    );
+    match matched {
+      Some((instruction, length)) => {
+        recognized.push(Recognized::Instruction(instruction));
+        index += length;
+      }
+      None => {
+        recognized.push(Recognized::Assembly(lines[index].0.clone()));
+        index += 1;
+      }
+    }
+  }
+  recognized
+}
+
+// Prints the recognized commands as a VM program, keeping unrecognized assembly as comments
+pub fn to_vm_source(recognized: &[Recognized]) -> String {
+  recognized.iter().map(|item| match item {
+    Recognized::Instruction(instruction) => format!("{}\n", instruction),
+    Recognized::Assembly(line) => format!("// asm: {}\n", line),
+  }).collect()
+}
+
+fn parse_line(line: &str) -> Option<Line> {
+  if let Some(operand) = line.strip_prefix('@') {
+    let operand = PREDEFINED_SYMBOLS.iter()
+      .find(|(name, _)| *name == operand)
+      .map_or(operand.to_string(), |(_, address)| address.to_string());
+    Some(Line::Address(operand))
+  } else if let Some(label) = line.strip_prefix('(') {
+    label.strip_suffix(')').map(|label| Line::Label(label.to_string()))
+  } else {
+    assemble_computation(line, 1, line).ok().map(Line::Computation)
+  }
+}
+
+fn is_placeholder(operand: &str) -> bool {
+  operand.contains(NAME_PLACEHOLDER)
+    || operand.contains(&NUMBER_PLACEHOLDER.to_string())
+    || operand.parse::<usize>().is_ok_and(|number| number >= NUMBER_PLACEHOLDER)
+}
+
+// Returns the captured operands and the number of lines matched.
+// Labels in the template are optional because disassembled code only has
+// labels where they are jumped to.
+fn match_template(template: &[Line], lines: &[(String, Option<Line>)]) -> Option<(HashMap<String, String>, usize)> {
+  let mut captures: HashMap<String, String> = HashMap::new();
+  let mut capture = |placeholder: &str, operand: &str| -> bool {
+    if placeholder.parse::<usize>().is_ok() && operand.parse::<usize>().is_err() {
+      return false;
+    }
+    captures.entry(placeholder.to_string()).or_insert_with(|| operand.to_string()) == operand
+  };
+  let mut index = 0;
+  for line in template.iter() {
+    let next = lines.get(index).and_then(|(_, line)| line.as_ref());
+    match (line, next) {
+      (Line::Label(placeholder), Some(Line::Label(label))) if is_placeholder(placeholder) => {
+        if !capture(placeholder, label) {
+          return None;
+        }
+        index += 1;
+      }
+      (Line::Label(_), _) =>
+        {}
+      (Line::Address(placeholder), Some(Line::Address(operand))) if is_placeholder(placeholder) => {
+        if !capture(placeholder, operand) {
+          return None;
+        }
+        index += 1;
+      }
+      (expected, Some(actual)) if expected == actual =>
+        index += 1,
+      _ =>
+        return None,
+    }
+  }
+  if index == 0 {
+    None
+  } else {
+    Some((captures, index))
+  }
+}
+
+// (function_label)
+// @SP
+// M=M+1 for each local variable
+fn match_function(lines: &[(String, Option<Line>)]) -> Option<(Instruction, usize)> {
+  let name = match lines.first() {
+    Some((_, Some(Line::Label(name)))) => name.clone(),
+    _ => return None,
+  };
+  if lines.get(1).and_then(|(_, line)| line.as_ref()) != Some(&Line::Address("0".to_string())) {
+    return None;
+  }
+  let increment = parse_line("M=M+1");
+  let local_vars = lines[2..].iter().take_while(|(_, line)| *line == increment).count();
+  // `@SP` right after a label is only a function when locals are allocated
+  // or the next line starts a new command
+  let next = lines.get(2 + local_vars).and_then(|(_, line)| line.as_ref());
+  if local_vars == 0 && matches!(next, Some(Line::Computation(_))) {
+    return None;
+  }
+  Some((Instruction::Function { name, local_vars }, 2 + local_vars))
+}
+
+// The templates come straight from the emitter so they can't drift apart.
+// Longer templates are tried first and templates with fewer placeholders
+// win ties so `push pointer 0` isn't mistaken for `push static 3`.
+fn templates() -> Vec<Template> {
+  let number = NUMBER_PLACEHOLDER;
+  let name = format!("{}_NAME", NAME_PLACEHOLDER);
+  let mut instructions: Vec<(Instruction, Build)> = Vec::new();
+  for arith_instruction in [
+    ArithInstruction::Add, ArithInstruction::Sub, ArithInstruction::Neg,
+    ArithInstruction::Eq, ArithInstruction::Gt, ArithInstruction::Lt,
+    ArithInstruction::And, ArithInstruction::Or, ArithInstruction::Not,
+  ].iter() {
+    let instruction = Instruction::Arithmetic(arith_instruction.clone());
+    instructions.push((instruction.clone(), Box::new(move |_| Some(instruction.clone()))));
+  }
+  for segment in [Segment::Local, Segment::Argument, Segment::This, Segment::That, Segment::Constant].iter().copied() {
+    instructions.push((
+      Instruction::Push { segment, offset: number },
+      Box::new(move |captures| Some(Instruction::Push { segment, offset: captured_number(captures, 0)? })),
+    ));
+    if segment != Segment::Constant {
+      instructions.push((
+        Instruction::Pop { segment, offset: number },
+        Box::new(move |captures| Some(Instruction::Pop { segment, offset: captured_number(captures, 0)? })),
+      ));
+    }
+  }
+  // Small offsets have their own templates
+  for segment in [Segment::Local, Segment::Argument, Segment::This, Segment::That].iter().copied() {
+    for offset in 0..=vm_emitter::POP_STEP_LIMIT {
+      if offset <= vm_emitter::PUSH_STEP_LIMIT {
+        instructions.push((Instruction::Push { segment, offset }, Box::new(move |_| Some(Instruction::Push { segment, offset }))));
+      }
+      instructions.push((Instruction::Pop { segment, offset }, Box::new(move |_| Some(Instruction::Pop { segment, offset }))));
+    }
+  }
+  // Temp entries are plain addresses like statics after assembly, so only the temp range counts
+  let MemoryLayout { temp_base, temp_size, .. } = MemoryLayout::default();
+  let temp_offset = move |captures: &HashMap<String, String>| captured_number(captures, temp_base).filter(|offset| *offset < temp_size);
+  instructions.push((
+    Instruction::Push { segment: Segment::Temp, offset: number },
+    Box::new(move |captures| Some(Instruction::Push { segment: Segment::Temp, offset: temp_offset(captures)? })),
+  ));
+  instructions.push((
+    Instruction::Pop { segment: Segment::Temp, offset: number },
+    Box::new(move |captures| Some(Instruction::Pop { segment: Segment::Temp, offset: temp_offset(captures)? })),
+  ));
+  for offset in 0..2 {
+    instructions.push((
+      Instruction::Push { segment: Segment::Pointer, offset },
+      Box::new(move |_| Some(Instruction::Push { segment: Segment::Pointer, offset })),
+    ));
+    instructions.push((
+      Instruction::Pop { segment: Segment::Pointer, offset },
+      Box::new(move |_| Some(Instruction::Pop { segment: Segment::Pointer, offset })),
+    ));
+  }
+  let static_operand = format!("{}.{}", NAME_PLACEHOLDER, number);
+  let static_key = static_operand.clone();
+  instructions.push((
+    Instruction::Push { segment: Segment::Static, offset: number },
+    Box::new(move |captures| Some(Instruction::Push { segment: Segment::Static, offset: static_offset(captures.get(&static_key)?)? })),
+  ));
+  let static_key = static_operand;
+  instructions.push((
+    Instruction::Pop { segment: Segment::Static, offset: number },
+    Box::new(move |captures| Some(Instruction::Pop { segment: Segment::Static, offset: static_offset(captures.get(&static_key)?)? })),
+  ));
+  let label_key = name.clone();
+  instructions.push((Instruction::Label(name.clone()), Box::new(move |captures| Some(Instruction::Label(captures.get(&label_key)?.clone())))));
+  let label_key = name.clone();
+  instructions.push((Instruction::Goto(name.clone()), Box::new(move |captures| Some(Instruction::Goto(captures.get(&label_key)?.clone())))));
+  let label_key = name.clone();
+  instructions.push((Instruction::IfGoto(name.clone()), Box::new(move |captures| Some(Instruction::IfGoto(captures.get(&label_key)?.clone())))));
+  let name_key = name.clone();
+  instructions.push((
+    Instruction::Call { name, args: number },
+    Box::new(move |captures| Some(Instruction::Call { name: captures.get(&name_key)?.clone(), args: captured_number(captures, 5)? })),
+  ));
+  instructions.push((Instruction::Return, Box::new(|_| Some(Instruction::Return))));
+
+  let mut templates = instructions.into_iter().map(|(instruction, build)| {
+    let assembly = vm_emitter::emit_instruction(NAME_PLACEHOLDER, number, &instruction);
+    Template {
+      lines: assembly.split('\n').filter(|line| !line.is_empty()).filter_map(parse_line).collect(),
+      build,
+    }
+  }).collect::<Vec<Template>>();
+  let placeholders = |template: &Template| template.lines.iter().filter(|line| match line {
+    Line::Address(operand) | Line::Label(operand) => is_placeholder(operand),
+    Line::Computation(_) => false,
+  }).count();
+  templates.sort_by_key(|template| (std::cmp::Reverse(template.lines.len()), placeholders(template)));
+  templates
+}
+
+// `difference` is what the emitter added to the captured number
+fn captured_number(captures: &HashMap<String, String>, difference: usize) -> Option<usize> {
+  captures.get(&(NUMBER_PLACEHOLDER + difference).to_string())?
+    .parse::<usize>().ok()?
+    .checked_sub(difference)
+}
+
+// Statics are either `File.3` symbols or, after assembly, addresses from RAM[16] on.
+// The assembler allocates addresses in order of first use so the recovered
+// indices are only unique, not the original ones.
+fn static_offset(operand: &str) -> Option<usize> {
+  match operand.parse::<usize>() {
+    Ok(address) => address.checked_sub(VARIABLE_BASE as usize),
+    Err(_) => operand.rsplit('.').next()?.parse::<usize>().ok(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::vm_recognizer::*;
+  use crate::hack_assembler::assemble;
+  use crate::hack_disassembler::disassemble;
+
+  #[test]
+  fn test_recognize() {
+    let source =
+"function Main.main 2
+push constant 17
+pop static 3
+push static 3
+push local 1
+eq
+if-goto TRUE
+push pointer 1
+pop that 4
+call Main.helper 2
+pop temp 6
+label TRUE
+goto TRUE
+function Main.helper 0
+push argument 1
+not
+return
+";
+    let instructions = parse(source).unwrap();
+    let assembly = vm_emitter::emit("Main", instructions.clone());
+    assert_eq!(
+      recognize(&assembly),
+      instructions.iter().cloned().map(Recognized::Instruction).collect::<Vec<Recognized>>()
+    );
+
+    let machine_code = assemble(&assembly).unwrap().iter()
+      .map(|word| format!("{:016b}", word))
+      .collect::<Vec<String>>()
+      .join("\n");
+    // Main.main is never called so its label, and with it the function, is lost.
+    // Labels and statics come back as addresses.
+    assert_eq!(to_vm_source(&recognize(&disassemble(&machine_code).unwrap())),
+"// asm: @0
+// asm: M=M+1
+// asm: M=M+1
+push constant 17
+pop static 0
+push static 0
+push local 1
+eq
+if-goto L127
+push pointer 1
+pop that 4
+call L129 2
+pop temp 6
+label L127
+goto L127
+function L129 0
+push argument 1
+not
+return
+");
+  }
+}
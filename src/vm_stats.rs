@@ -0,0 +1,192 @@
+use crate::vm_parser::*;
+use crate::backend::Backend;
+use crate::vm_emitter::HackBackend;
+use itertools::Itertools;
+
+// Static variables are allocated by the assembler starting at RAM[16]
+// and have to stay below the stack at RAM[256]
+pub const STATIC_SLOTS: usize = 240;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+  pub total_instructions: usize,
+  // Hack instructions per VM function, largest first
+  pub functions: Vec<(String, usize)>,
+  // (command kind, number of commands, Hack instructions), most expensive first
+  pub commands: Vec<(String, usize, usize)>,
+  // Distinct static indices used by each file
+  pub statics: Vec<(String, Vec<usize>)>,
+}
+
+// Measures the code `backend` emits for each command, so the sizes add up to the
+// program it compiles with its layout and optimizations. Code the optimizations
+// hold back counts for the command it comes out with.
+pub fn collect(files: &[(String, Vec<Instruction>)], mut backend: HackBackend) -> Stats {
+  let mut functions: Vec<(String, usize)> = Vec::new();
+  let mut commands: Vec<(String, usize, usize)> = Vec::new();
+  let mut statics = Vec::new();
+  backend.begin_program(files);
+  let bootstrap = crate::rom_size(&backend.take_output());
+  if bootstrap > 0 {
+    functions.push(("(bootstrap)".to_string(), bootstrap));
+  }
+  let mut current_function = String::new();
+  for (program_name, instructions) in files.iter() {
+    // Code held back at the end of the last file belongs to its last function
+    backend.begin_file(program_name);
+    add_size(&mut functions, &current_function, crate::rom_size(&backend.take_output()));
+    current_function = format!("(top level of {}.vm)", program_name);
+    for instruction in instructions.iter().filter(|instruction| !matches!(instruction, Instruction::Ignored)) {
+      if let Instruction::Function { name, .. } = instruction {
+        current_function = name.clone();
+      }
+      backend.emit_instruction(instruction);
+      let size = crate::rom_size(&backend.take_output());
+      match functions.iter_mut().find(|(name, _)| *name == current_function) {
+        Some((_, function_size)) => *function_size += size,
+        None => functions.push((current_function.clone(), size)),
+      }
+      let kind = command_kind(instruction);
+      match commands.iter_mut().find(|(other_kind, _, _)| *other_kind == kind) {
+        Some((_, count, command_size)) => {
+          *count += 1;
+          *command_size += size;
+        }
+        None => commands.push((kind, 1, size)),
+      }
+    }
+    let static_indices = instructions.iter().filter_map(|instruction| match instruction {
+      Instruction::Push { segment: Segment::Static, offset } | Instruction::Pop { segment: Segment::Static, offset } =>
+        Some(*offset),
+      _ =>
+        None,
+    }).unique().sorted().collect::<Vec<usize>>();
+    statics.push((program_name.clone(), static_indices));
+  }
+  add_size(&mut functions, &current_function, crate::rom_size(&backend.finish()));
+  let total_instructions = functions.iter().map(|(_, size)| size).sum::<usize>();
+  functions.sort_by(|(_, size1), (_, size2)| size2.cmp(size1));
+  commands.sort_by(|(_, _, size1), (_, _, size2)| size2.cmp(size1));
+  Stats {
+    total_instructions,
+    functions,
+    commands,
+    statics,
+  }
+}
+
+pub fn report(stats: &Stats) -> String {
+  let function_width = stats.functions.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+  let command_width = stats.commands.iter().map(|(kind, _, _)| kind.len()).max().unwrap_or(0);
+  let file_width = stats.statics.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("total".len());
+  let total_statics = stats.statics.iter().map(|(_, indices)| indices.len()).sum::<usize>();
+  format!(
+"ROM usage: {} of {} Hack instructions ({:.1}%)
+
+Instructions per function:
+{}
+
+Instructions per command:
+{}
+
+Static variables per file:
+{}
+  {:<file_width$}  {} of {} slots",
+    stats.total_instructions,
+    crate::ROM_SIZE,
+    stats.total_instructions as f64 * 100.0 / crate::ROM_SIZE as f64,
+    stats.functions.iter().map(|(name, size)|
+      format!("  {:<function_width$}  {:>6}", name, size, function_width = function_width)
+    ).join("\n"),
+    stats.commands.iter().map(|(kind, count, size)|
+      format!("  {:<command_width$}  {:>6} commands  {:>6} instructions", kind, count, size, command_width = command_width)
+    ).join("\n"),
+    stats.statics.iter().map(|(name, indices)|
+      format!("  {:<file_width$}  {}{}", name, indices.len(),
+        if indices.is_empty() { String::new() } else { format!(" (static {})", indices.iter().join(", ")) },
+        file_width = file_width,
+      )
+    ).join("\n"),
+    "total", total_statics, STATIC_SLOTS,
+    file_width = file_width,
+  )
+}
+
+// Adds code that came out between commands to a function seen before
+fn add_size(functions: &mut [(String, usize)], function: &str, size: usize) {
+  if let Some((_, function_size)) = functions.iter_mut().find(|(name, _)| name == function) {
+    *function_size += size;
+  }
+}
+
+fn command_kind(instruction: &Instruction) -> String {
+  match instruction {
+    Instruction::Arithmetic(arith_instruction) =>
+      arith_instruction.to_string(),
+    Instruction::Push { segment, .. } =>
+      format!("push {}", segment),
+    Instruction::Pop { segment, .. } =>
+      format!("pop {}", segment),
+    Instruction::Label(_) =>
+      "label".to_string(),
+    Instruction::Goto(_) =>
+      "goto".to_string(),
+    Instruction::IfGoto(_) =>
+      "if-goto".to_string(),
+    Instruction::Function { .. } =>
+      "function".to_string(),
+    Instruction::Call { .. } =>
+      "call".to_string(),
+    Instruction::Return =>
+      "return".to_string(),
+    Instruction::Ignored =>
+      "ignored".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::vm_stats::*;
+  use crate::memory_layout::MemoryLayout;
+  use crate::vm_emitter::Optimizations;
+
+  #[test]
+  fn test_collect() {
+    let source =
+"function Sys.init 0
+push constant 7
+pop static 2
+push static 2
+call Main.double 1
+label HALT
+goto HALT
+function Main.double 0
+push argument 0
+push argument 0
+add
+return
+".to_string();
+    let sources = vec![("Sys".to_string(), source)];
+    let files = crate::parse_program(&sources).unwrap();
+    let stats = collect(&files, HackBackend::default());
+    assert_eq!(stats.total_instructions, crate::rom_size(&crate::compile_program(&sources).unwrap()));
+    assert_eq!(stats.functions.iter().map(|(_, size)| size).sum::<usize>(), stats.total_instructions);
+    assert!(stats.functions.iter().any(|(name, _)| name == "(bootstrap)"));
+    assert_eq!(stats.commands.iter().find(|(kind, _, _)| kind == "push argument"), Some(&("push argument".to_string(), 2, 16)));
+    assert_eq!(stats.statics, vec![("Sys".to_string(), vec![2])]);
+
+    // The sizes follow the layout and optimizations of the compiled program
+    let optimizations = [
+      Optimizations { stack_registers: true, tail_calls: false },
+      Optimizations { stack_registers: false, tail_calls: true },
+    ];
+    let layout = MemoryLayout { stack_base: 300, static_base: 100, static_limit: 300, ..MemoryLayout::default() };
+    for (layout, optimizations) in [(MemoryLayout::default(), optimizations[0]), (MemoryLayout::default(), optimizations[1]), (layout, Optimizations::default())] {
+      let optimized = collect(&files, HackBackend::new(layout.clone(), optimizations));
+      assert_eq!(optimized.total_instructions, crate::rom_size(&crate::compile_program_in(&sources, &layout, optimizations).unwrap()));
+      assert_eq!(optimized.functions.iter().map(|(_, size)| size).sum::<usize>(), optimized.total_instructions);
+    }
+    let optimized = collect(&files, HackBackend::new(MemoryLayout::default(), optimizations[0]));
+    assert!(optimized.total_instructions < stats.total_instructions);
+  }
+}
@@ -0,0 +1,262 @@
+use crate::vm_parser::*;
+use crate::backend::{self, Backend};
+use crate::vm_emitter;
+use std::collections::HashMap;
+
+// Where the code after a label, function entry or call lands in the `br_table` dispatch
+#[derive(Default)]
+struct Cases {
+  labels: HashMap<String, usize>,
+  // The case that stops the program, used as the return address of the bootstrap
+  halt: usize,
+}
+
+// Emits a WebAssembly text module that runs the program on the Hack address space.
+// The exported memory `ram` holds one 16-bit word per Hack address, so JavaScript
+// can read it as an `Int16Array`, and RAM[0] starts at 256 unless the host changes it.
+// Wasm only has structured control flow so the whole program is one `run` function
+// where every VM label, function entry and return address is a case of a loop
+// around a `br_table`. `run` takes the number of jumps after which it gives up so
+// programs that loop forever don't freeze the page.
+pub fn emit_program(files: Vec<(String, Vec<Instruction>)>) -> String {
+  backend::emit(&mut WatBackend::default(), &files)
+}
+
+#[derive(Default)]
+pub struct WatBackend {
+  cases: Cases,
+  statics: HashMap<(String, usize), usize>,
+  program_name: String,
+  // The code of every case so far
+  code: Vec<String>,
+}
+
+impl Backend for WatBackend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+    self.cases = collect_cases(files);
+    self.statics = vm_emitter::static_addresses(files);
+    self.code = vec![String::new()];
+    if self.cases.labels.contains_key("Sys.init") {
+      self.code[0] = format!("      ;; Bootstrap: call Sys.init 0, which never returns\n{}\n", emit_call(&self.cases, "Sys.init", 0, self.cases.halt));
+    }
+  }
+
+  fn begin_file(&mut self, program_name: &str) {
+    self.program_name = program_name.to_string();
+  }
+
+  fn emit_instruction(&mut self, instruction: &Instruction) {
+    if let Instruction::Label(_) | Instruction::Function { .. } = instruction {
+      self.code.push(String::new());
+    }
+    let return_case = self.code.len();
+    let code = emit_instruction(&self.cases, &self.statics, &self.program_name, return_case, instruction);
+    self.code.last_mut().unwrap().push_str(&format!("      ;; {}\n{}\n", instruction, code));
+    if let Instruction::Call { .. } = instruction {
+      self.code.push(String::new());
+    }
+  }
+
+  fn finish(&mut self) -> String {
+    let halt = self.cases.halt;
+    let blocks = (0..=halt).rev()
+      .map(|case| format!("      block $case_{}\n", case))
+      .collect::<String>();
+    let table = (0..=halt)
+      .map(|case| format!("$case_{}", case))
+      .collect::<Vec<String>>()
+      .join(" ");
+    let body = self.code.iter().enumerate()
+      .map(|(case, code)| format!("      end\n      ;; case {}\n{}", case, code))
+      .collect::<String>();
+format!(
+"(module
+  ;; 32K words of 2 bytes each
+  (memory (export \"ram\") 1)
+  ;; SP = 256
+  (data (i32.const 0) \"\\00\\01\")
+
+  ;; Addresses wrap around the 32K RAM instead of leaving it
+  (func $get (param $address i32) (result i32)
+    (i32.load16_s (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1))))
+  (func $set (param $address i32) (param $value i32)
+    (i32.store16 (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1)) (local.get $value)))
+  (func $push (param $value i32)
+    (call $set (call $get (i32.const 0)) (local.get $value))
+    (call $set (i32.const 0) (i32.add (call $get (i32.const 0)) (i32.const 1))))
+  (func $pop (result i32)
+    (call $set (i32.const 0) (i32.sub (call $get (i32.const 0)) (i32.const 1)))
+    (call $get (call $get (i32.const 0))))
+
+  (func (export \"run\") (param $jumps i32)
+    (local $pc i32) (local $x i32) (local $y i32) (local $frame i32)
+    loop $dispatch
+      (if (i32.eqz (local.get $jumps)) (then (return)))
+      (local.set $jumps (i32.sub (local.get $jumps) (i32.const 1)))
+{}      (br_table {} (local.get $pc))
+{}      end
+    end)
+)
+", blocks, table, body)
+  }
+}
+
+// Numbers the cases the same way `WatBackend` splits the code: case 0 is the start
+// and every label, function and call return point begins a new one
+fn collect_cases(files: &[(String, Vec<Instruction>)]) -> Cases {
+  let mut labels = HashMap::new();
+  let mut count = 1;
+  for instruction in files.iter().flat_map(|(_, instructions)| instructions.iter()) {
+    match instruction {
+      Instruction::Label(name) | Instruction::Function { name, .. } => {
+        labels.entry(name.clone()).or_insert(count);
+        count += 1;
+      }
+      Instruction::Call { .. } =>
+        count += 1,
+      _ => {}
+    }
+  }
+  Cases { labels, halt: count }
+}
+
+// `return_case` is where a call continues after the callee returns
+fn emit_instruction(cases: &Cases, statics: &HashMap<(String, usize), usize>, program_name: &str, return_case: usize, instruction: &Instruction) -> String {
+  match instruction {
+    Instruction::Arithmetic(arith_instruction) =>
+      match arith_instruction {
+        ArithInstruction::Add =>
+          emit_binary_arithmetic("(i32.add (local.get $x) (local.get $y))"),
+        ArithInstruction::Sub =>
+          emit_binary_arithmetic("(i32.sub (local.get $x) (local.get $y))"),
+        ArithInstruction::Eq =>
+          emit_binary_arithmetic("(i32.sub (i32.const 0) (i32.eq (local.get $x) (local.get $y)))"),
+        ArithInstruction::Gt =>
+          emit_binary_arithmetic("(i32.sub (i32.const 0) (i32.gt_s (local.get $x) (local.get $y)))"),
+        ArithInstruction::Lt =>
+          emit_binary_arithmetic("(i32.sub (i32.const 0) (i32.lt_s (local.get $x) (local.get $y)))"),
+        ArithInstruction::And =>
+          emit_binary_arithmetic("(i32.and (local.get $x) (local.get $y))"),
+        ArithInstruction::Or =>
+          emit_binary_arithmetic("(i32.or (local.get $x) (local.get $y))"),
+        ArithInstruction::Neg =>
+          "      (call $push (i32.sub (i32.const 0) (call $pop)))".to_string(),
+        ArithInstruction::Not =>
+          "      (call $push (i32.xor (call $pop) (i32.const -1)))".to_string(),
+      },
+    Instruction::Push { segment: Segment::Constant, offset } =>
+      format!("      (call $push (i32.const {}))", offset),
+    Instruction::Push { segment, offset } =>
+      format!("      (call $push (call $get {}))", segment_address(statics, program_name, *segment, *offset)),
+    Instruction::Pop { segment, offset } =>
+      format!("      (call $set {} (call $pop))", segment_address(statics, program_name, *segment, *offset)),
+    Instruction::Ignored =>
+      panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
+    Instruction::Label(_) | Instruction::Function { local_vars: 0, .. } =>
+      String::new(),
+    Instruction::Function { local_vars, .. } =>
+      vec!["      (call $push (i32.const 0))"; *local_vars].join("\n"),
+    Instruction::Goto(label) =>
+      emit_goto(cases, label),
+    Instruction::IfGoto(label) =>
+      format!("      (if (call $pop) (then\n{}))", emit_goto(cases, label)),
+    Instruction::Call { name, args } =>
+      emit_call(cases, name, *args, return_case),
+    Instruction::Return =>
+      emit_return(),
+  }
+}
+
+// The Hack address of the segment entry
+fn segment_address(statics: &HashMap<(String, usize), usize>, program_name: &str, segment: Segment, offset: usize) -> String {
+  let base_plus_offset = |base: usize| format!("(i32.add (call $get (i32.const {})) (i32.const {}))", base, offset);
+  match segment {
+    Segment::Local =>
+      base_plus_offset(1),
+    Segment::Argument =>
+      base_plus_offset(2),
+    Segment::This =>
+      base_plus_offset(3),
+    Segment::That =>
+      base_plus_offset(4),
+    Segment::Constant =>
+      panic!("`constant {}` has no address. The parser should filter out `pop constant` before emitting.", offset),
+    Segment::Static =>
+      format!("(i32.const {})", statics[&(program_name.to_string(), offset)]),
+    Segment::Temp =>
+      format!("(i32.const {})", 5 + offset),
+    Segment::Pointer =>
+      format!("(i32.const {})", 3 + offset),
+  }
+}
+
+// Storing back to 16 bits wraps the result around like the Hack ALU
+fn emit_binary_arithmetic(expression: &str) -> String {
+  format!(
+"      (local.set $y (call $pop))
+      (local.set $x (call $pop))
+      (call $push {})", expression)
+}
+
+// Jumping to a label the program never defines traps
+fn emit_goto(cases: &Cases, label: &str) -> String {
+  match cases.labels.get(label) {
+    Some(case) =>
+      format!("      (local.set $pc (i32.const {}))\n      (br $dispatch)", case),
+    None =>
+      format!("      ;; {} is undefined\n      unreachable", label),
+  }
+}
+
+// Pushes the same frame as the Hack emitter so RAM looks the same in both,
+// except that the return address is a case number
+fn emit_call(cases: &Cases, name: &str, args: usize, return_case: usize) -> String {
+  format!(
+"      (call $push (i32.const {}))
+      (call $push (call $get (i32.const 1)))
+      (call $push (call $get (i32.const 2)))
+      (call $push (call $get (i32.const 3)))
+      (call $push (call $get (i32.const 4)))
+      (call $set (i32.const 2) (i32.sub (call $get (i32.const 0)) (i32.const {})))
+      (call $set (i32.const 1) (call $get (i32.const 0)))
+{}", return_case, 5 + args, emit_goto(cases, name))
+}
+
+fn emit_return() -> String {
+"      (local.set $frame (call $get (i32.const 1)))
+      (local.set $pc (call $get (i32.sub (local.get $frame) (i32.const 5))))
+      (call $set (call $get (i32.const 2)) (call $pop))
+      (call $set (i32.const 0) (i32.add (call $get (i32.const 2)) (i32.const 1)))
+      (call $set (i32.const 4) (call $get (i32.sub (local.get $frame) (i32.const 1))))
+      (call $set (i32.const 3) (call $get (i32.sub (local.get $frame) (i32.const 2))))
+      (call $set (i32.const 2) (call $get (i32.sub (local.get $frame) (i32.const 3))))
+      (call $set (i32.const 1) (call $get (i32.sub (local.get $frame) (i32.const 4))))
+      (br $dispatch)".to_string()
+}
+
+#[cfg(test)]
+mod test {
+  use crate::wat_emitter::*;
+  use crate::test_support::*;
+  use std::process::Command;
+
+  #[test]
+  fn test_emit_program() {
+    let binary = wat::parse_str(emit_program(fibonacci_program(HALT))).unwrap();
+
+    // Runs the module when node is around
+    let output = run_if_installed("node", "the WebAssembly output", &[("program.wasm", &binary)], |directory| {
+      let script = format!(
+"const fs = require('fs');
+WebAssembly.instantiate(fs.readFileSync('{}')).then(({{ instance }}) => {{
+  instance.exports.run(1000000);
+  const ram = new Int16Array(instance.exports.ram.buffer);
+  console.log(ram[16], ram[17]);
+}});", directory.join("program.wasm").display());
+      Command::new("node").arg("-e").arg(script).output()
+    });
+    if let Some(output) = output {
+      assert_eq!(String::from_utf8(output.stdout).unwrap(), "6765 3\n");
+    }
+  }
+}
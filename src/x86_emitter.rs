@@ -0,0 +1,393 @@
+use crate::vm_parser::*;
+use crate::backend::{self, Backend};
+use crate::vm_emitter;
+use std::collections::HashMap;
+
+// Labels the assembly jumps to, collected before emitting
+#[derive(Default)]
+struct Symbols {
+  // VM labels and function names, which share one namespace like in Hack assembly
+  labels: HashMap<String, usize>,
+  // RAM address of every (file, static index), the same as in the Hack output
+  statics: HashMap<(String, usize), usize>,
+}
+
+// Emits x86-64 GNU assembler (AT&T syntax) defining `void vm_run(void)`, which runs the
+// program on a simulated 16-bit Hack RAM `int16_t ram[32768]`. Arithmetic happens in
+// 32-bit registers and is stored back as 16 bits so it wraps around like the Hack ALU.
+// `%rbx` holds the address of `ram` for the whole run so the output also works in
+// position independent executables. Return addresses don't fit in a 16-bit word so
+// calls push an index into a table of return labels instead.
+// Link it with `RUNTIME` to get a native Linux executable.
+pub fn emit_program(files: Vec<(String, Vec<Instruction>)>) -> String {
+  backend::emit(&mut X86Backend::default(), &files)
+}
+
+#[derive(Default)]
+pub struct X86Backend {
+  symbols: Symbols,
+  program_name: String,
+  // Numbers the return label of the latest call, 0 being the halting code
+  return_index: usize,
+  body: Vec<String>,
+}
+
+impl Backend for X86Backend {
+  fn begin_program(&mut self, files: &[(String, Vec<Instruction>)]) {
+    self.symbols = collect_symbols(files);
+    if self.symbols.labels.contains_key("Sys.init") {
+      self.body.push("  # Bootstrap: SP = 256, call Sys.init 0 and halt when it returns\n  movw $256, (%rbx)".to_string());
+      self.body.push(emit_call(&self.symbols, "Sys.init", 0, 0));
+    }
+  }
+
+  fn begin_file(&mut self, program_name: &str) {
+    self.program_name = program_name.to_string();
+    self.body.push(format!("  # {}.vm", program_name));
+  }
+
+  fn emit_instruction(&mut self, instruction: &Instruction) {
+    if let Instruction::Call { .. } = instruction {
+      self.return_index += 1;
+    }
+    self.body.push(format!("  # {}\n{}", instruction, emit_instruction(&self.symbols, &self.program_name, self.return_index, instruction)));
+  }
+
+  fn finish(&mut self) -> String {
+    let return_table = (0..=self.return_index)
+      .map(|index| format!("  .long .Lreturn_{} - .Lreturn_table", index))
+      .collect::<Vec<String>>()
+      .join("\n");
+format!(
+"# eax = address of the segment entry at `offset` from the base pointer in RAM[`base`]
+.macro SEGMENT base, offset
+  movzwl 2*\\base(%rbx), %eax
+  addl $\\offset, %eax
+  andl $0x7fff, %eax
+.endm
+
+# RAM[SP++] = cx
+.macro PUSH
+  movzwl (%rbx), %eax
+  andl $0x7fff, %eax
+  movw %cx, (%rbx,%rax,2)
+  incw (%rbx)
+.endm
+
+# ecx = RAM[--SP] sign extended
+.macro POP
+  decw (%rbx)
+  movzwl (%rbx), %eax
+  andl $0x7fff, %eax
+  movswl (%rbx,%rax,2), %ecx
+.endm
+
+# Gives up once the runtime's jump budget runs out
+.macro STEP
+  decq vm_steps_left(%rip)
+  jz .Lreturn_0
+.endm
+
+  .bss
+  .globl ram
+  .align 32
+ram:
+  .zero 65536
+
+  .data
+  .globl vm_steps_left
+  .align 8
+vm_steps_left:
+  .quad 0x7fffffffffffffff
+
+  .text
+  .globl vm_run
+  .type vm_run, @function
+vm_run:
+  pushq %rbx
+  leaq ram(%rip), %rbx
+{}
+  # Halts when the program runs off its end or Sys.init returns
+.Lreturn_0:
+  popq %rbx
+  ret
+
+# Looks up the return label of the call with index eax
+.Ldispatch:
+  leaq .Lreturn_table(%rip), %rdx
+  movslq (%rdx,%rax,4), %rax
+  addq %rdx, %rax
+  jmp *%rax
+
+  .align 4
+.Lreturn_table:
+{}
+  .section .note.GNU-stack,\"\",@progbits
+", self.body.join("\n"), return_table)
+  }
+}
+
+fn collect_symbols(files: &[(String, Vec<Instruction>)]) -> Symbols {
+  let mut labels = HashMap::new();
+  for instruction in files.iter().flat_map(|(_, instructions)| instructions.iter()) {
+    if let Instruction::Label(name) | Instruction::Function { name, .. } = instruction {
+      let index = labels.len();
+      labels.entry(name.clone()).or_insert(index);
+    }
+  }
+  Symbols { labels, statics: vm_emitter::static_addresses(files) }
+}
+
+// `return_index` numbers the return label of the latest call
+fn emit_instruction(symbols: &Symbols, program_name: &str, return_index: usize, instruction: &Instruction) -> String {
+  match instruction {
+    Instruction::Arithmetic(arith_instruction) =>
+      match arith_instruction {
+        ArithInstruction::Add =>
+          emit_binary_arithmetic("addl %edx, %ecx"),
+        ArithInstruction::Sub =>
+          emit_binary_arithmetic("subl %edx, %ecx"),
+        ArithInstruction::Eq =>
+          emit_comparison("sete"),
+        ArithInstruction::Gt =>
+          emit_comparison("setg"),
+        ArithInstruction::Lt =>
+          emit_comparison("setl"),
+        ArithInstruction::And =>
+          emit_binary_arithmetic("andl %edx, %ecx"),
+        ArithInstruction::Or =>
+          emit_binary_arithmetic("orl %edx, %ecx"),
+        ArithInstruction::Neg =>
+          "  POP\n  negl %ecx\n  PUSH".to_string(),
+        ArithInstruction::Not =>
+          "  POP\n  notl %ecx\n  PUSH".to_string(),
+      },
+    Instruction::Push { segment, offset } =>
+      match segment {
+        Segment::Constant =>
+          format!("  movl ${}, %ecx\n  PUSH", offset),
+        Segment::Local | Segment::Argument | Segment::This | Segment::That =>
+          format!("  SEGMENT {}, {}\n  movzwl (%rbx,%rax,2), %ecx\n  PUSH", segment_base(*segment), offset),
+        _ =>
+          format!("  movzwl {}(%rbx), %ecx\n  PUSH", 2 * fixed_address(symbols, program_name, *segment, *offset)),
+      },
+    Instruction::Pop { segment, offset } =>
+      match segment {
+        Segment::Local | Segment::Argument | Segment::This | Segment::That =>
+          format!("  POP\n  SEGMENT {}, {}\n  movw %cx, (%rbx,%rax,2)", segment_base(*segment), offset),
+        _ =>
+          format!("  POP\n  movw %cx, {}(%rbx)", 2 * fixed_address(symbols, program_name, *segment, *offset)),
+      },
+    Instruction::Ignored =>
+      panic!("The emitter should not encountered Ignored instructions.\nThere's either a problem in the emitter or Rust."),
+    Instruction::Label(label) =>
+      format!("{}:\n  STEP", label_name(symbols, label)),
+    Instruction::Goto(label) =>
+      format!("  {}", emit_jump(symbols, "jmp", label)),
+    Instruction::IfGoto(label) =>
+      format!("  POP\n  testw %cx, %cx\n  {}", emit_jump(symbols, "jnz", label)),
+    Instruction::Function { name, local_vars } =>
+      format!("{}:\n  STEP{}", label_name(symbols, name), "\n  movl $0, %ecx\n  PUSH".repeat(*local_vars)),
+    Instruction::Call { name, args } =>
+      emit_call(symbols, name, *args, return_index),
+    Instruction::Return =>
+      emit_return(),
+  }
+}
+
+// The RAM address holding the base pointer of the segment
+fn segment_base(segment: Segment) -> usize {
+  match segment {
+    Segment::Local => 1,
+    Segment::Argument => 2,
+    Segment::This => 3,
+    _ => 4,
+  }
+}
+
+// The RAM address of a static, temp or pointer entry
+fn fixed_address(symbols: &Symbols, program_name: &str, segment: Segment, offset: usize) -> usize {
+  match segment {
+    Segment::Static =>
+      symbols.statics[&(program_name.to_string(), offset)],
+    Segment::Temp =>
+      5 + offset,
+    Segment::Pointer =>
+      3 + offset,
+    _ =>
+      panic!("`{}` has no fixed address. There's a problem in the emitter.", segment),
+  }
+}
+
+// ecx = x, edx = y
+fn emit_binary_arithmetic(operation_str: &str) -> String {
+  format!("  POP\n  movl %ecx, %edx\n  POP\n  {}\n  PUSH", operation_str)
+}
+
+// Pushes -1 when the comparison holds and 0 otherwise
+fn emit_comparison(set_instruction: &str) -> String {
+  emit_binary_arithmetic(&format!("cmpw %dx, %cx\n  {} %cl\n  movzbl %cl, %ecx\n  negl %ecx", set_instruction))
+}
+
+// Label names can hold characters like `$` that need quoting in GNU as,
+// so every label is numbered and jumps keep the original name in a comment
+fn label_name(symbols: &Symbols, label: &str) -> String {
+  format!(".Llabel_{}", symbols.labels[label])
+}
+
+// Jumping to a label the program never defines crashes with an invalid opcode
+fn emit_jump(symbols: &Symbols, jump_instruction: &str, label: &str) -> String {
+  if symbols.labels.contains_key(label) {
+    format!("{} {} # {}", jump_instruction, label_name(symbols, label), label)
+  } else {
+    format!("ud2 # {} is undefined", label)
+  }
+}
+
+// Pushes the same frame as the Hack emitter so RAM looks the same in both,
+// except that the return address is an index into the return table
+fn emit_call(symbols: &Symbols, name: &str, args: usize, return_index: usize) -> String {
+  format!(
+"  movl ${}, %ecx
+  PUSH
+  movzwl 2(%rbx), %ecx
+  PUSH
+  movzwl 4(%rbx), %ecx
+  PUSH
+  movzwl 6(%rbx), %ecx
+  PUSH
+  movzwl 8(%rbx), %ecx
+  PUSH
+  movzwl (%rbx), %eax
+  subl ${}, %eax
+  movw %ax, 4(%rbx)
+  movw (%rbx), %ax
+  movw %ax, 2(%rbx)
+  {}{}", return_index, 5 + args, emit_jump(symbols, "jmp", name),
+  // The bootstrap returns to the halting code instead
+  if return_index == 0 { String::new() } else { format!("\n.Lreturn_{}:", return_index) })
+}
+
+// r8d = frame, r9d = return index, read before `*ARG` overwrites it when there are no arguments
+fn emit_return() -> String {
+"  movzwl 2(%rbx), %r8d
+  leal -5(%r8d), %eax
+  andl $0x7fff, %eax
+  movzwl (%rbx,%rax,2), %r9d
+  POP
+  movzwl 4(%rbx), %eax
+  andl $0x7fff, %eax
+  movw %cx, (%rbx,%rax,2)
+  incl %eax
+  movw %ax, (%rbx)
+.irp register, 4, 3, 2, 1
+  leal \\register-5(%r8d), %eax
+  andl $0x7fff, %eax
+  movw (%rbx,%rax,2), %cx
+  movw %cx, 2*\\register(%rbx)
+.endr
+  movl %r9d, %eax
+  jmp .Ldispatch".to_string()
+}
+
+// Runs `vm_run` with RAM set from `address=value` arguments and prints the stack.
+// `--max-steps N` stops programs that never halt after N jumps to a label and
+// `--screen FILE` saves the screen memory map as a PBM image. Set RAM[24576]
+// to stub a key press.
+pub const RUNTIME: &str =
+"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+extern int16_t ram[32768];
+extern int64_t vm_steps_left;
+void vm_run(void);
+
+#define SCREEN 16384
+#define SCREEN_WIDTH 512
+#define SCREEN_HEIGHT 256
+
+static int save_screen(const char *path) {
+  FILE *file = fopen(path, \"w\");
+  int row, col;
+  if (file == NULL) {
+    return 0;
+  }
+  fprintf(file, \"P1\\n%d %d\\n\", SCREEN_WIDTH, SCREEN_HEIGHT);
+  for (row = 0; row < SCREEN_HEIGHT; row++) {
+    for (col = 0; col < SCREEN_WIDTH; col++) {
+      uint16_t word = (uint16_t)ram[SCREEN + row * SCREEN_WIDTH / 16 + col / 16];
+      fputc(word >> (col % 16) & 1 ? '1' : '0', file);
+    }
+    fputc('\\n', file);
+  }
+  return fclose(file) == 0;
+}
+
+int main(int argc, char **argv) {
+  const char *screen_path = NULL;
+  int i, address, value;
+  ram[0] = 256;
+  for (i = 1; i < argc; i++) {
+    if (strcmp(argv[i], \"--max-steps\") == 0 && i + 1 < argc) {
+      vm_steps_left = atoll(argv[++i]);
+    } else if (strcmp(argv[i], \"--screen\") == 0 && i + 1 < argc) {
+      screen_path = argv[++i];
+    } else if (sscanf(argv[i], \"%d=%d\", &address, &value) == 2) {
+      ram[address & 0x7fff] = (int16_t)value;
+    }
+  }
+  vm_run();
+  for (address = 256; address < ram[0]; address++) {
+    printf(\"%d\\n\", ram[address]);
+  }
+  if (screen_path != NULL && !save_screen(screen_path)) {
+    fprintf(stderr, \"I couldn't write the screen to %s.\\n\", screen_path);
+    return 1;
+  }
+  return 0;
+}
+";
+
+#[cfg(test)]
+mod test {
+  use crate::x86_emitter::*;
+  use crate::test_support::*;
+  use std::process::Command;
+
+  #[test]
+  fn test_emit_program() {
+    // Sys.init returns 6765 - 3 - (7 == 7) through THAT, which the runtime prints
+    // as the stack left after the bootstrap
+    let ending =
+"push static 0
+push static 1
+sub
+push constant 7
+push constant 7
+eq
+pop pointer 1
+push pointer 1
+sub
+return
+";
+    let assembly = emit_program(fibonacci_program(ending));
+
+    // Builds and runs a native executable when gcc is around
+    let files = [("program.s", assembly.as_bytes()), ("runtime.c", RUNTIME.as_bytes())];
+    let output = run_if_installed("gcc", "the x86-64 output", &files, |directory| {
+      let status = Command::new("gcc")
+        .args(["-Wall", "-Werror", "-O2", "-o"])
+        .arg(directory.join("program"))
+        .arg(directory.join("program.s"))
+        .arg(directory.join("runtime.c"))
+        .status()?;
+      assert!(status.success());
+      Command::new(directory.join("program")).args(["--max-steps", "1000000"]).output()
+    });
+    if let Some(output) = output {
+      assert_eq!(String::from_utf8(output.stdout).unwrap(), "6763\n");
+    }
+  }
+}
@@ -1,10 +1,18 @@
 extern crate vm_compiler;
 extern crate clap;
 
-use clap::{App, Arg,};
-use std::fs::File;
+use clap::{App, AppSettings, Arg, SubCommand};
+use lip::{Located, Location};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::net::TcpListener;
+use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use vm_compiler::backend::Target;
+use vm_compiler::memory_layout::MemoryLayout;
+use vm_compiler::vm_emitter::Optimizations;
 
 macro_rules! error {
   ($($arg:tt)*) => ({
@@ -13,22 +21,61 @@ macro_rules! error {
   })
 }
 
+const INPUT_EXTENSION: &str = "vm";
+const JACK_EXTENSION: &str = "jack";
+const OUTPUT_EXTENSION: &str = "asm";
+const HACK_EXTENSION: &str = "hack";
+// The C runtime that `--target x86` output is linked with
+const X86_RUNTIME_FILE: &str = "runtime.c";
+const CFG_DOT_EXTENSION: &str = "cfg.dot";
+const CALL_GRAPH_DOT_EXTENSION: &str = "calls.dot";
+// The collapsed stacks read by flame graph tools
+const FOLDED_EXTENSION: &str = "folded";
+// How many lines `--profile` shows
+const PROFILE_LINES: usize = 20;
+const DEBUG_PROMPT: &str = "(debug) ";
+const REPLAY_PROMPT: &str = "(replay) ";
+const TRACE_EXTENSION: &str = "trace";
+const INPUT_TYPE: &str = "vm";
+const OUTPUT_TYPE: &str = "assembly";
+// How often `--watch` checks the input for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// How the VM commands are turned into output, besides the target
+struct Options {
+  layout: MemoryLayout,
+  optimizations: Optimizations,
+  // Functions with at most this many commands are inlined
+  inline_threshold: Option<usize>,
+  // Checks the stack depth of every command in VM files and the local and argument offsets
+  verify: bool,
+  // Rejects commands outside of functions and functions without a final `return`
+  strict: bool,
+}
+
 fn main() {
-  const INPUT_EXTENSION: &'static str = "vm";
-  const OUTPUT_EXTENSION: &'static str = "asm";
-  const INPUT_TYPE: &'static str = "vm";
-  const OUTPUT_TYPE: &'static str = "assembly";
+  let targets = vm_compiler::backend::targets();
+  let subcommand_input_help = format!(
+    "Sets the input {} program, a `.{}` file, a `.{}` class or a directory of them",
+    INPUT_TYPE,
+    INPUT_EXTENSION,
+    JACK_EXTENSION,
+  );
   let matches = App::new("VM Compiler")
     .version("1.0")
     .author("Kevin Li <kevinli020508@gmail.com>")
     .about("Compiler for the virtual machine of the Nand to Tetris course")
+    .setting(AppSettings::SubcommandsNegateReqs)
     .arg(
       Arg::with_name("input")
         .short("i")
         .help(&format!(
-          "Sets the input {} program to compile, file extension should be `.{}`",
+          "Sets the input {} program to compile, file extension should be `.{}` or `.{}` for a Jack class. Pass a directory to compile all of its `.{}` and `.{}` files into one program",
           INPUT_TYPE,
           INPUT_EXTENSION,
+          JACK_EXTENSION,
+          INPUT_EXTENSION,
+          JACK_EXTENSION,
         ))
         .takes_value(true)
         .required(true),
@@ -37,13 +84,215 @@ fn main() {
       Arg::with_name("output")
         .short("o")
         .help(&format!(
-          "Sets the output file to hold the {} code, file exntesion should be `.{}`",
+          "Sets the output file to hold the {} code, file exntesion should be `.{}` or the one of the `--target`",
           OUTPUT_TYPE,
           OUTPUT_EXTENSION
         ))
         .takes_value(true),
     )
+    .arg(
+      Arg::with_name("target")
+        .long("target")
+        .help(&format!(
+          "Sets the code generation target: {}",
+          targets.iter().map(|target| format!("`{}` for {} (`.{}`)", target.name, target.description, target.extension)).collect::<Vec<String>>().join(", "),
+        ))
+        .takes_value(true)
+        .possible_values(&targets.iter().map(|target| target.name).collect::<Vec<&str>>())
+        .default_value("hack"),
+    )
+    .arg(
+      Arg::with_name("stats")
+        .long("stats")
+        .help("Prints the ROM usage per function and per command and the static variables used by each file"),
+    )
+    .arg(
+      Arg::with_name("wat")
+        .long("wat")
+        .help("Same as `--target wat`")
+        .conflicts_with("target"),
+    )
+    .arg(
+      Arg::with_name("emit")
+        .long("emit")
+        .value_name("GRAPH")
+        .possible_values(&["cfg-dot", "call-graph-dot"])
+        .help(&format!(
+          "Also writes a Graphviz graph next to the {} output: `cfg-dot` for the control flow of every function (`.{}`) or `call-graph-dot` for who calls whom (`.{}`), which also reports undefined functions, missing arguments and recursion",
+          OUTPUT_TYPE,
+          CFG_DOT_EXTENSION,
+          CALL_GRAPH_DOT_EXTENSION,
+        )),
+    )
+    .arg(
+      Arg::with_name("layout")
+        .long("layout")
+        .value_name("FILE")
+        .help("Reads where the stack, temp, static and heap segments go from `name = value` lines like `stack_base = 512`. Only for the `hack` target"),
+    )
+    .arg(
+      Arg::with_name("optimize")
+        .long("optimize")
+        .help("Keeps values of straight-line code in registers instead of the stack. Only for the `hack` target"),
+    )
+    .arg(
+      Arg::with_name("tail-calls")
+        .long("tail-calls")
+        .help("Reuses the frame for a `call` right before a `return` so tail recursion doesn't grow the stack. Only for the `hack` target"),
+    )
+    .arg(
+      Arg::with_name("inline")
+        .long("inline")
+        .value_name("SIZE")
+        .validator(|size| size.parse::<usize>().map(|_| ()).map_err(|_| format!("`{}` isn't a number of commands.", size)))
+        .help("Replaces calls to straight-line functions of at most SIZE commands with their bodies"),
+    )
+    .arg(
+      Arg::with_name("verify")
+        .long("verify")
+        .help("Rejects VM files where a command pops from an empty stack, paths reach a label with different stack depths, `return` has nothing to return, or a function uses more locals than it declares or more arguments than its callers pass"),
+    )
+    .arg(
+      Arg::with_name("strict")
+        .long("strict")
+        .help("Rejects VM files with commands outside of functions when they have functions, `return` outside of a function, or a function that can run off its end without `return`. Only the tests of Project 7 need these"),
+    )
+    .arg(
+      Arg::with_name("profile")
+        .long("profile")
+        .help("Runs the Hack code of the program in an emulator instead of writing it and prints the instructions executed per function, per line and per path through the calls")
+        .conflicts_with_all(&["watch", "target", "layout", "optimize", "tail-calls", "inline", "emit", "wat"]),
+    )
+    .arg(
+      Arg::with_name("cycles")
+        .long("cycles")
+        .value_name("COUNT")
+        .default_value("10000000")
+        .validator(|count| count.parse::<usize>().map(|_| ()).map_err(|_| format!("`{}` isn't a number of instructions.", count)))
+        .help("Stops `--profile` after running this many Hack instructions, for programs like games that never halt"),
+    )
+    .arg(
+      Arg::with_name("collapsed")
+        .long("collapsed")
+        .requires("profile")
+        .help(&format!(
+          "Also writes the profile as collapsed stacks for flame graph tools next to the {} output, with the extension `.{}`",
+          OUTPUT_TYPE,
+          FOLDED_EXTENSION,
+        )),
+    )
+    .arg(
+      Arg::with_name("gdb")
+        .long("gdb")
+        .value_name("PORT")
+        .validator(|port| port.parse::<u16>().map(|_| ()).map_err(|_| format!("`{}` isn't a port number.", port)))
+        .help("Runs the Hack code of the program in an emulator instead of writing it and waits for gdb or another debugger speaking the GDB remote protocol on this local port")
+        .conflicts_with_all(&["watch", "target", "emit", "wat", "profile"]),
+    )
+    .arg(
+      Arg::with_name("watch")
+        .long("watch")
+        .help("Keeps running and recompiles whenever the input changes"),
+    )
+    .arg(
+      Arg::with_name("decompile")
+        .long("decompile")
+        .help(&format!(
+          "Recovers the {} commands from an `.{}` or `.{}` file produced by this compiler and writes them to a `.{}` file",
+          INPUT_TYPE,
+          OUTPUT_EXTENSION,
+          HACK_EXTENSION,
+          INPUT_EXTENSION,
+        ))
+        .conflicts_with_all(&["stats", "watch", "target", "emit", "layout", "optimize", "tail-calls", "inline", "verify", "strict"]),
+    )
+    .subcommand(
+      SubCommand::with_name("debug")
+        .about("Runs the program one VM command at a time with breakpoints. Type `help` at the prompt to see the commands")
+        .arg(subcommand_input(&subcommand_input_help)),
+    )
+    .subcommand(
+      SubCommand::with_name("record")
+        .about("Runs the program and records every VM command it runs with the stack before it, for `replay`")
+        .arg(subcommand_input(&subcommand_input_help))
+        .arg(
+          Arg::with_name("output")
+            .short("o")
+            .help(&format!("Sets the trace file to write, file extension should be `.{}`", TRACE_EXTENSION))
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("steps")
+            .long("steps")
+            .value_name("COUNT")
+            .default_value("10000000")
+            .validator(|count| count.parse::<usize>().map(|_| ()).map_err(|_| format!("`{}` isn't a number of commands.", count)))
+            .help("Stops recording after this many VM commands, for programs like games that never halt"),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("replay")
+        .about("Moves forwards and backwards through a trace from `record`. Type `help` at the prompt to see the commands")
+        .arg(subcommand_input(&subcommand_input_help))
+        .arg(
+          Arg::with_name("trace")
+            .short("t")
+            .help("Sets the trace file of the program to replay")
+            .takes_value(true)
+            .required(true),
+        ),
+    )
     .get_matches();
+  match matches.subcommand() {
+    ("debug", Some(matches)) => {
+      debug(Path::new(matches.value_of("input").unwrap()));
+      return;
+    }
+    ("record", Some(matches)) => {
+      let steps = matches.value_of("steps").unwrap().parse::<usize>().unwrap();
+      record(Path::new(matches.value_of("input").unwrap()), matches.value_of("output").map(Path::new), steps);
+      return;
+    }
+    ("replay", Some(matches)) => {
+      replay(Path::new(matches.value_of("input").unwrap()), Path::new(matches.value_of("trace").unwrap()));
+      return;
+    }
+    _ => {}
+  }
+  let target_name = if matches.is_present("wat") { "wat" } else { matches.value_of("target").unwrap() };
+  let target = vm_compiler::backend::target(target_name).unwrap();
+  let layout = match matches.value_of("layout") {
+    Some(_) if target.name != "hack" =>
+      error!("`--layout` only applies to the `hack` target but the target is `{}`.", target.name),
+    Some(layout_path) => {
+      let source = match fs::read_to_string(layout_path) {
+        Ok(source) => source,
+        Err(why) => error!("I couldn't read layout file {}: {}.", layout_path, why),
+      };
+      match MemoryLayout::parse(&source) {
+        Ok(layout) => layout,
+        Err(error) => error!("{}", error),
+      }
+    }
+    None => MemoryLayout::default(),
+  };
+  let optimizations = Optimizations {
+    stack_registers: matches.is_present("optimize"),
+    tail_calls: matches.is_present("tail-calls"),
+  };
+  let flags = [("optimize", optimizations.stack_registers), ("tail-calls", optimizations.tail_calls), ("stats", matches.is_present("stats"))];
+  for (flag, is_present) in flags.iter() {
+    if *is_present && target.name != "hack" {
+      error!("`--{}` only applies to the `hack` target but the target is `{}`.", flag, target.name);
+    }
+  }
+  let options = Options {
+    layout,
+    optimizations,
+    inline_threshold: matches.value_of("inline").map(|size| size.parse::<usize>().unwrap()),
+    verify: matches.is_present("verify"),
+    strict: matches.is_present("strict"),
+  };
   let input_path = Path::new(matches.value_of("input").unwrap());
   if !input_path.exists() {
     error!(
@@ -51,42 +300,44 @@ fn main() {
       input_path.display()
     );
   }
-  if !input_path.is_file() {
-    error!("Input path `{}` points to a directory instead of an `.{}` file.\nTry passing in a file path.", input_path.display(), INPUT_EXTENSION);
+  if matches.is_present("decompile") {
+    let output_path = matches.value_of("output").map_or(input_path.with_extension(INPUT_EXTENSION), PathBuf::from);
+    decompile(input_path, &output_path);
+    return;
   }
-  let print_extension_error = || {
-    error!("Input file `{}` doesn't have a valid extension. Should end with `.{}` for a {} input.", input_path.file_name().unwrap().to_str().unwrap(), INPUT_EXTENSION, INPUT_TYPE);
-  };
-  match input_path.extension() {
-    Some(extension) => {
-      if extension != INPUT_EXTENSION {
+  let default_output_path = if input_path.is_dir() {
+    let directory_name = match input_path.canonicalize().ok().and_then(|path| path.file_name().map(|name| name.to_os_string())) {
+      Some(name) => name,
+      None => error!("I couldn't figure out the name of directory `{}`.", input_path.display()),
+    };
+    input_path.join(directory_name).with_extension(target.extension)
+  } else {
+    let print_extension_error = || {
+      error!("Input file `{}` doesn't have a valid extension. Should end with `.{}` for a {} input or `.{}` for a Jack class.", input_path.file_name().unwrap().to_str().unwrap(), INPUT_EXTENSION, INPUT_TYPE, JACK_EXTENSION);
+    };
+    match input_path.extension() {
+      Some(extension) => {
+        if extension != INPUT_EXTENSION && extension != JACK_EXTENSION {
+          print_extension_error();
+          return;
+        }
+      }
+      None => {
         print_extension_error();
         return;
       }
     }
-    None => {
-      print_extension_error();
-      return;
-    }
-  }
-  let input_file_name = input_path.file_name().unwrap()
-    .to_str()
-    .unwrap()
-    .replace(&format!(".{}", INPUT_EXTENSION), "");
-  let default_output_path_str = &(input_path
-    .to_str()
-    .unwrap()
-    .replace(&format!(".{}", INPUT_EXTENSION), "") + &format!(".{}", OUTPUT_EXTENSION));
-  let default_output_path = Path::new(default_output_path_str);
+    input_path.with_extension(target.extension)
+  };
   let output_path = matches
     .value_of("output")
-    .map_or(default_output_path, |path_str| Path::new(path_str));
+    .map_or(default_output_path.as_path(), Path::new);
   let print_extension_error = || {
-    error!("Output file `{}` doesn't have a valid extension. Should end with `.{}` for an {} output.", output_path.file_name().unwrap().to_str().unwrap(), OUTPUT_EXTENSION, OUTPUT_TYPE)
+    error!("Output file `{}` doesn't have a valid extension. Should end with `.{}` for a {} output.", output_path.file_name().unwrap().to_str().unwrap(), target.extension, target.description)
   };
   match output_path.extension() {
     Some(extension) => {
-      if extension != OUTPUT_EXTENSION {
+      if extension != target.extension {
         print_extension_error();
         return;
       }
@@ -97,19 +348,46 @@ fn main() {
     }
   }
 
-  let mut input_file = match File::open(&input_path) {
-    Err(why) => error!("I couldn't open {}: {}.", input_path.display(), why),
-    Ok(file) => file,
-  };
-
-  // Read the file contents into a string, returns `io::Result<usize>`
-  let mut input_str = String::new();
-  match input_file.read_to_string(&mut input_str) {
-    Err(why) => error!("I couldn't read {}: {}.", input_path.display(), why),
-    Ok(_) => println!("Loaded input file {}.", input_path.display()),
+  if matches.is_present("profile") {
+    let cycles = matches.value_of("cycles").unwrap().parse::<usize>().unwrap();
+    profile(input_path, output_path, cycles, matches.is_present("collapsed"));
+  } else if let Some(port) = matches.value_of("gdb") {
+    debug_server(input_path, &target, &options, port.parse::<u16>().unwrap());
+  } else if matches.is_present("watch") {
+    watch(input_path, output_path, &target, &options);
+  } else {
+    compile(input_path, output_path, &target, &options, matches.is_present("stats"), matches.value_of("emit"));
   }
+}
+
+fn compile(input_path: &Path, output_path: &Path, target: &Target, options: &Options, show_stats: bool, graph: Option<&str>) {
+  let sources = match read_sources(input_path) {
+    Err(error) => error!("{}", error),
+    Ok(sources) => sources,
+  };
+  println!("Loaded input {}.", input_path.display());
 
-  let output = match vm_compiler::compile(&input_file_name, &input_str) {
+  let output = parse_sources(&sources, options).and_then(|files| {
+    let inlined = inline(options, &files);
+    if show_stats {
+      println!("{}\n", vm_compiler::vm_stats::report(&vm_compiler::vm_stats::collect(&inlined, hack_backend(options))));
+      match vm_compiler::stack_usage::estimate(&files, "Sys.init") {
+        Ok(usage) => println!("Stack usage: at most {} words through {}\n", usage.words, usage.path.join(" -> ")),
+        Err(reason) => println!("Stack usage: {}\n", reason),
+      }
+    }
+    if target.name == "hack" {
+      if let Some(warning) = vm_compiler::stack_usage::check(&files, &options.layout) {
+        println!("{}\n", warning);
+      }
+    }
+    if let Some(graph) = graph {
+      let graph_path = write_graph(output_path, graph, &files)?;
+      println!("Wrote to {}.", graph_path.display());
+    }
+    emit(target, options, &inlined)
+  });
+  let output = match output {
     Ok(output) => {
       println!(
         "Compiled program {}",
@@ -122,13 +400,416 @@ fn main() {
     }
   };
 
-  let mut output_file = match File::create(&output_path) {
-    Err(why) => error!("I couldn't create {}: {}.", output_path.display(), why),
-    Ok(file) => file,
+  match write_output(output_path, &output) {
+    Err(error) => error!("{}", error),
+    Ok(_) => println!("Wrote to {}.", output_path.display()),
+  }
+  if target.name == "x86" {
+    match link_x86(output_path) {
+      Err(error) => error!("{}", error),
+      Ok(message) => println!("{}", message),
+    }
+  }
+}
+
+fn profile(input_path: &Path, output_path: &Path, cycles: usize, write_collapsed: bool) {
+  let sources = match read_sources(input_path) {
+    Err(error) => error!("{}", error),
+    Ok(sources) => sources,
+  };
+  println!("Loaded input {}.", input_path.display());
+  let files = match parse_located_sources(&sources) {
+    Err(error) => error!("{}", error),
+    Ok(files) => files,
+  };
+  let profile = match vm_compiler::profiler::profile(&files, cycles) {
+    Err(error) => error!("{}", error),
+    Ok(profile) => profile,
+  };
+  println!("{}\n\n{}", profile.flat_report(PROFILE_LINES), profile.call_tree_report());
+  if write_collapsed {
+    let collapsed_path = output_path.with_extension(FOLDED_EXTENSION);
+    match write_output(&collapsed_path, &profile.collapsed_stacks()) {
+      Err(error) => error!("{}", error),
+      Ok(_) => println!("Wrote to {}.", collapsed_path.display()),
+    }
+  }
+}
+
+fn debug(input_path: &Path) {
+  let sources = match read_sources(input_path) {
+    Err(error) => error!("{}", error),
+    Ok(sources) => sources,
+  };
+  let files = match parse_located_sources(&sources) {
+    Err(error) => error!("{}", error),
+    Ok(files) => files,
   };
+  let mut debugger = vm_compiler::debugger::Debugger::new(files);
+  println!("Loaded input {}. Type `help` to see the commands.\n{}", input_path.display(), debugger.status());
+  prompt(DEBUG_PROMPT, |line| debugger.execute(line));
+}
 
-  match output_file.write_all(output.as_bytes()) {
-    Err(why) => error!("I couldn't write to {}: {}.", output_path.display(), why),
+fn record(input_path: &Path, output_path: Option<&Path>, steps: usize) {
+  let sources = match read_sources(input_path) {
+    Err(error) => error!("{}", error),
+    Ok(sources) => sources,
+  };
+  let files = match parse_located_sources(&sources) {
+    Err(error) => error!("{}", error),
+    Ok(files) => files,
+  };
+  let output_path = match output_path {
+    Some(output_path) => output_path.to_path_buf(),
+    None if input_path.is_dir() => match input_path.canonicalize().ok().and_then(|path| path.file_name().map(|name| name.to_os_string())) {
+      Some(name) => input_path.join(name).with_extension(TRACE_EXTENSION),
+      None => error!("I couldn't figure out the name of directory `{}`.", input_path.display()),
+    },
+    None => input_path.with_extension(TRACE_EXTENSION),
+  };
+  let mut machine = vm_compiler::vm_interpreter::Machine::new(files);
+  let (trace, state) = vm_compiler::trace::record(&mut machine, steps);
+  match state {
+    Ok(vm_compiler::vm_interpreter::State::Halted) => println!("The program halted after {} commands.", machine.steps),
+    Ok(vm_compiler::vm_interpreter::State::Running) => println!("Stopped recording after {} commands.", machine.steps),
+    Err(error) => println!("{}\nRecorded the {} commands before it.", error, machine.steps - 1),
+  }
+  match fs::write(&output_path, trace.to_bytes()) {
+    Err(why) => error!("I couldn't write {}: {}.", output_path.display(), why),
     Ok(_) => println!("Wrote to {}.", output_path.display()),
   }
 }
+
+fn replay(input_path: &Path, trace_path: &Path) {
+  let sources = match read_sources(input_path) {
+    Err(error) => error!("{}", error),
+    Ok(sources) => sources,
+  };
+  let files = match parse_located_sources(&sources) {
+    Err(error) => error!("{}", error),
+    Ok(files) => files,
+  };
+  let replay = fs::read(trace_path)
+    .map_err(|why| format!("I couldn't read trace {}: {}.", trace_path.display(), why))
+    .and_then(|bytes| vm_compiler::trace::Trace::from_bytes(&bytes))
+    .and_then(|trace| vm_compiler::trace::Replay::new(files, trace));
+  let mut replay = match replay {
+    Err(error) => error!("{}", error),
+    Ok(replay) => replay,
+  };
+  println!("Loaded trace {}. Type `help` to see the commands.\n{}", trace_path.display(), replay.status());
+  prompt(REPLAY_PROMPT, |line| replay.execute(line));
+}
+
+// Reads commands from the standard input until `quit` and prints what
+// `execute` returns for each
+fn prompt(prompt: &str, mut execute: impl FnMut(&str) -> String) {
+  let stdin = std::io::stdin();
+  loop {
+    print!("{}", prompt);
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match stdin.lock().read_line(&mut line) {
+      Ok(0) | Err(_) => break,
+      Ok(_) => {}
+    }
+    match line.trim() {
+      "quit" | "q" => break,
+      line => {
+        let output = execute(line);
+        if !output.is_empty() {
+          println!("{}", output);
+        }
+      }
+    }
+  }
+}
+
+fn debug_server(input_path: &Path, target: &Target, options: &Options, port: u16) {
+  let rom = read_sources(input_path)
+    .and_then(|sources| parse_sources(&sources, options))
+    .and_then(|files| emit(target, options, &inline(options, &files)))
+    .and_then(|assembly| vm_compiler::hack_assembler::assemble(&assembly));
+  let rom = match rom {
+    Err(error) => error!("{}", error),
+    Ok(rom) => rom,
+  };
+  let listener = match TcpListener::bind(("127.0.0.1", port)) {
+    Err(why) => error!("I couldn't listen on port {}: {}.", port, why),
+    Ok(listener) => listener,
+  };
+  println!("Loaded input {}. Waiting for a debugger on port {}, try `target remote :{}` in gdb.", input_path.display(), port, port);
+  let stream = match listener.accept() {
+    Err(why) => error!("I couldn't accept the debugger: {}.", why),
+    Ok((stream, _)) => stream,
+  };
+  let mut stub = vm_compiler::gdb_stub::GdbStub::new(vm_compiler::hack_emulator::Emulator::new(rom));
+  match stub.serve(stream) {
+    Err(error) => error!("{}", error),
+    Ok(_) => println!("The debugger disconnected."),
+  }
+}
+
+fn subcommand_input<'a>(help: &'a str) -> Arg<'a, 'a> {
+  Arg::with_name("input")
+    .short("i")
+    .help(help)
+    .takes_value(true)
+    .required(true)
+}
+
+fn decompile(input_path: &Path, output_path: &Path) {
+  let is_hack = input_path.extension().is_some_and(|extension| extension == HACK_EXTENSION);
+  if !is_hack && input_path.extension().is_none_or(|extension| extension != OUTPUT_EXTENSION) {
+    error!("Input file `{}` can't be decompiled. Should end with `.{}` or `.{}`.", input_path.display(), OUTPUT_EXTENSION, HACK_EXTENSION);
+  }
+  if output_path.extension().is_none_or(|extension| extension != INPUT_EXTENSION) {
+    error!("Output file `{}` doesn't have a valid extension. Should end with `.{}` for a {} output.", output_path.display(), INPUT_EXTENSION, INPUT_TYPE);
+  }
+  let source = match fs::read_to_string(input_path) {
+    Ok(source) => source,
+    Err(why) => error!("I couldn't read {}: {}.", input_path.display(), why),
+  };
+  let assembly = if is_hack {
+    match vm_compiler::hack_disassembler::disassemble(&source) {
+      Ok(assembly) => assembly,
+      Err(error) => error!("{}", error),
+    }
+  } else {
+    source
+  };
+  let recognized = vm_compiler::vm_recognizer::recognize(&assembly);
+  let unrecognized = recognized.iter()
+    .filter(|item| matches!(item, vm_compiler::vm_recognizer::Recognized::Assembly(_)))
+    .count();
+  match write_output(output_path, &vm_compiler::vm_recognizer::to_vm_source(&recognized)) {
+    Err(error) => error!("{}", error),
+    Ok(_) => println!(
+      "Recovered {} VM commands, kept {} unrecognized assembly lines as comments and wrote to {}.",
+      recognized.len() - unrecognized,
+      unrecognized,
+      output_path.display(),
+    ),
+  }
+}
+
+// Polls the input instead of relying on file system events
+// so watching works the same in any container
+fn watch(input_path: &Path, output_path: &Path, target: &Target, options: &Options) {
+  println!("Watching {} for changes. Press Ctrl-C to stop.", input_path.display());
+  let mut last_snapshot = None;
+  loop {
+    let snapshot = snapshot(input_path);
+    if last_snapshot.as_ref() != Some(&snapshot) {
+      last_snapshot = Some(snapshot);
+      match recompile(input_path, output_path, target, options) {
+        Ok((vm_commands, output)) if target.name == "hack" => {
+          let hack_instructions = vm_compiler::rom_size(&output);
+          println!(
+            "Compiled {} VM commands into {} Hack instructions ({:.1}% of the ROM) and wrote to {}.",
+            vm_commands,
+            hack_instructions,
+            hack_instructions as f64 * 100.0 / vm_compiler::ROM_SIZE as f64,
+            output_path.display(),
+          )
+        }
+        Ok((vm_commands, _)) =>
+          println!("Compiled {} VM commands into {} and wrote to {}.", vm_commands, target.description, output_path.display()),
+        Err(error) =>
+          println!("{}\n\nWaiting for changes...", error),
+      }
+    }
+    thread::sleep(WATCH_INTERVAL);
+  }
+}
+
+// Returns the number of VM commands compiled and the output
+fn recompile(input_path: &Path, output_path: &Path, target: &Target, options: &Options) -> Result<(usize, String), String> {
+  let sources = read_sources(input_path)?;
+  let files = parse_sources(&sources, options)?;
+  let vm_commands = files.iter().map(|(_, instructions)| instructions.len()).sum();
+  let output = emit(target, options, &inline(options, &files))?;
+  write_output(output_path, &output)?;
+  if target.name == "x86" {
+    println!("{}", link_x86(output_path)?);
+  }
+  Ok((vm_commands, output))
+}
+
+// Writes the runtime next to x86-64 assembly and links them into a native
+// executable when gcc is installed
+fn link_x86(output_path: &Path) -> Result<String, String> {
+  let runtime_path = output_path.with_file_name(X86_RUNTIME_FILE);
+  write_output(&runtime_path, vm_compiler::x86_emitter::RUNTIME)?;
+  let executable_path = output_path.with_extension("");
+  let linked = Command::new("gcc")
+    .arg("-O2")
+    .arg("-o")
+    .arg(&executable_path)
+    .arg(output_path)
+    .arg(&runtime_path)
+    .status();
+  match linked {
+    Ok(status) if status.success() =>
+      Ok(format!("Wrote to {}.\nLinked into {}.", runtime_path.display(), executable_path.display())),
+    Ok(_) =>
+      Err(format!("I couldn't link {} with {} using gcc.", output_path.display(), runtime_path.display())),
+    Err(_) =>
+      Ok(format!(
+        "Wrote to {}.\nI couldn't find gcc to link it so try `gcc -o {} {} {}` on a machine that has it.",
+        runtime_path.display(),
+        executable_path.display(),
+        output_path.display(),
+        runtime_path.display(),
+      )),
+  }
+}
+
+// The files the backend gets, with small functions inlined for `--inline`
+fn inline(options: &Options, files: &[(String, Vec<vm_compiler::vm_parser::Instruction>)]) -> Vec<(String, Vec<vm_compiler::vm_parser::Instruction>)> {
+  match options.inline_threshold {
+    Some(threshold) => vm_compiler::inliner::inline(files, threshold, &options.layout),
+    None => files.to_vec(),
+  }
+}
+
+// The Hack backend for the layout and optimizations, shared by `emit` and `--stats`
+fn hack_backend(options: &Options) -> vm_compiler::vm_emitter::HackBackend {
+  vm_compiler::vm_emitter::HackBackend::new(options.layout.clone(), options.optimizations)
+}
+
+// Hack assembly also has to fit in the layout and the ROM
+fn emit(target: &Target, options: &Options, files: &[(String, Vec<vm_compiler::vm_parser::Instruction>)]) -> Result<String, String> {
+  if target.name == "hack" {
+    options.layout.check_program(files)?;
+    vm_compiler::check_rom_size(vm_compiler::backend::emit(&mut hack_backend(options), files))
+  } else {
+    Ok(vm_compiler::backend::emit((target.new)().as_mut(), files))
+  }
+}
+
+// Writes the graph picked with `--emit` next to the output and returns its path
+fn write_graph(output_path: &Path, graph: &str, files: &[(String, Vec<vm_compiler::vm_parser::Instruction>)]) -> Result<PathBuf, String> {
+  let (extension, output) = match graph {
+    "cfg-dot" => (CFG_DOT_EXTENSION, files.iter().map(|(program_name, instructions)|
+      vm_compiler::control_flow::to_dot(instructions, &vm_compiler::control_flow::build(program_name, instructions))
+    ).collect::<Vec<String>>().join("\n")),
+    "call-graph-dot" => {
+      let call_graph = vm_compiler::call_graph::build(files);
+      for problem in call_graph.report() {
+        println!("{}\n", problem);
+      }
+      (CALL_GRAPH_DOT_EXTENSION, call_graph.to_dot())
+    }
+    _ => unreachable!("clap only accepts the listed graphs"),
+  };
+  let graph_path = output_path.with_extension(extension);
+  write_output(&graph_path, &output)?;
+  Ok(graph_path)
+}
+
+// Changes whenever an input file is added, removed, or modified
+fn snapshot(input_path: &Path) -> Vec<(PathBuf, Option<SystemTime>, Option<u64>)> {
+  input_files(input_path).unwrap_or_default().into_iter().map(|path| {
+    let metadata = fs::metadata(&path).ok();
+    let modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+    let length = metadata.map(|metadata| metadata.len());
+    (path, modified, length)
+  }).collect()
+}
+
+fn input_files(input_path: &Path) -> Result<Vec<PathBuf>, String> {
+  if input_path.is_dir() {
+    let entries = fs::read_dir(input_path)
+      .map_err(|why| format!("I couldn't read directory {}: {}.", input_path.display(), why))?;
+    let mut paths = entries
+      .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+      .filter(|path| path.is_file() && path.extension().is_some_and(|extension|
+        extension == INPUT_EXTENSION || extension == JACK_EXTENSION
+      ))
+      .collect::<Vec<PathBuf>>();
+    paths.sort();
+    if paths.is_empty() {
+      Err(format!("I couldn't find any `.{}` or `.{}` files in directory {}.", INPUT_EXTENSION, JACK_EXTENSION, input_path.display()))
+    } else {
+      Ok(paths)
+    }
+  } else {
+    Ok(vec![input_path.to_path_buf()])
+  }
+}
+
+// Compiles the Jack classes to VM instructions and parses the VM files, keeping the file order
+fn parse_sources(sources: &[(PathBuf, String, String)], options: &Options) -> Result<Vec<(String, Vec<vm_compiler::vm_parser::Instruction>)>, String> {
+  let mut checks: Vec<vm_compiler::Check> = Vec::new();
+  if options.verify {
+    checks.push(vm_compiler::stack_verifier::verify);
+  }
+  if options.strict {
+    checks.push(vm_compiler::strict_mode::lint);
+  }
+  let checks = &checks;
+  let (files, errors): (Vec<_>, Vec<_>) = sources.iter().map(|(path, program_name, source)| {
+    let source = vec![(program_name.clone(), source.clone())];
+    if path.extension().is_some_and(|extension| extension == JACK_EXTENSION) {
+      vm_compiler::parse_jack_program(&source)
+    } else {
+      vm_compiler::parse_checked_program(&source, checks)
+    }
+  }).partition(Result::is_ok);
+  if errors.is_empty() {
+    if options.verify {
+      let vm_sources = sources.iter()
+        .filter(|(path, _, _)| path.extension().is_some_and(|extension| extension == INPUT_EXTENSION))
+        .map(|(_, program_name, source)| (program_name.clone(), source.clone()))
+        .collect::<Vec<(String, String)>>();
+      vm_compiler::offset_lint::check(&vm_sources)?;
+    }
+    Ok(files.into_iter().flat_map(Result::unwrap).collect())
+  } else {
+    Err(errors.into_iter().map(Result::unwrap_err).collect::<Vec<String>>().join("\n\n"))
+  }
+}
+
+// A program name with the commands of its file
+type LocatedFile = (String, Vec<Located<vm_compiler::vm_parser::Instruction>>);
+
+// Same as `parse_sources` but keeps the line of each VM command. Jack classes
+// get the lines of their VM listing.
+fn parse_located_sources(sources: &[(PathBuf, String, String)]) -> Result<Vec<LocatedFile>, String> {
+  sources.iter().map(|(path, program_name, source)| {
+    let instructions = if path.extension().is_some_and(|extension| extension == JACK_EXTENSION) {
+      vm_compiler::compile_jack(source).map(|instructions| instructions.into_iter().enumerate().map(|(index, instruction)| {
+        let line = Location { row: index + 1, col: 1 };
+        Located { value: instruction, from: line, to: line }
+      }).collect())
+    } else {
+      vm_compiler::vm_parser::parse_located(source)
+        .map_err(|diagnostics| vm_compiler::vm_parser::display_diagnostics(source, &diagnostics))
+    };
+    instructions
+      .map(|instructions| (program_name.clone(), instructions))
+      .map_err(|error| format!("In {}:\n{}", path.file_name().unwrap().to_str().unwrap(), error))
+  }).collect()
+}
+
+// Returns (path, program name, source) triples where the program name is the file name without extension
+fn read_sources(input_path: &Path) -> Result<Vec<(PathBuf, String, String)>, String> {
+  input_files(input_path)?.into_iter().map(|path| {
+    let mut input_file = File::open(&path)
+      .map_err(|why| format!("I couldn't open {}: {}.", path.display(), why))?;
+    // Read the file contents into a string, returns `io::Result<usize>`
+    let mut input_str = String::new();
+    input_file.read_to_string(&mut input_str)
+      .map_err(|why| format!("I couldn't read {}: {}.", path.display(), why))?;
+    let program_name = path.file_stem().unwrap().to_str().unwrap().to_string();
+    Ok((path, program_name, input_str))
+  }).collect()
+}
+
+fn write_output(output_path: &Path, output: &str) -> Result<(), String> {
+  let mut output_file = File::create(output_path)
+    .map_err(|why| format!("I couldn't create {}: {}.", output_path.display(), why))?;
+  output_file.write_all(output.as_bytes())
+    .map_err(|why| format!("I couldn't write to {}: {}.", output_path.display(), why))
+}
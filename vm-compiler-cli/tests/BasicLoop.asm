@@ -5,24 +5,16 @@ A=M
 M=D
 @SP
 M=M+1
-@LCL
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@LCL
 A=M
-A=D-M
-M=D-A
+M=D
 (LOOP_START)
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -30,10 +22,7 @@ M=D
 @SP
 M=M+1
 @LCL
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -50,23 +39,15 @@ A=M
 M=M+D
 @SP
 M=M+1
-@LCL
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@LCL
 A=M
-A=D-M
-M=D-A
+M=D
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -90,23 +71,15 @@ A=M
 M=M-D
 @SP
 M=M+1
-@ARG
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@ARG
 A=M
-A=D-M
-M=D-A
+M=D
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -118,12 +91,9 @@ M=M-1
 A=M
 D=M
 @LOOP_START
-D;JGT
+D;JNE
 @LCL
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
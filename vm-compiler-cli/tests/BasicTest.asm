@@ -5,18 +5,13 @@ A=M
 M=D
 @SP
 M=M+1
-@LCL
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@LCL
 A=M
-A=D-M
-M=D-A
+M=D
 @21
 D=A
 @SP
@@ -31,30 +26,21 @@ A=M
 M=D
 @SP
 M=M+1
-@ARG
-D=M
-@2
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
-@ARG
 D=M
-@1
-D=D+A
+@ARG
+A=M+1
+A=A+1
+M=D
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
+D=M
+@ARG
+A=M+1
+M=D
 @36
 D=A
 @SP
@@ -66,14 +52,14 @@ M=M+1
 D=M
 @6
 D=D+A
+@R13
+M=D
 @SP
-M=M-1
-A=M
-D=D+M
-@SP
+AM=M-1
+D=M
+@R13
 A=M
-A=D-M
-M=D-A
+M=D
 @42
 D=A
 @SP
@@ -88,30 +74,25 @@ A=M
 M=D
 @SP
 M=M+1
-@THAT
-D=M
-@5
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
-@THAT
 D=M
-@2
-D=D+A
+@THAT
+A=M+1
+A=A+1
+A=A+1
+A=A+1
+A=A+1
+M=D
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
+D=M
+@THAT
+A=M+1
+A=A+1
+M=D
 @510
 D=A
 @SP
@@ -119,23 +100,14 @@ A=M
 M=D
 @SP
 M=M+1
-@5
-D=A
-@6
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
-@LCL
 D=M
-@0
-D=D+A
-A=D
+@11
+M=D
+@LCL
+A=M
 D=M
 @SP
 A=M
@@ -145,8 +117,7 @@ M=M+1
 @THAT
 D=M
 @5
-D=D+A
-A=D
+A=D+A
 D=M
 @SP
 A=M
@@ -164,10 +135,7 @@ M=M+D
 @SP
 M=M+1
 @ARG
-D=M
-@1
-D=D+A
-A=D
+A=M+1
 D=M
 @SP
 A=M
@@ -187,8 +155,7 @@ M=M+1
 @THIS
 D=M
 @6
-D=D+A
-A=D
+A=D+A
 D=M
 @SP
 A=M
@@ -198,8 +165,7 @@ M=M+1
 @THIS
 D=M
 @6
-D=D+A
-A=D
+A=D+A
 D=M
 @SP
 A=M
@@ -226,11 +192,7 @@ A=M
 M=M-D
 @SP
 M=M+1
-@5
-D=A
-@6
-D=D+A
-A=D
+@11
 D=M
 @SP
 A=M
@@ -1,8 +1,5 @@
 @ARG
-D=M
-@1
-D=D+A
-A=D
+A=M+1
 D=M
 @SP
 A=M
@@ -22,18 +19,13 @@ A=M
 M=D
 @SP
 M=M+1
-@THAT
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@THAT
 A=M
-A=D-M
-M=D-A
+M=D
 @1
 D=A
 @SP
@@ -41,23 +33,15 @@ A=M
 M=D
 @SP
 M=M+1
-@THAT
-D=M
-@1
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
-@ARG
 D=M
-@0
-D=D+A
-A=D
+@THAT
+A=M+1
+M=D
+@ARG
+A=M
 D=M
 @SP
 A=M
@@ -81,24 +65,16 @@ A=M
 M=M-D
 @SP
 M=M+1
-@ARG
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@ARG
 A=M
-A=D-M
-M=D-A
+M=D
 (MAIN_LOOP_START)
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -110,15 +86,12 @@ M=M-1
 A=M
 D=M
 @COMPUTE_ELEMENT
-D;JGT
+D;JNE
 @END_PROGRAM
 0;JMP
 (COMPUTE_ELEMENT)
 @THAT
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -126,10 +99,7 @@ M=D
 @SP
 M=M+1
 @THAT
-D=M
-@1
-D=D+A
-A=D
+A=M+1
 D=M
 @SP
 A=M
@@ -146,18 +116,14 @@ A=M
 M=M+D
 @SP
 M=M+1
-@THAT
-D=M
-@2
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
+D=M
+@THAT
+A=M+1
+A=A+1
+M=D
 @THAT
 D=M
 @SP
@@ -189,10 +155,7 @@ D=M
 @THAT
 M=D
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -216,18 +179,13 @@ A=M
 M=M-D
 @SP
 M=M+1
-@ARG
-D=M
-@0
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
+D=M
+@ARG
 A=M
-A=D-M
-M=D-A
+M=D
 @MAIN_LOOP_START
 0;JMP
 (END_PROGRAM)
\ No newline at end of file
@@ -31,18 +31,14 @@ A=M
 M=D
 @SP
 M=M+1
-@THIS
-D=M
-@2
-D=D+A
 @SP
 M=M-1
 A=M
-D=D+M
-@SP
-A=M
-A=D-M
-M=D-A
+D=M
+@THIS
+A=M+1
+A=A+1
+M=D
 @46
 D=A
 @SP
@@ -54,14 +50,14 @@ M=M+1
 D=M
 @6
 D=D+A
+@R13
+M=D
 @SP
-M=M-1
-A=M
-D=D+M
-@SP
+AM=M-1
+D=M
+@R13
 A=M
-A=D-M
-M=D-A
+M=D
 @THIS
 D=M
 @SP
@@ -79,6 +75,7 @@ M=M+1
 @SP
 M=M-1
 A=M
+D=M
 @SP
 M=M-1
 A=M
@@ -86,10 +83,8 @@ M=M+D
 @SP
 M=M+1
 @THIS
-D=M
-@2
-D=D+A
-A=D
+A=M+1
+A=A+1
 D=M
 @SP
 A=M
@@ -109,8 +104,7 @@ M=M+1
 @THAT
 D=M
 @6
-D=D+A
-A=D
+A=D+A
 D=M
 @SP
 A=M
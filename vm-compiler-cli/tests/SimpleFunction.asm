@@ -3,10 +3,7 @@
 M=M+1
 M=M+1
 @LCL
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -14,10 +11,7 @@ M=D
 @SP
 M=M+1
 @LCL
-D=M
-@1
-D=D+A
-A=D
+A=M+1
 D=M
 @SP
 A=M
@@ -41,10 +35,7 @@ M=!M
 @SP
 M=M+1
 @ARG
-D=M
-@0
-D=D+A
-A=D
+A=M
 D=M
 @SP
 A=M
@@ -62,10 +53,7 @@ M=M+D
 @SP
 M=M+1
 @ARG
-D=M
-@1
-D=D+A
-A=D
+A=M+1
 D=M
 @SP
 A=M
@@ -0,0 +1,88 @@
+extern crate vm_compiler;
+extern crate serde_json;
+
+mod server;
+
+use serde_json::{json, Value};
+use std::io::prelude::*;
+use std::io::{self, BufRead};
+
+// The language server speaks JSON-RPC over stdio:
+//   Content-Length: <bytes>\r\n
+//   \r\n
+//   <json message>
+fn main() {
+  let stdin = io::stdin();
+  let mut input = stdin.lock();
+  let stdout = io::stdout();
+  let mut output = stdout.lock();
+  let mut server = server::Server::new();
+  loop {
+    let message = match read_message(&mut input) {
+      Ok(Some(message)) => message,
+      Ok(None) => std::process::exit(1),
+      Err(error) => {
+        eprintln!("I couldn't read a message from the client: {}.", error);
+        std::process::exit(1);
+      }
+    };
+    let message = match serde_json::from_str::<Value>(&message) {
+      Ok(message) => message,
+      Err(error) => {
+        write_message(&mut output, &json!({
+          "jsonrpc": "2.0",
+          "id": Value::Null,
+          "error": { "code": -32700, "message": format!("I couldn't parse the message as JSON: {}.", error) },
+        }));
+        continue;
+      }
+    };
+    for reply in server.handle(&message) {
+      write_message(&mut output, &reply);
+    }
+    if let Some(exit_code) = server.exit_code() {
+      std::process::exit(exit_code);
+    }
+  }
+}
+
+// Returns `Ok(None)` when the client closed the stream
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+  let mut content_length = None;
+  loop {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+    let header = header.trim_end();
+    if header.is_empty() {
+      break;
+    }
+    let mut parts = header.splitn(2, ':');
+    if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+      if name.eq_ignore_ascii_case("Content-Length") {
+        content_length = value.trim().parse::<usize>().ok();
+      }
+    }
+  }
+  match content_length {
+    Some(length) => {
+      let mut content = vec![0; length];
+      input.read_exact(&mut content)?;
+      String::from_utf8(content)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+    None => Err(io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")),
+  }
+}
+
+fn write_message(output: &mut impl Write, message: &Value) {
+  let content = message.to_string();
+  let result = write!(output, "Content-Length: {}\r\n\r\n{}", content.len(), content)
+    .and_then(|_| output.flush());
+  if let Err(error) = result {
+    eprintln!("I couldn't write a message to the client: {}.", error);
+    std::process::exit(1);
+  }
+}
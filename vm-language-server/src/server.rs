@@ -0,0 +1,323 @@
+use lip::Location;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use vm_compiler::offset_lint;
+use vm_compiler::stack_verifier;
+use vm_compiler::vm_emitter;
+use vm_compiler::vm_parser::*;
+
+const SEGMENTS: [&str; 8] = ["local", "argument", "this", "that", "constant", "static", "temp", "pointer"];
+
+pub struct Server {
+  documents: HashMap<String, String>,
+  shutdown_requested: bool,
+  exit_code: Option<i32>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SymbolKind {
+  Label,
+  Function,
+}
+
+// A label or function name as it appears in a document.
+// `line` and the columns are 0-based and count characters.
+struct Symbol {
+  name: String,
+  kind: SymbolKind,
+  is_definition: bool,
+  line: usize,
+  start: usize,
+  end: usize,
+}
+
+impl Server {
+  pub fn new() -> Self {
+    Server {
+      documents: HashMap::new(),
+      shutdown_requested: false,
+      exit_code: None,
+    }
+  }
+
+  pub fn exit_code(&self) -> Option<i32> {
+    self.exit_code
+  }
+
+  // Returns the responses and notifications to send back to the client
+  pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+    let params = &message["params"];
+    match (message["method"].as_str(), message.get("id")) {
+      (Some(method), Some(id)) =>
+        vec![self.handle_request(method, id.clone(), params)],
+      (Some(method), None) =>
+        self.handle_notification(method, params),
+      // Responses from the client are not used
+      (None, _) =>
+        vec![],
+    }
+  }
+
+  fn handle_request(&mut self, method: &str, id: Value, params: &Value) -> Value {
+    let result = match method {
+      "initialize" =>
+        json!({
+          "capabilities": {
+            "textDocumentSync": 1,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "hoverProvider": true,
+            "completionProvider": { "triggerCharacters": [" "] },
+          },
+          "serverInfo": { "name": "vm-language-server", "version": "0.1.0" },
+        }),
+      "shutdown" => {
+        self.shutdown_requested = true;
+        Value::Null
+      }
+      "textDocument/definition" =>
+        self.definition(params),
+      "textDocument/references" =>
+        self.references(params),
+      "textDocument/hover" =>
+        self.hover(params),
+      "textDocument/completion" =>
+        self.completion(params),
+      _ =>
+        return json!({
+          "jsonrpc": "2.0",
+          "id": id,
+          "error": { "code": -32601, "message": format!("I don't know how to handle the `{}` request.", method) },
+        }),
+    };
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+  }
+
+  fn handle_notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+    match method {
+      "textDocument/didOpen" => {
+        let text = params["textDocument"]["text"].as_str().unwrap_or_default().to_string();
+        self.documents.insert(uri.clone(), text);
+        self.publish_diagnostics(&uri).into_iter().collect()
+      }
+      "textDocument/didChange" => {
+        // We asked for full document sync so the last change holds the whole text
+        if let Some(text) = params["contentChanges"].as_array()
+          .and_then(|changes| changes.last())
+          .and_then(|change| change["text"].as_str()) {
+          self.documents.insert(uri.clone(), text.to_string());
+        }
+        self.publish_diagnostics(&uri).into_iter().collect()
+      }
+      "textDocument/didClose" => {
+        self.documents.remove(&uri);
+        vec![notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }))]
+      }
+      "exit" => {
+        self.exit_code = Some(if self.shutdown_requested { 0 } else { 1 });
+        vec![]
+      }
+      _ =>
+        vec![],
+    }
+  }
+
+  // Nothing to publish for documents that aren't open, like after a `didClose`
+  fn publish_diagnostics(&self, uri: &str) -> Option<Value> {
+    let source = self.documents.get(uri)?;
+    let (instructions, mut diagnostics) = parse_with_diagnostics(&with_trailing_newline(source));
+    // Stack depths of a partly parsed file would be misleading
+    if diagnostics.iter().all(|diagnostic| diagnostic.severity != Severity::Error) {
+      diagnostics.extend(stack_verifier::verify(&instructions));
+      // Only calls from the same document count for arguments
+      let files = [(String::new(), instructions)];
+      diagnostics.extend(offset_lint::lint(&files).into_iter().map(|(_, diagnostic)| diagnostic));
+    }
+    let diagnostics = diagnostics.iter().map(|diagnostic| {
+      let to = if diagnostic.to == diagnostic.from {
+        Location { col: diagnostic.from.col + 1, ..diagnostic.from }
+      } else {
+        diagnostic.to
+      };
+      json!({
+        "range": {
+          "start": to_position(source, diagnostic.from),
+          "end": to_position(source, to),
+        },
+        "severity": match diagnostic.severity { Severity::Error => 1, Severity::Warning => 2 },
+        "source": "vm-compiler",
+        "message": diagnostic.message,
+      })
+    }).collect::<Vec<Value>>();
+    Some(notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics })))
+  }
+
+  fn definition(&self, params: &Value) -> Value {
+    match self.symbol_at(params) {
+      Some((uri, symbol)) =>
+        self.related_symbols(&uri, &symbol).into_iter()
+          .find(|(_, other)| other.is_definition)
+          .map_or(Value::Null, |(uri, other)| to_lsp_location(&self.documents[&uri], &uri, &other)),
+      None =>
+        Value::Null,
+    }
+  }
+
+  fn references(&self, params: &Value) -> Value {
+    let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+    match self.symbol_at(params) {
+      Some((uri, symbol)) =>
+        Value::Array(
+          self.related_symbols(&uri, &symbol).into_iter()
+            .filter(|(_, other)| include_declaration || !other.is_definition)
+            .map(|(uri, other)| to_lsp_location(&self.documents[&uri], &uri, &other))
+            .collect()
+        ),
+      None =>
+        json!([]),
+    }
+  }
+
+  fn hover(&self, params: &Value) -> Value {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+    let (source, line, _) = match self.position(params) {
+      Some(position) => position,
+      None => return Value::Null,
+    };
+    let (instructions, _) = parse_with_diagnostics(&with_trailing_newline(source));
+    instructions.iter().enumerate()
+      .find(|(_, instruction)| instruction.from.row == line + 1)
+      .map_or(Value::Null, |(instruction_index, instruction)| {
+        let assembly = vm_emitter::emit_instruction(&program_name(uri), instruction_index, &instruction.value);
+        json!({
+          "contents": { "kind": "markdown", "value": format!("```asm\n{}\n```", assembly) },
+          "range": {
+            "start": to_position(source, instruction.from),
+            "end": to_position(source, instruction.to),
+          },
+        })
+      })
+  }
+
+  fn completion(&self, params: &Value) -> Value {
+    let (source, line, character) = match self.position(params) {
+      Some(position) => position,
+      None => return json!([]),
+    };
+    let before_cursor = source.lines().nth(line).unwrap_or_default()
+      .chars().take(character).collect::<String>();
+    let words = before_cursor.split_whitespace().collect::<Vec<&str>>();
+    let completing_segment = match words.as_slice() {
+      [command] => before_cursor.ends_with(char::is_whitespace).then_some(*command),
+      [command, _] => (!before_cursor.ends_with(char::is_whitespace)).then_some(*command),
+      _ => None,
+    };
+    let segments = match completing_segment {
+      Some("push") => SEGMENTS.iter().collect::<Vec<&&str>>(),
+      Some("pop") => SEGMENTS.iter().filter(|segment| **segment != "constant").collect(),
+      _ => vec![],
+    };
+    Value::Array(segments.into_iter().map(|segment| json!({ "label": segment, "kind": 14 })).collect())
+  }
+
+  // Returns the document text plus the 0-based line and character column of the cursor
+  fn position(&self, params: &Value) -> Option<(&String, usize, usize)> {
+    let source = self.documents.get(params["textDocument"]["uri"].as_str()?)?;
+    let line = params["position"]["line"].as_u64()? as usize;
+    let utf16_column = params["position"]["character"].as_u64()? as usize;
+    let mut utf16_offset = 0;
+    let character = source.lines().nth(line).unwrap_or_default().chars()
+      .take_while(|c| {
+        utf16_offset += c.len_utf16();
+        utf16_offset <= utf16_column
+      }).count();
+    Some((source, line, character))
+  }
+
+  fn symbol_at(&self, params: &Value) -> Option<(String, Symbol)> {
+    let uri = params["textDocument"]["uri"].as_str()?;
+    let (source, line, character) = self.position(params)?;
+    symbols(source).into_iter()
+      .find(|symbol| symbol.line == line && symbol.start <= character && character <= symbol.end)
+      .map(|symbol| (uri.to_string(), symbol))
+  }
+
+  // Labels are local to their file while functions can be called from any open file
+  fn related_symbols(&self, uri: &str, symbol: &Symbol) -> Vec<(String, Symbol)> {
+    let documents = match symbol.kind {
+      SymbolKind::Label =>
+        vec![uri.to_string()],
+      SymbolKind::Function => {
+        let mut uris = self.documents.keys().filter(|other| *other != uri).cloned().collect::<Vec<String>>();
+        uris.sort();
+        uris.insert(0, uri.to_string());
+        uris
+      }
+    };
+    documents.into_iter().flat_map(|uri| {
+      symbols(&self.documents[&uri]).into_iter()
+        .filter(|other| other.kind == symbol.kind && other.name == symbol.name)
+        .map(|other| (uri.clone(), other))
+        .collect::<Vec<(String, Symbol)>>()
+    }).collect()
+  }
+}
+
+fn symbols(source: &str) -> Vec<Symbol> {
+  let (instructions, _) = parse_with_diagnostics(&with_trailing_newline(source));
+  instructions.into_iter().filter_map(|instruction| {
+    let (name, kind, is_definition) = match instruction.value {
+      Instruction::Label(name) => (name, SymbolKind::Label, true),
+      Instruction::Goto(name) | Instruction::IfGoto(name) => (name, SymbolKind::Label, false),
+      Instruction::Function { name, .. } => (name, SymbolKind::Function, true),
+      Instruction::Call { name, .. } => (name, SymbolKind::Function, false),
+      _ => return None,
+    };
+    let line = instruction.from.row - 1;
+    // The name is always the second word of the command
+    let text = source.lines().nth(line)?;
+    let start = text.char_indices()
+      .skip_while(|(_, c)| !c.is_whitespace())
+      .find(|(_, c)| !c.is_whitespace())
+      .map(|(byte_index, _)| text[..byte_index].chars().count())?;
+    let end = start + name.chars().count();
+    Some(Symbol { name, kind, is_definition, line, start, end })
+  }).collect()
+}
+
+// The parser expects every command to end with a newline but editors often drop the last one
+fn with_trailing_newline(source: &str) -> String {
+  if source.ends_with('\n') {
+    source.to_string()
+  } else {
+    format!("{}\n", source)
+  }
+}
+
+fn program_name(uri: &str) -> String {
+  let file_name = uri.rsplit('/').next().unwrap_or(uri);
+  file_name.strip_suffix(".vm").unwrap_or(file_name).to_string()
+}
+
+fn to_lsp_location(source: &str, uri: &str, symbol: &Symbol) -> Value {
+  json!({
+    "uri": uri,
+    "range": {
+      "start": to_position(source, Location { row: symbol.line + 1, col: symbol.start + 1 }),
+      "end": to_position(source, Location { row: symbol.line + 1, col: symbol.end + 1 }),
+    },
+  })
+}
+
+// Parser locations are 1-based and count characters while LSP positions
+// are 0-based and count UTF-16 code units
+fn to_position(source: &str, location: Location) -> Value {
+  let line = source.lines().nth(location.row - 1).unwrap_or_default();
+  let character = line.chars().take(location.col - 1).map(char::len_utf16).sum::<usize>();
+  json!({ "line": location.row - 1, "character": character })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+  json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
@@ -0,0 +1,126 @@
+extern crate serde_json;
+
+use serde_json::{json, Value};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+
+fn send(input: &mut ChildStdin, message: Value) {
+  let content = message.to_string();
+  write!(input, "Content-Length: {}\r\n\r\n{}", content.len(), content).unwrap();
+  input.flush().unwrap();
+}
+
+fn receive(output: &mut BufReader<ChildStdout>) -> Value {
+  let mut content_length = 0;
+  loop {
+    let mut header = String::new();
+    output.read_line(&mut header).unwrap();
+    let header = header.trim_end();
+    if header.is_empty() {
+      break;
+    }
+    if let Some(length) = header.strip_prefix("Content-Length: ") {
+      content_length = length.parse().unwrap();
+    }
+  }
+  let mut content = vec![0; content_length];
+  output.read_exact(&mut content).unwrap();
+  serde_json::from_slice(&content).unwrap()
+}
+
+fn request(id: u64, method: &str, params: Value) -> Value {
+  json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+fn position(line: u64, character: u64) -> Value {
+  json!({ "textDocument": { "uri": "file:///Main.vm" }, "position": { "line": line, "character": character } })
+}
+
+#[test]
+fn test_scripted_session() {
+  let mut server = Command::new(env!("CARGO_BIN_EXE_vm-language-server"))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  let mut input = server.stdin.take().unwrap();
+  let mut output = BufReader::new(server.stdout.take().unwrap());
+
+  send(&mut input, request(1, "initialize", json!({ "capabilities": {} })));
+  assert_eq!(receive(&mut output)["result"]["capabilities"]["hoverProvider"], json!(true));
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+
+  let source =
+"function Main.main 0
+push constant 3
+call Main.double 1
+label END
+goto END
+function Main.double 0
+push argument 0
+push argument 0
+add
+return
+label UNUSED
+";
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+    "textDocument": { "uri": "file:///Main.vm", "languageId": "vm", "version": 1, "text": source },
+  }}));
+  let diagnostics = receive(&mut output);
+  assert_eq!(diagnostics["method"], json!("textDocument/publishDiagnostics"));
+  assert_eq!(diagnostics["params"]["diagnostics"], json!([{
+    "range": { "start": { "line": 10, "character": 6 }, "end": { "line": 10, "character": 12 } },
+    "severity": 2,
+    "source": "vm-compiler",
+    "message": "I found an unused label named UNUSED. Try removing it or use it somewhere.",
+  }]));
+
+  send(&mut input, request(2, "textDocument/definition", position(2, 8)));
+  assert_eq!(receive(&mut output)["result"], json!({
+    "uri": "file:///Main.vm",
+    "range": { "start": { "line": 5, "character": 9 }, "end": { "line": 5, "character": 20 } },
+  }));
+
+  let mut references_params = position(3, 7);
+  references_params["context"] = json!({ "includeDeclaration": false });
+  send(&mut input, request(3, "textDocument/references", references_params));
+  assert_eq!(receive(&mut output)["result"], json!([{
+    "uri": "file:///Main.vm",
+    "range": { "start": { "line": 4, "character": 5 }, "end": { "line": 4, "character": 8 } },
+  }]));
+
+  send(&mut input, request(4, "textDocument/hover", position(1, 0)));
+  assert_eq!(
+    receive(&mut output)["result"]["contents"]["value"],
+    json!("```asm\n@3\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n```")
+  );
+
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+    "textDocument": { "uri": "file:///Main.vm", "version": 2 },
+    "contentChanges": [{ "text": "pop constant 1\npop " }],
+  }}));
+  let diagnostics = receive(&mut output);
+  assert_eq!(diagnostics["params"]["diagnostics"][0]["range"]["start"], json!({ "line": 0, "character": 0 }));
+  assert_eq!(diagnostics["params"]["diagnostics"][0]["severity"], json!(1));
+
+  send(&mut input, request(5, "textDocument/completion", position(1, 4)));
+  let completions = receive(&mut output)["result"].as_array().unwrap()
+    .iter().map(|item| item["label"].as_str().unwrap().to_string()).collect::<Vec<String>>();
+  assert_eq!(completions, vec!["local", "argument", "this", "that", "static", "temp", "pointer"]);
+
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "textDocument/didClose", "params": {
+    "textDocument": { "uri": "file:///Main.vm" },
+  }}));
+  assert_eq!(receive(&mut output)["params"], json!({ "uri": "file:///Main.vm", "diagnostics": [] }));
+  // A change without text to a closed document has nothing to publish
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+    "textDocument": { "uri": "file:///Main.vm", "version": 3 },
+    "contentChanges": [],
+  }}));
+
+  send(&mut input, request(6, "shutdown", Value::Null));
+  assert_eq!(receive(&mut output)["result"], Value::Null);
+  send(&mut input, json!({ "jsonrpc": "2.0", "method": "exit" }));
+  assert_eq!(server.wait().unwrap().code(), Some(0));
+}